@@ -0,0 +1,115 @@
+// Block hashes of uploaded contents for --delta-upload
+//
+// Files are split into fixed size blocks and the SHA-256 of every
+// block is kept in the cloudstate. On the next upload only the
+// blocks whose hash changed are sent, which covers appends and in
+// place writes like those of logs and databases. Inserting bytes
+// shifts every block after it, such files are uploaded whole
+
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::crypto;
+
+// Tests don't need megabytes of contents
+const BLOCK_SIZE: usize = if cfg!(test) { 1 << 10 } else { 1 << 20 };
+
+// Smaller files are cheaper to upload whole
+pub const MIN_DELTA_SIZE: usize = 8 * BLOCK_SIZE;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BlockHashes {
+    block_size: usize,
+    size: usize,
+
+    // Version of the cloud file the hashes belong to,
+    // updates are only applied on top of that one
+    pub etag: String,
+    hashes: Vec<String>,
+}
+
+fn block_hashes(contents: &[u8], block_size: usize) -> impl Iterator<Item = String> + '_ {
+    contents
+        .chunks(block_size)
+        .map(|block| crypto::to_hex(&Sha256::digest(block)))
+}
+
+impl BlockHashes {
+    pub fn new(contents: &[u8], etag: String) -> Self {
+        BlockHashes {
+            block_size: BLOCK_SIZE,
+            size: contents.len(),
+            etag,
+            hashes: block_hashes(contents, BLOCK_SIZE).collect(),
+        }
+    }
+
+    // Consecutive changed blocks are merged into one range
+    // @Returns byte ranges to upload, none when the file
+    // shrank or more than half of it changed
+    pub fn changed_ranges(&self, contents: &[u8]) -> Option<Vec<Range<usize>>> {
+        if contents.len() < self.size || self.block_size == 0 {
+            return None;
+        }
+
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        for (index, hash) in block_hashes(contents, self.block_size).enumerate() {
+            if self.hashes.get(index) == Some(&hash) {
+                continue;
+            }
+
+            let start = index * self.block_size;
+            let end = (start + self.block_size).min(contents.len());
+            match ranges.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ => ranges.push(start..end),
+            }
+        }
+
+        let changed: usize = ranges.iter().map(|range| range.len()).sum();
+        if changed * 2 > contents.len() {
+            return None;
+        }
+
+        Some(ranges)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blocks::{BlockHashes, BLOCK_SIZE};
+
+    #[test]
+    fn test_changed_ranges() {
+        let contents = vec![0u8; 10 * BLOCK_SIZE];
+        let hashes = BlockHashes::new(&contents, "\"1\"".to_string());
+        assert_eq!(hashes.changed_ranges(&contents), Some(Vec::new()));
+
+        // Appends only send the new tail, the old last
+        // block is complete so it still matches
+        let mut appended = contents.clone();
+        appended.extend_from_slice(b"new line\n");
+        let ranges = hashes.changed_ranges(&appended).unwrap();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0], 10 * BLOCK_SIZE..10 * BLOCK_SIZE + 9);
+
+        // Neighbouring changed blocks are merged
+        let mut written = contents.clone();
+        written[BLOCK_SIZE + 5] = 1;
+        written[2 * BLOCK_SIZE] = 1;
+        written[7 * BLOCK_SIZE] = 1;
+        assert_eq!(
+            hashes.changed_ranges(&written),
+            Some(vec![
+                BLOCK_SIZE..3 * BLOCK_SIZE,
+                7 * BLOCK_SIZE..8 * BLOCK_SIZE
+            ])
+        );
+
+        // Shrunk or mostly rewritten files go whole
+        assert_eq!(hashes.changed_ranges(&contents[1..]), None);
+        assert_eq!(hashes.changed_ranges(&vec![1u8; 10 * BLOCK_SIZE]), None);
+    }
+}
@@ -0,0 +1,104 @@
+// Passphrase encryption of small blobs like the exported config
+//
+// The key is derived with PBKDF2-HMAC-SHA256 from the passphrase and
+// a random salt, contents are sealed with ChaCha20-Poly1305. Salt,
+// nonce and ciphertext are kept hex encoded in a JSON envelope
+
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::SyncError;
+
+// Tests don't need to be slow to brute force
+const KDF_ROUNDS: u32 = if cfg!(test) { 1_000 } else { 600_000 };
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    cloudsync_sealed: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Key {
+    let mut key = Key::default();
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(passphrase.as_bytes(), salt, KDF_ROUNDS, &mut key);
+    key
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(data: &str) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..data.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(data.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn seal(passphrase: &str, plaintext: &[u8]) -> Vec<u8> {
+    let mut salt = [0u8; SALT_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt));
+    let ciphertext = cipher.encrypt(&nonce, plaintext).unwrap();
+
+    let envelope = Envelope {
+        cloudsync_sealed: 1,
+        salt: to_hex(&salt),
+        nonce: to_hex(&nonce),
+        ciphertext: to_hex(&ciphertext),
+    };
+    serde_json::to_vec(&envelope).unwrap()
+}
+
+pub fn is_sealed(data: &[u8]) -> bool {
+    serde_json::from_slice::<Envelope>(data).is_ok()
+}
+
+// A wrong passphrase and tampered contents
+// can't be told apart, both fail to decrypt
+pub fn open(passphrase: &str, data: &[u8]) -> Result<Vec<u8>, SyncError> {
+    let invalid = || SyncError::Parse("Invalid encrypted data".to_string());
+
+    let envelope: Envelope = serde_json::from_slice(data).map_err(|_| invalid())?;
+    let salt = from_hex(&envelope.salt).ok_or_else(invalid)?;
+    let nonce = from_hex(&envelope.nonce)
+        .filter(|nonce| nonce.len() == NONCE_SIZE)
+        .ok_or_else(invalid)?;
+    let ciphertext = from_hex(&envelope.ciphertext).ok_or_else(invalid)?;
+
+    let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt));
+    cipher
+        .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| SyncError::Auth("Wrong passphrase or corrupt data".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::{is_sealed, open, seal};
+
+    #[test]
+    fn test_seal_round_trip() {
+        let sealed = seal("correct horse", b"{\"accounts\":{}}");
+        assert!(is_sealed(&sealed));
+        assert!(!is_sealed(b"{\"accounts\":{}}"));
+
+        assert_eq!(
+            open("correct horse", &sealed).unwrap(),
+            b"{\"accounts\":{}}"
+        );
+        assert!(open("wrong horse", &sealed).is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use std::fmt;
+
+// Errors surfaced by the library, the cli only prints them
+// but callers can match on the kind to decide what to do
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyncError {
+    // Token missing, revoked or expired beyond refresh
+    Auth(String),
+
+    // Request never got a response
+    Network(String),
+
+    // Server answered with an error status
+    Api { code: u32, message: String },
+
+    // Local filesystem
+    Io(String),
+
+    // Unexpected response, config or date format
+    Parse(String),
+
+    // Bad arguments or account setup
+    Usage(String),
+
+    // Local clock is too far off the server's, --strict-clock only
+    ClockSkew { seconds: u64 },
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncError::Auth(message)
+            | SyncError::Network(message)
+            | SyncError::Io(message)
+            | SyncError::Parse(message)
+            | SyncError::Usage(message) => write!(f, "{}", message),
+            SyncError::Api { code, message } if message.is_empty() => {
+                write!(f, "Request failed with status {}", code)
+            }
+            SyncError::Api { code, message } => {
+                write!(f, "Request failed with status {}: {}", code, message)
+            }
+            SyncError::ClockSkew { seconds } => write!(
+                f,
+                "Local clock is off by {} seconds from the server, sync decisions will be wrong",
+                seconds
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SyncError {}
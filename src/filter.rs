@@ -0,0 +1,187 @@
+// Include and exclude rules for synced paths
+//
+// Patterns without a '/' match the file name at any depth,
+// patterns with a '/' match the whole path from the sync root
+// *  matches anything within a single path component
+// ** matches across path components
+// ?  matches a single character other than '/'
+//
+// A max depth leaves out anything more than that
+// many folders below the sync root
+//
+// Remote ignores are plain path prefixes of cloud folders or files
+// managed elsewhere, they match whole path components only
+
+#[derive(Default)]
+pub struct PathFilter {
+    includes: Vec<String>,
+    excludes: Vec<String>,
+    max_depth: Option<usize>,
+    remote_ignores: Vec<String>,
+}
+
+impl PathFilter {
+    pub fn new(includes: Vec<String>, excludes: Vec<String>) -> Self {
+        PathFilter {
+            includes,
+            excludes,
+            max_depth: None,
+            remote_ignores: Vec::new(),
+        }
+    }
+
+    pub fn with_max_depth(mut self, max_depth: Option<usize>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn with_remote_ignores(mut self, prefixes: Vec<String>) -> Self {
+        self.remote_ignores = prefixes
+            .iter()
+            .map(|prefix| prefix.trim_matches('/').to_string())
+            .filter(|prefix| !prefix.is_empty())
+            .collect();
+        self
+    }
+
+    // Reads exclude patterns from a .cloudignore file,
+    // one per line with # starting a comment
+    pub fn read_ignore_file(file_path: &str) -> Vec<String> {
+        let contents = std::fs::read_to_string(file_path).unwrap_or_default();
+
+        contents
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    // Path relative to the sync root, excludes win over includes
+    // and no includes means everything is included
+    pub fn is_included(&self, path: &str) -> bool {
+        let path = path.trim_start_matches('/');
+
+        let depth = path.matches('/').count();
+        if self.max_depth.is_some_and(|max_depth| depth > max_depth) {
+            return false;
+        }
+
+        if self.remote_ignores.iter().any(|prefix| {
+            path.strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        }) {
+            return false;
+        }
+
+        if self
+            .excludes
+            .iter()
+            .any(|pattern| matches_path(pattern, path))
+        {
+            return false;
+        }
+
+        self.includes.is_empty()
+            || self
+                .includes
+                .iter()
+                .any(|pattern| matches_path(pattern, path))
+    }
+}
+
+fn matches_path(pattern: &str, path: &str) -> bool {
+    if pattern.contains('/') {
+        glob_match(pattern.trim_start_matches('/'), path)
+    } else {
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+        glob_match(pattern, file_name)
+    }
+}
+
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_match_chars(&pattern, &text)
+}
+
+fn glob_match_chars(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', rest @ ..] => {
+            // "**/" can also match no folders at all
+            if let ['/', after_slash @ ..] = rest {
+                if glob_match_chars(after_slash, text) {
+                    return true;
+                }
+            }
+
+            (0..=text.len()).any(|start| glob_match_chars(rest, &text[start..]))
+        }
+        ['*', rest @ ..] => {
+            for start in 0..=text.len() {
+                if glob_match_chars(rest, &text[start..]) {
+                    return true;
+                }
+
+                if text.get(start) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        }
+        ['?', rest @ ..] => match text {
+            [first, text_rest @ ..] if *first != '/' => glob_match_chars(rest, text_rest),
+            _ => false,
+        },
+        [literal, rest @ ..] => match text {
+            [first, text_rest @ ..] if first == literal => glob_match_chars(rest, text_rest),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{glob_match, PathFilter};
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.md", "README.md"));
+        assert!(!glob_match("*.md", "docs/README.md"));
+        assert!(glob_match("docs/**/*.md", "docs/a/b/README.md"));
+        assert!(glob_match("docs/**/*.md", "docs/README.md"));
+        assert!(glob_match("file?.txt", "file1.txt"));
+        assert!(!glob_match("file?.txt", "file10.txt"));
+    }
+
+    #[test]
+    fn test_exclude_wins() {
+        let filter = PathFilter::new(vec!["*.md".to_string()], vec!["drafts/**".to_string()]);
+
+        assert!(filter.is_included("/notes/todo.md"));
+        assert!(!filter.is_included("/notes/todo.txt"));
+        assert!(!filter.is_included("/drafts/todo.md"));
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let filter = PathFilter::default().with_max_depth(Some(1));
+
+        assert!(filter.is_included("/root.txt"));
+        assert!(filter.is_included("/one/a.txt"));
+        assert!(!filter.is_included("/one/two/b.txt"));
+    }
+
+    #[test]
+    fn test_remote_ignores() {
+        let filter = PathFilter::default()
+            .with_remote_ignores(vec!["/Photos/".to_string(), "Apps/Camera".to_string()]);
+
+        assert!(!filter.is_included("/Photos"));
+        assert!(!filter.is_included("/Photos/2024/a.jpg"));
+        assert!(!filter.is_included("/Apps/Camera/b.jpg"));
+        assert!(filter.is_included("/Photos.txt"));
+        assert!(filter.is_included("/Apps/Notes/c.txt"));
+    }
+}
@@ -0,0 +1,62 @@
+// Gzip of cloudstates for --compress-state
+//
+// Compressed files are told apart from JSON by the gzip magic bytes,
+// so reading never needs to know how a file was written
+
+use std::io::{Read, Write};
+
+use flate2::{bufread::GzDecoder, write::GzEncoder, Compression};
+
+const MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+pub fn compress(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(data)
+        .and_then(|_| encoder.finish())
+        .map_err(|err| format!("Cannot compress: {}", err))
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoder = GzDecoder::new(data);
+    let mut decompressed = Vec::new();
+    decoder
+        .read_to_end(&mut decompressed)
+        .map_err(|err| format!("Gzip stream is corrupt: {}", err))?;
+
+    // The decoder stops at the end of the first stream
+    if !decoder.into_inner().is_empty() {
+        return Err("Data after the end of the gzip stream".to_string());
+    }
+
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gzip::{compress, decompress, is_gzip};
+
+    #[test]
+    fn test_gzip_round_trip() {
+        let json = r#"{"version":1,"entries":{"/a.txt":{"cloud_id":"1","last_modified":0}}}"#
+            .repeat(10_000);
+
+        let compressed = compress(json.as_bytes()).unwrap();
+        assert!(is_gzip(&compressed));
+        assert!(!is_gzip(json.as_bytes()));
+        assert!(compressed.len() * 10 < json.len());
+        assert_eq!(decompress(&compressed).unwrap(), json.as_bytes());
+
+        assert_eq!(decompress(&compress(b"").unwrap()).unwrap(), b"");
+        assert!(decompress(&compressed[..compressed.len() / 2]).is_err());
+        assert!(decompress(b"\x1f\x8bnot gzip").is_err());
+
+        let mut trailing = compressed.clone();
+        trailing.extend_from_slice(b"{}");
+        assert!(decompress(&trailing).is_err());
+    }
+}
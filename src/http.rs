@@ -0,0 +1,782 @@
+use std::{
+    cell::{Cell, RefCell},
+    fs::File,
+    io::{BufReader, Read, Seek, Write},
+    ops::{Deref, DerefMut},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use curl::easy::{Easy, List};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::{parse_http_date, SyncError, Token};
+
+// Retries of a single request, 0 fails on the first error
+pub const DEFAULT_MAX_RETRIES: u32 = 4;
+
+// Retries all requests of a sync may make together
+pub const DEFAULT_RETRY_BUDGET: u32 = 200;
+
+// Requests failing in a row before the service is
+// taken to be down and no more requests are sent
+pub const BREAKER_THRESHOLD: u32 = 10;
+
+// Characters of a body that isn't JSON kept in errors
+const BODY_PREVIEW: usize = 200;
+
+// curl asks for 100-continue before sending a body over 1 MiB and
+// waits a second when the server doesn't answer it, the empty
+// header turns that off so uploads are sent right away
+pub const NO_EXPECT: &str = "Expect:";
+
+// A reset handle keeps its connection, dns and tls session
+// caches, so keeping one around per thread lets requests
+// reuse the keep-alive connection to the same host
+thread_local! {
+    static IDLE_HANDLE: RefCell<Option<Easy>> = const { RefCell::new(None) };
+}
+
+// Handle borrowed from the thread's idle slot,
+// it's reset and put back when dropped
+pub struct Handle {
+    easy: Option<Easy>,
+    auth: Option<Auth>,
+}
+
+impl Deref for Handle {
+    type Target = Easy;
+
+    fn deref(&self) -> &Easy {
+        self.easy.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Handle {
+    fn deref_mut(&mut self) -> &mut Easy {
+        self.easy.as_mut().unwrap()
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        if let Some(mut easy) = self.easy.take() {
+            easy.reset();
+            IDLE_HANDLE.with(|idle| *idle.borrow_mut() = Some(easy));
+        }
+    }
+}
+
+// Nested requests get a fresh handle since
+// the idle one is already in use
+pub fn handle() -> Handle {
+    let easy = IDLE_HANDLE
+        .with(|idle| idle.borrow_mut().take())
+        .unwrap_or_else(Easy::new);
+    Handle {
+        easy: Some(easy),
+        auth: None,
+    }
+}
+
+// Gets a new token for the one the server rejected
+pub type Refresh = Box<dyn Fn(&Token) -> Result<Token, SyncError>>;
+
+// Bearer token of a request and how to refresh it
+pub struct Bearer {
+    pub token: Token,
+    pub refresh: Refresh,
+}
+
+// Kept so the headers can be set again with a new token
+struct Auth {
+    bearer: Bearer,
+    headers: Vec<String>,
+}
+
+// Tokens refreshed after a 401 by the access token they replaced, so
+// requests made with a stale token use the new one straight away
+static REFRESHED: Mutex<Vec<(String, Token)>> = Mutex::new(Vec::new());
+
+// Tokens already passed end the walk, a refresh handing
+// back a token seen before would otherwise loop for ever
+fn latest_token(refreshed: &[(String, Token)], token: &Token) -> Token {
+    let mut token = token.clone();
+    let mut visited = vec![token.access_token.clone()];
+    while let Some((_, newer)) = refreshed
+        .iter()
+        .find(|(stale, _)| *stale == token.access_token)
+    {
+        if visited.contains(&newer.access_token) {
+            break;
+        }
+        visited.push(newer.access_token.clone());
+        token = newer.clone();
+    }
+    token
+}
+
+// @Returns the token refreshed in place of this one
+// during a request, or the token itself
+pub fn current_token(token: &Token) -> Token {
+    latest_token(&REFRESHED.lock().unwrap(), token)
+}
+
+// Refreshing under the lock so requests rejected at
+// the same time on other threads share the new token
+pub fn refresh_rejected(bearer: &Bearer, rejected: &str) -> Result<Token, SyncError> {
+    let mut refreshed = REFRESHED.lock().unwrap();
+
+    let current = latest_token(&refreshed, &bearer.token);
+    if current.access_token != rejected {
+        return Ok(current);
+    }
+
+    message!("INFO: Token was rejected, refreshing it");
+    let token = (bearer.refresh)(&current)?;
+    if token.access_token != rejected {
+        refreshed.push((rejected.to_string(), token.clone()));
+    }
+    Ok(token)
+}
+
+// Retries left and requests failed in a row, shared by every
+// request of a sync so a down service isn't retried per file
+struct Breaker {
+    retries_left: u32,
+    max_retries: u32,
+    failures: u32,
+}
+
+impl Breaker {
+    // @Returns whether the request may be tried again after
+    // the failed attempt, using up a retry of the budget
+    fn take_retry(&mut self, attempt: u32) -> bool {
+        if attempt > self.max_retries {
+            return false;
+        }
+
+        let left = self.retries_left > 0;
+        self.retries_left = self.retries_left.saturating_sub(1);
+        left
+    }
+
+    // Failures are server errors, throttling and network errors
+    // left after the retries, other responses reset the count
+    fn record(&mut self, failed: bool) {
+        self.failures = if failed { self.failures + 1 } else { 0 };
+    }
+
+    fn tripped(&self) -> bool {
+        self.failures >= BREAKER_THRESHOLD
+    }
+}
+
+static BREAKER: Mutex<Breaker> = Mutex::new(Breaker {
+    retries_left: DEFAULT_RETRY_BUDGET,
+    max_retries: DEFAULT_MAX_RETRIES,
+    failures: 0,
+});
+
+// Called at the start of every sync
+pub fn reset_breaker(retry_budget: u32, max_retries: u32) {
+    *BREAKER.lock().unwrap() = Breaker {
+        retries_left: retry_budget,
+        max_retries,
+        failures: 0,
+    };
+}
+
+pub fn take_retry(attempt: u32) -> bool {
+    BREAKER.lock().unwrap().take_retry(attempt)
+}
+
+pub fn record_outcome(failed: bool) {
+    BREAKER.lock().unwrap().record(failed);
+}
+
+pub fn breaker_tripped() -> bool {
+    BREAKER.lock().unwrap().tripped()
+}
+
+pub fn breaker_error() -> SyncError {
+    SyncError::Network(format!(
+        "Service looks down, {} requests in a row failed",
+        BREAKER_THRESHOLD
+    ))
+}
+
+impl Handle {
+    // Sets the Authorization header along with the others,
+    // send retries once with a refreshed token on a 401
+    pub fn authorize(&mut self, bearer: Bearer, headers: &[&str]) {
+        let mut bearer = bearer;
+        bearer.token = current_token(&bearer.token);
+
+        self.auth = Some(Auth {
+            bearer,
+            headers: headers.iter().map(|header| header.to_string()).collect(),
+        });
+        self.apply_auth();
+    }
+
+    fn apply_auth(&mut self) {
+        let Some(auth) = &self.auth else {
+            return;
+        };
+
+        let mut headers = List::new();
+        headers
+            .append(&format!(
+                "Authorization:Bearer {}",
+                auth.bearer.token.access_token
+            ))
+            .unwrap();
+        for header in &auth.headers {
+            headers.append(header).unwrap();
+        }
+        self.easy.as_mut().unwrap().http_headers(headers).unwrap();
+    }
+
+    // @Returns whether the request can be sent again with a new token
+    fn reauthorize(&mut self) -> bool {
+        let Some(auth) = &mut self.auth else {
+            return false;
+        };
+
+        let rejected = auth.bearer.token.access_token.clone();
+        match refresh_rejected(&auth.bearer, &rejected) {
+            Ok(token) => {
+                auth.bearer.token = token;
+                self.apply_auth();
+                true
+            }
+            Err(err) => {
+                message!("ERROR: Cannot refresh rejected token: {}", err);
+                false
+            }
+        }
+    }
+}
+
+pub struct Response {
+    pub code: u32,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Deserialize)]
+struct GraphErrorDetails {
+    code: Option<String>,
+    message: Option<String>,
+    retryAfterSeconds: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct GraphError {
+    error: GraphErrorDetails,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.code)
+    }
+
+    fn is_json(&self) -> bool {
+        self.header("Content-Type")
+            .is_none_or(|content_type| content_type.contains("json"))
+    }
+
+    // Text of the body with the tags of html pages left out,
+    // those come from proxies and throttling instead of graph
+    fn preview(&self) -> String {
+        let body = String::from_utf8_lossy(&self.body);
+
+        let mut text = String::new();
+        let mut in_tag = false;
+        for char in body.chars() {
+            match char {
+                '<' => in_tag = true,
+                '>' if in_tag => {
+                    in_tag = false;
+                    text.push(' ');
+                }
+                _ if !in_tag => text.push(char),
+                _ => {}
+            }
+        }
+
+        let words: Vec<&str> = text.split_whitespace().collect();
+        words.join(" ").chars().take(BODY_PREVIEW).collect()
+    }
+
+    // Only a 2xx JSON body is parsed, empty bodies and
+    // error pages get an error saying what came back
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, SyncError> {
+        if !self.is_success() {
+            return Err(SyncError::Api {
+                code: self.code,
+                message: self.preview(),
+            });
+        }
+
+        if self.body.iter().all(u8::is_ascii_whitespace) {
+            return Err(SyncError::Parse(format!(
+                "Expected JSON but got an empty response with status {}",
+                self.code
+            )));
+        }
+
+        if !self.is_json() {
+            return Err(SyncError::Parse(format!(
+                "Expected JSON but got {} with status {}: {}",
+                self.header("Content-Type").unwrap_or_default(),
+                self.code,
+                self.preview()
+            )));
+        }
+
+        serde_json::from_slice(&self.body).map_err(parse_error)
+    }
+
+    // Non 2xx responses become errors carrying
+    // the graph error message when there is one
+    pub fn success(self) -> Result<Response, SyncError> {
+        if self.is_success() {
+            return Ok(self);
+        }
+
+        let message = match serde_json::from_slice::<GraphError>(&self.body) {
+            Ok(error) => format!(
+                "{}: {}",
+                error.error.code.unwrap_or_default(),
+                error.error.message.unwrap_or_default()
+            ),
+            Err(_) => self.preview(),
+        };
+
+        Err(SyncError::Api {
+            code: self.code,
+            message,
+        })
+    }
+}
+
+pub fn parse_error(err: serde_json::Error) -> SyncError {
+    SyncError::Parse(format!("Cannot parse response: {}", err))
+}
+
+// Temporary file successful response bodies are written to instead
+// of memory, for responses too large to buffer. Removed when dropped
+pub struct Spool {
+    path: PathBuf,
+    file: File,
+}
+
+impl Spool {
+    pub fn new() -> Result<Spool, SyncError> {
+        static NEXT_SPOOL: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "cloudsync-{}-{}.spool",
+            std::process::id(),
+            NEXT_SPOOL.fetch_add(1, Ordering::SeqCst)
+        ));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|err| SyncError::Io(format!("Cannot create spool file: {}", err)))?;
+
+        Ok(Spool { path, file })
+    }
+
+    fn clear(&mut self) -> std::io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.rewind()
+    }
+
+    // Reads the body of the last response from the start
+    pub fn reader(&mut self) -> Result<BufReader<&File>, SyncError> {
+        self.file
+            .rewind()
+            .map_err(|err| SyncError::Io(format!("Cannot read spool file: {}", err)))?;
+        Ok(BufReader::new(&self.file))
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn perform_once(
+    handle: &mut Easy,
+    body: Option<&[u8]>,
+    mut spool: Option<&mut Spool>,
+) -> Result<Response, curl::Error> {
+    let mut response = Response {
+        code: 0,
+        headers: Vec::new(),
+        body: Vec::new(),
+    };
+    let mut body = body;
+
+    // Error bodies are small and needed for
+    // retries so only 2xx bodies are spooled
+    let status = Cell::new(0);
+
+    {
+        let mut transfer = handle.transfer();
+        if let Some(body) = body.as_mut() {
+            transfer.read_function(|into| Ok(body.read(into).unwrap()))?;
+        }
+
+        transfer.header_function(|header| {
+            let header = String::from_utf8_lossy(header);
+
+            // Only keeping headers of the last
+            // response when redirects are followed
+            if header.starts_with("HTTP/") {
+                response.headers.clear();
+                let code = header.split_whitespace().nth(1);
+                status.set(code.and_then(|code| code.parse().ok()).unwrap_or(0));
+            } else if let Some((name, value)) = header.split_once(':') {
+                response
+                    .headers
+                    .push((name.trim().to_string(), value.trim().to_string()));
+            }
+            true
+        })?;
+
+        transfer.write_function(|data| {
+            match spool.as_deref_mut() {
+                Some(spool) if (200..300).contains(&status.get()) => {
+                    // Writing less than given aborts the transfer
+                    if spool.file.write_all(data).is_err() {
+                        return Ok(0);
+                    }
+                }
+                _ => response.body.extend_from_slice(data),
+            }
+            Ok(data.len())
+        })?;
+
+        transfer.perform()?;
+    }
+
+    response.code = handle.response_code()?;
+    Ok(response)
+}
+
+// Waits stop doubling here, about a minute
+const MAX_BACKOFF_EXPONENT: u32 = 6;
+
+// Somewhere between half and all of 2^attempt seconds, so clients
+// throttled together don't all come back at the same moment
+pub(crate) fn backoff(attempt: u32) -> Duration {
+    let max = Duration::from_secs(1 << attempt.min(MAX_BACKOFF_EXPONENT));
+    let jitter = OsRng.next_u64() % (max.as_millis() as u64 / 2 + 1);
+    max / 2 + Duration::from_millis(jitter)
+}
+
+// Retry-After is either seconds or a http date
+fn retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let retry_at = parse_http_date(value).ok()?;
+    Some(Duration::from_secs(
+        retry_at.saturating_sub(crate::timestamp()),
+    ))
+}
+
+// Graph asks throttled clients to wait for a specific time
+// either in the Retry-After header or in the error body
+// @Returns none when the response shouldn't be retried
+pub fn retry_delay(
+    code: u32,
+    headers: &[(String, String)],
+    body: &[u8],
+    attempt: u32,
+) -> Option<Duration> {
+    match code {
+        429 | 503 => {
+            let header = headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case("retry-after"))
+                .and_then(|(_, value)| retry_after(value));
+
+            let from_body = || {
+                serde_json::from_slice::<GraphError>(body)
+                    .ok()
+                    .and_then(|error| error.error.retryAfterSeconds)
+                    .map(Duration::from_secs)
+            };
+
+            Some(
+                header
+                    .or_else(from_body)
+                    .unwrap_or_else(|| backoff(attempt)),
+            )
+        }
+        500 | 502 | 504 => Some(backoff(attempt)),
+        _ => None,
+    }
+}
+
+pub(crate) fn is_transient(err: &curl::Error) -> bool {
+    err.is_couldnt_connect()
+        || err.is_couldnt_resolve_host()
+        || err.is_operation_timedout()
+        || err.is_send_error()
+        || err.is_recv_error()
+        || err.is_got_nothing()
+        || err.is_ssl_connect_error()
+        || err.is_partial_file()
+}
+
+// Performs the request on an already configured handle,
+// retrying throttled requests, server errors and network failures
+// @Returns the final response whatever its status code
+pub fn send(handle: &mut Handle, body: Option<&[u8]>) -> Result<Response, SyncError> {
+    send_with(handle, body, None)
+}
+
+// Same as send with a successful body left in the
+// spool, the response body only holds error bodies
+pub fn send_spooled(handle: &mut Handle, spool: &mut Spool) -> Result<Response, SyncError> {
+    send_with(handle, None, Some(spool))
+}
+
+fn send_with(
+    handle: &mut Handle,
+    body: Option<&[u8]>,
+    mut spool: Option<&mut Spool>,
+) -> Result<Response, SyncError> {
+    let mut attempt = 1;
+
+    // A token can expire on the server before it does
+    // locally, those requests get a single retry
+    let mut reauthorized = false;
+
+    if breaker_tripped() {
+        return Err(breaker_error());
+    }
+
+    loop {
+        if let Some(spool) = spool.as_deref_mut() {
+            spool
+                .clear()
+                .map_err(|err| SyncError::Io(format!("Cannot clear spool file: {}", err)))?;
+        }
+
+        let (delay, reason) = match perform_once(handle, body, spool.as_deref_mut()) {
+            Ok(response) if response.code == 401 && !reauthorized => {
+                reauthorized = true;
+                if handle.reauthorize() {
+                    continue;
+                }
+                record_outcome(false);
+                return Ok(response);
+            }
+            Ok(response) => {
+                let delay = retry_delay(response.code, &response.headers, &response.body, attempt);
+                match delay {
+                    Some(delay) if take_retry(attempt) => {
+                        (delay, format!("status {}", response.code))
+                    }
+                    _ => {
+                        record_outcome(delay.is_some());
+                        return Ok(response);
+                    }
+                }
+            }
+            Err(err) if is_transient(&err) && take_retry(attempt) => {
+                (backoff(attempt), err.to_string())
+            }
+            Err(err) => {
+                record_outcome(true);
+                return Err(SyncError::Network(format!(
+                    "Cannot perform request: {}",
+                    err
+                )));
+            }
+        };
+
+        message!(
+            "INFO: Request failed with {}, retrying in {:.1}s",
+            reason,
+            delay.as_secs_f64()
+        );
+        std::thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::http::{
+        handle, latest_token, retry_delay, Breaker, Response, BREAKER_THRESHOLD,
+        DEFAULT_MAX_RETRIES,
+    };
+    use crate::{SyncError, Token};
+
+    #[test]
+    fn test_retry_delay() {
+        let headers = vec![("Retry-After".to_string(), "7".to_string())];
+        let body = br#"{"error":{"code":"activityLimitReached","retryAfterSeconds":12}}"#;
+
+        assert_eq!(
+            retry_delay(429, &headers, body, 1),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(
+            retry_delay(503, &[], body, 1),
+            Some(Duration::from_secs(12))
+        );
+        assert_eq!(retry_delay(404, &headers, body, 1), None);
+
+        // Without a delay from the server the backoff is jittered
+        for _ in 0..20 {
+            let delay = retry_delay(429, &[], b"", 3).unwrap();
+            assert!(delay >= Duration::from_secs(4) && delay <= Duration::from_secs(8));
+        }
+
+        // Many retries don't wait for ever
+        for attempt in [7, 25, 64, u32::MAX] {
+            let delay = retry_delay(500, &[], b"", attempt).unwrap();
+            assert!(delay >= Duration::from_secs(32) && delay <= Duration::from_secs(64));
+        }
+    }
+
+    #[test]
+    fn test_response_json() {
+        let response = |code: u32, content_type: &str, body: &str| Response {
+            code,
+            headers: vec![("content-type".to_string(), content_type.to_string())],
+            body: body.as_bytes().to_vec(),
+        };
+
+        let item = response(201, "application/json; charset=utf-8", r#"{"id":"1"}"#);
+        let item: serde_json::Value = item.json().unwrap();
+        assert_eq!(item["id"], "1");
+
+        let empty = response(204, "application/json", "");
+        assert!(matches!(
+            empty.json::<serde_json::Value>(),
+            Err(SyncError::Parse(_))
+        ));
+
+        // Throttling pages of proxies say what they are
+        let page =
+            "<html><head><title>Too Many Requests</title></head>\n<body>Slow down</body></html>";
+        let Err(SyncError::Parse(message)) =
+            response(200, "text/html", page).json::<serde_json::Value>()
+        else {
+            panic!("html parsed as JSON");
+        };
+        assert_eq!(
+            message,
+            "Expected JSON but got text/html with status 200: Too Many Requests Slow down"
+        );
+
+        let Err(SyncError::Api { code, message }) = response(503, "text/html", page).success()
+        else {
+            panic!("503 succeeded");
+        };
+        assert_eq!(code, 503);
+        assert_eq!(message, "Too Many Requests Slow down");
+    }
+
+    #[test]
+    fn test_breaker() {
+        let mut breaker = Breaker {
+            retries_left: 2,
+            max_retries: DEFAULT_MAX_RETRIES,
+            failures: 0,
+        };
+        assert!(breaker.take_retry(1));
+        assert!(breaker.take_retry(2));
+        assert!(!breaker.take_retry(3));
+
+        // Past the retries of a request the budget is kept
+        let mut breaker = Breaker {
+            retries_left: 2,
+            max_retries: 1,
+            failures: 0,
+        };
+        assert!(breaker.take_retry(1));
+        assert!(!breaker.take_retry(2));
+        assert_eq!(breaker.retries_left, 1);
+
+        // --max-retries 0 never retries
+        breaker.max_retries = 0;
+        assert!(!breaker.take_retry(1));
+        assert_eq!(breaker.retries_left, 1);
+
+        // Only failures in a row trip it
+        for _ in 1..BREAKER_THRESHOLD {
+            breaker.record(true);
+        }
+        breaker.record(false);
+        breaker.record(true);
+        assert!(!breaker.tripped());
+
+        for _ in 1..BREAKER_THRESHOLD {
+            breaker.record(true);
+        }
+        assert!(breaker.tripped());
+    }
+
+    #[test]
+    fn test_latest_token() {
+        let token = |access_token: &str| Token {
+            access_token: access_token.to_string(),
+            refresh_token: String::new(),
+            valid_till: 0,
+        };
+
+        // Tokens refreshed more than once follow the chain
+        let refreshed = vec![("a".to_string(), token("b")), ("b".to_string(), token("c"))];
+        assert_eq!(latest_token(&refreshed, &token("a")).access_token, "c");
+        assert_eq!(latest_token(&refreshed, &token("c")).access_token, "c");
+        assert_eq!(latest_token(&refreshed, &token("x")).access_token, "x");
+
+        // Refreshes handing back a token seen before don't loop
+        let refreshed = vec![("a".to_string(), token("a"))];
+        assert_eq!(latest_token(&refreshed, &token("a")).access_token, "a");
+        let refreshed = vec![("a".to_string(), token("b")), ("b".to_string(), token("a"))];
+        assert_eq!(latest_token(&refreshed, &token("a")).access_token, "b");
+        assert_eq!(latest_token(&refreshed, &token("b")).access_token, "a");
+    }
+
+    #[test]
+    fn test_handle_reused() {
+        let first = handle().raw();
+        assert_eq!(handle().raw(), first);
+
+        // A handle still in use isn't handed out twice
+        let outer = handle();
+        let inner = handle();
+        assert_ne!(outer.raw(), inner.raw());
+    }
+}
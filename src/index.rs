@@ -0,0 +1,155 @@
+// Comparing the local walk with the cloudstate in one pass
+//
+// Both are kept sorted by path, local files under the folder to sync
+// and cloudstate entries relative to it. The shared folder prefix
+// doesn't change the order, so walking both in step pairs every path
+// up without building full paths or looking anything up
+
+use std::{cmp::Ordering, iter::Peekable};
+
+pub struct SortedJoin<'a, L, R, A, B>
+where
+    L: Iterator<Item = (&'a str, A)>,
+    R: Iterator<Item = (&'a str, B)>,
+{
+    local: Peekable<L>,
+    cloud: Peekable<R>,
+}
+
+impl<'a, L, R, A, B> SortedJoin<'a, L, R, A, B>
+where
+    L: Iterator<Item = (&'a str, A)>,
+    R: Iterator<Item = (&'a str, B)>,
+{
+    // Both sides must come sorted by path, BTreeMap iterators do
+    pub fn new(local: L, cloud: R) -> Self {
+        SortedJoin {
+            local: local.peekable(),
+            cloud: cloud.peekable(),
+        }
+    }
+}
+
+impl<'a, L, R, A, B> Iterator for SortedJoin<'a, L, R, A, B>
+where
+    L: Iterator<Item = (&'a str, A)>,
+    R: Iterator<Item = (&'a str, B)>,
+{
+    // @Returns path with what each side has for it
+    type Item = (&'a str, Option<A>, Option<B>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let order = match (self.local.peek(), self.cloud.peek()) {
+            (None, None) => return None,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((local_path, _)), Some((cloud_path, _))) => local_path.cmp(cloud_path),
+        };
+
+        match order {
+            Ordering::Less => {
+                let (file_path, local) = self.local.next()?;
+                Some((file_path, Some(local), None))
+            }
+            Ordering::Greater => {
+                let (file_path, cloud) = self.cloud.next()?;
+                Some((file_path, None, Some(cloud)))
+            }
+            Ordering::Equal => {
+                let (file_path, local) = self.local.next()?;
+                let (_, cloud) = self.cloud.next()?;
+                Some((file_path, Some(local), Some(cloud)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        time::Instant,
+    };
+
+    use crate::{drive_relative_path, index::SortedJoin};
+
+    #[test]
+    fn test_sorted_join() {
+        let local_files = BTreeMap::from([
+            ("/sync/a.txt".to_string(), 1),
+            ("/sync/b/c.txt".to_string(), 2),
+            ("/sync/d.txt".to_string(), 3),
+        ]);
+        let entries = BTreeMap::from([
+            ("/b/c.txt".to_string(), "x"),
+            ("/b/e.txt".to_string(), "y"),
+            ("/d.txt".to_string(), "z"),
+        ]);
+
+        let joined: Vec<_> = SortedJoin::new(
+            local_files
+                .iter()
+                .map(|(file_path, ts)| (drive_relative_path("/sync", file_path), *ts)),
+            entries
+                .iter()
+                .map(|(file_path, id)| (file_path.as_str(), *id)),
+        )
+        .collect();
+
+        assert_eq!(
+            joined,
+            vec![
+                ("/a.txt", Some(1), None),
+                ("/b/c.txt", Some(2), Some("x")),
+                ("/b/e.txt", None, Some("y")),
+                ("/d.txt", Some(3), Some("z")),
+            ]
+        );
+    }
+
+    // cargo test --release -- --ignored --nocapture bench_sorted_join
+    #[test]
+    #[ignore]
+    fn bench_sorted_join() {
+        const FILES: usize = 500_000;
+        let folder_to_sync = "/home/user/sync";
+        let file_path = |i: usize| format!("/dir{}/sub{}/file{}.txt", i % 97, i % 13, i);
+
+        // Every tenth file was deleted locally
+        let local: Vec<(String, u64)> = (0..FILES)
+            .filter(|i| i % 10 != 0)
+            .map(|i| (format!("{}{}", folder_to_sync, file_path(i)), i as u64))
+            .collect();
+        let cloud: Vec<String> = (0..FILES).map(file_path).collect();
+
+        let local_files: HashMap<String, u64> = local.iter().cloned().collect();
+        let entries: HashMap<String, u64> = cloud.iter().map(|p| (p.clone(), 0)).collect();
+        let started = Instant::now();
+        let missing = entries
+            .keys()
+            .filter(|p| !local_files.contains_key(&format!("{}{}", folder_to_sync, p)))
+            .count();
+        let lookups = started.elapsed();
+        assert_eq!(missing, FILES / 10);
+        drop((local_files, entries));
+
+        let local_files: BTreeMap<String, u64> = local.iter().cloned().collect();
+        let entries: BTreeMap<String, u64> = cloud.iter().map(|p| (p.clone(), 0)).collect();
+        let started = Instant::now();
+        let missing = SortedJoin::new(
+            local_files
+                .iter()
+                .map(|(p, ts)| (drive_relative_path(folder_to_sync, p), ts)),
+            entries.iter().map(|(p, ts)| (p.as_str(), ts)),
+        )
+        .filter(|(_, local, cloud)| local.is_none() && cloud.is_some())
+        .count();
+        let joined = started.elapsed();
+        assert_eq!(missing, FILES / 10);
+
+        println!(
+            "{} files: lookups {:?}, sorted join {:?}",
+            FILES, lookups, joined
+        );
+    }
+}
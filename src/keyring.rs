@@ -0,0 +1,35 @@
+// Account secrets kept in the platform's secret store
+//
+// Linux uses the Secret Service, macOS the login keychain and Windows
+// the credential manager, all through the keyring crate. Entries are
+// found by the cloudsync service and the account name
+
+use ::keyring::Entry;
+
+use crate::SyncError;
+
+const SERVICE: &str = "cloudsync";
+
+fn keyring_error(err: impl std::fmt::Display) -> SyncError {
+    SyncError::Io(format!("Keyring: {}", err))
+}
+
+fn entry(account_name: &str) -> Result<Entry, SyncError> {
+    Entry::new(SERVICE, account_name).map_err(keyring_error)
+}
+
+pub fn store(account_name: &str, secret: &str) -> Result<(), SyncError> {
+    entry(account_name)?
+        .set_password(secret)
+        .map_err(keyring_error)
+}
+
+pub fn load(account_name: &str) -> Result<String, SyncError> {
+    entry(account_name)?.get_password().map_err(keyring_error)
+}
+
+pub fn delete(account_name: &str) -> Result<(), SyncError> {
+    entry(account_name)?
+        .delete_credential()
+        .map_err(keyring_error)
+}
@@ -1,21 +1,166 @@
 use std::{
-    collections::HashMap,
-    io::{Read, Seek},
-    os::unix::prelude::FileExt,
-    time::{SystemTime, UNIX_EPOCH},
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::RecvTimeoutError,
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use notify::{RecursiveMode, Watcher};
+
 use serde::{Deserialize, Serialize};
 
+use filter::PathFilter;
+use index::SortedJoin;
+use profile::Profile;
+use progress::Progress;
+use transform::{BomStrip, LineEndings, PdfExport};
+
+// println for messages which can come up while a command writes
+// data to stdout, like token refreshes, retries and config problems
+macro_rules! message {
+    ($($arg:tt)*) => {
+        if crate::MESSAGES_TO_STDERR.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+mod blocks;
+mod crypto;
+mod error;
+mod filter;
+mod gzip;
+mod http;
+mod index;
+mod keyring;
 pub mod onedrive;
+mod profile;
+mod progress;
+mod serve;
+mod shutdown;
+mod transfer;
+mod transform;
+mod watch;
+pub mod webdav;
+mod xattr;
+
+pub use error::SyncError;
 
 const BOLD_START: &str = "\x1b[1m";
 const BOLD_END: &str = "\x1b[0m";
 
+// Set by --color before any output
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+// Set by commands whose stdout is data, like cat
+static MESSAGES_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+// Auto colors terminals unless NO_COLOR is set, an empty one
+// doesn't count, an explicit mode wins over both
+fn color_enabled(mode: ColorMode, is_terminal: bool, no_color: Option<&str>) -> bool {
+    match mode {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        ColorMode::Auto => is_terminal && no_color.is_none_or(str::is_empty),
+    }
+}
+
+pub fn set_color(mode: ColorMode) {
+    let no_color = std::env::var("NO_COLOR").ok();
+    let enabled = color_enabled(mode, std::io::stdout().is_terminal(), no_color.as_deref());
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+// Global options can go anywhere on the command line, they are
+// taken out so commands see their positional arguments in place
+// Assuming args
+// clousync ... [--color=<auto|always|never>] ...
+// clousync ... [--color <auto|always|never>] ...
+pub fn take_color_option(args: &mut Vec<String>) -> Result<ColorMode, SyncError> {
+    let mut mode = ColorMode::Auto;
+
+    let mut index = 0;
+    while index < args.len() {
+        let value = if let Some(value) = args[index].strip_prefix("--color=") {
+            let value = value.to_string();
+            args.remove(index);
+            value
+        } else if args[index] == "--color" {
+            args.remove(index);
+            if index >= args.len() {
+                return Err(SyncError::Usage(
+                    "--color expects auto, always or never".to_string(),
+                ));
+            }
+            args.remove(index)
+        } else {
+            index += 1;
+            continue;
+        };
+
+        mode = match value.as_str() {
+            "auto" => ColorMode::Auto,
+            "always" => ColorMode::Always,
+            "never" => ColorMode::Never,
+            _ => {
+                return Err(SyncError::Usage(format!(
+                    "--color expects auto, always or never, got {}",
+                    value
+                )))
+            }
+        };
+    }
+
+    Ok(mode)
+}
+
+fn bold(text: &str) -> String {
+    if COLOR.load(Ordering::Relaxed) {
+        format!("{}{}{}", BOLD_START, text, BOLD_END)
+    } else {
+        text.to_string()
+    }
+}
+
+// Metadata files cloudsync keeps inside the synced folder,
+// these are never uploaded or overwritten by downloads
+// .cloudstate      last known state of the synced files
+// .cloudignore     patterns of files to leave out of the sync
+// .cloudsync.lock  held while a sync is running
+// .cloudstate.N    cloudstate of an earlier sync, see rotate_cloudstate
+// <name>.cloudsync-meta.json  extended attributes of <name>, see xattr
+// .<name>.<pid>.cloudsync-tmp  download in progress, see write_atomic
+const RESERVED_NAMES: &[&str] = &[".cloudstate", ".cloudignore", ".cloudsync.lock", TRASH_DIR];
+
+// Set after a sync which left failed transfers behind,
+// which makes the next sync a full one
+pub const SYNC_ERRORS_KEY: &str = "sync_errors";
+
+// Local deletes go here with --trash
+const TRASH_DIR: &str = ".cloudsync-trash";
+
+const TEMP_SUFFIX: &str = ".cloudsync-tmp";
+
 #[derive(Serialize, Deserialize, Clone)]
 pub enum SyncService {
     GDrive,
     Onedrive,
+    WebDav,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -36,542 +181,6206 @@ pub struct DriveDelta {
     pub cloud_id: String,
     pub file_path: String,
     pub last_modified: u64,
+
+    // Creation time on the client which uploaded
+    // the file, if the provider keeps it
+    pub created: Option<u64>,
+
+    // Tag which only changes with the contents
+    pub ctag: Option<String>,
+
+    // Bytes of the contents, if the provider lists them
+    pub size: Option<u64>,
     pub delta_type: DriveDeltaType,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Account {
-    pub service: SyncService,
-    pub token: Token,
-    pub last_synced: u64,
-    pub attributes: HashMap<String, String>,
+// Child of a cloud folder listed by ls
+#[derive(Debug, PartialEq)]
+pub struct FolderEntry {
+    pub name: String,
+    pub is_folder: bool,
+    pub size: Option<u64>,
+    pub last_modified: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Config {
-    accounts: HashMap<String, Account>,
-}
+// Counters collected during a sync
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct SyncReport {
+    pub uploaded: u32,
+    pub downloaded: u32,
+    pub deleted_local: u32,
+    pub deleted_remote: u32,
+    pub errors: u32,
+    pub duration_ms: u128,
 
-#[derive(Serialize, Deserialize)]
-struct CloudStateEntry {
-    cloud_id: String,
-    last_modified: u64,
+    // The sync stopped early because the service looked down
+    pub breaker_tripped: bool,
+
+    // Transfers left for the next sync by --max-files
+    pub deferred: u32,
+
+    // Files uploaded or copied in this sync
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub uploads: Vec<UploadedFile>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct CloudState {
-    entries: HashMap<String, CloudStateEntry>,
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadedFile {
+    pub path: String,
+    pub cloud_id: String,
 }
 
-pub fn urlencode(data: &str) -> String {
-    data.replace(' ', "%20")
+// Single line meant for log scraping
+impl std::fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "SYNC_SUMMARY uploaded={} downloaded={} deleted_local={} deleted_remote={} errors={} duration_ms={} breaker_tripped={} deferred={}",
+            self.uploaded,
+            self.downloaded,
+            self.deleted_local,
+            self.deleted_remote,
+            self.errors,
+            self.duration_ms,
+            self.breaker_tripped,
+            self.deferred
+        )
+    }
 }
 
-#[derive(Default)]
-struct SyncFlags {
-    fresh: bool,
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Account {
+    pub service: SyncService,
+    pub token: Token,
+    pub last_synced: u64,
+    pub attributes: HashMap<String, String>,
 }
 
-// Assuming args
-// clousync sync <folder> <account_name> [--fresh/-f]
-pub fn sync(args: &Vec<String>) -> Result<(), String> {
-    if args.len() < 4 {
-        return Err("Incorrect no of arguments".to_string());
+impl Account {
+    // New accounts have never been synced
+    pub fn new(service: SyncService, token: Token) -> Self {
+        Account {
+            service,
+            token,
+            last_synced: 0,
+            attributes: HashMap::new(),
+        }
     }
 
-    let folder = &args[2];
-    let account_name = &args[3];
+    pub fn with_attributes(mut self, attributes: HashMap<String, String>) -> Self {
+        self.attributes.extend(attributes);
+        self
+    }
 
-    let folder_path = std::fs::canonicalize(folder)
-        .map_err(|err| format!("Cannot sync to {} because: {}", folder, err))?;
+    fn uses_keyring(&self) -> bool {
+        self.attributes
+            .get(KEYRING_KEY)
+            .is_some_and(|value| value == "true")
+    }
 
-    let mut sync_flags = SyncFlags::default();
+    fn has_secrets(&self) -> bool {
+        !self.token.access_token.is_empty()
+            || !self.token.refresh_token.is_empty()
+            || SECRET_ATTRIBUTES
+                .iter()
+                .any(|key| self.attributes.contains_key(*key))
+    }
 
-    // Parsing flags
-    // Flags come after the positional arguments
-    for flag in args.iter().skip(4) {
-        match flag.as_str() {
-            "--fresh" | "-f" => sync_flags.fresh = true,
-            _ => {
-                return Err("Invalid flags".to_string());
-            }
+    // Blanks are left in the account in their place
+    // @Returns the secrets as kept in the keyring
+    fn take_secrets(&mut self) -> String {
+        let secrets = Secrets {
+            token: Token {
+                access_token: std::mem::take(&mut self.token.access_token),
+                refresh_token: std::mem::take(&mut self.token.refresh_token),
+                valid_till: self.token.valid_till,
+            },
+            attributes: SECRET_ATTRIBUTES
+                .iter()
+                .filter_map(|key| {
+                    let value = self.attributes.remove(*key)?;
+                    Some((key.to_string(), value))
+                })
+                .collect(),
         };
+        serde_json::to_string(&secrets).unwrap()
     }
 
-    let folder_path_str = folder_path.to_string_lossy().to_string();
+    fn restore_secrets(&mut self, secrets: &str) -> Result<(), SyncError> {
+        let secrets: Secrets = serde_json::from_str(secrets)
+            .map_err(|err| SyncError::Parse(format!("Invalid secrets: {}", err)))?;
+        self.token = secrets.token;
+        self.attributes.extend(secrets.attributes);
+        Ok(())
+    }
+}
 
-    let config_path = config_path();
-    let config_data = std::fs::read_to_string(config_path)
-        .map_err(|err| format!("Cannot read config: {}", err))?;
+// Accounts with it keep their token and passwords in the
+// platform's secret store instead of the config
+pub const KEYRING_KEY: &str = "keyring";
 
-    let mut config: Config = serde_json::from_str(config_data.as_str())
-        .map_err(|err| format!("Cannot read config: {}", err))?;
+// Folder synced when sync, daemon and watch are only given the account
+pub const DEFAULT_FOLDER_KEY: &str = "default_folder";
 
-    if let Some(account) = config.accounts.get_mut(account_name) {
-        sync_files(account, account_name, &folder_path_str, &sync_flags)?;
-    } else {
-        return Err("Unknown account name please login first".to_string());
-    }
+// Attributes which go to the keyring along with the token
+const SECRET_ATTRIBUTES: &[&str] = &["password", "client_secret"];
 
-    Ok(())
+#[derive(Serialize, Deserialize)]
+struct Secrets {
+    token: Token,
+    attributes: BTreeMap<String, String>,
 }
 
-// Assuming args
-// clousync login <gdrive|onedrive>
-pub fn login(args: &Vec<String>) -> Result<(), String> {
-    if args.len() < 3 {
-        return Err("Incorrect no of arguments".to_string());
-    }
+// Secrets last read from or written to the keyring by account
+// name, unchanged ones aren't written again with every config
+static KEYRING_CACHE: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
 
-    match args[2].as_str() {
-        "onedrive" => {
-            let login_url = onedrive::get_oauth_url();
-            println!(
-                "{}Copy paste this url to browser{}: \n\n{}",
-                BOLD_START, BOLD_END, login_url
-            );
-        }
-        "gdrive" => todo!(),
-        _ => {
-            return Err("Please specify a service".to_string());
-        }
-    };
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct Config {
+    accounts: HashMap<String, Account>,
 
-    Ok(())
+    // Custom oauth app registration
+    #[serde(default)]
+    onedrive: onedrive::ClientConfig,
+
+    // Folders synced by serve
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    folders: Vec<WatchedFolder>,
 }
 
-// Assuming args
-// clousync save <gdrive|onedrive> <account_name> <auth_code>
-pub fn save(args: &Vec<String>) -> Result<(), String> {
-    if args.len() < 5 {
-        return Err("Incorrect no of arguments".to_string());
-    }
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+struct WatchedFolder {
+    folder: String,
+    account: String,
+}
 
-    let service = match args[2].as_str() {
-        "gdrive" => SyncService::GDrive,
-        "onedrive" => SyncService::Onedrive,
-        _ => {
-            return Err("Incorrect sync service".to_string());
-        }
-    };
+// Transfer which failed on the last sync
+// and has to be retried on the next one
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+enum PendingTransfer {
+    Upload,
+    Download,
+}
 
-    let account_name = &args[3];
-    let auth_code = &args[4];
-    let token = match service {
-        SyncService::GDrive => todo!(),
-        SyncService::Onedrive => onedrive::get_token(auth_code, "authorization_code"),
-    }?;
+#[derive(Serialize, Deserialize, Clone)]
+struct CloudStateEntry {
+    cloud_id: String,
+    last_modified: u64,
 
-    let account = Account {
-        service: SyncService::Onedrive,
-        token,
-        last_synced: 0,
-        attributes: HashMap::new(),
-    };
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pending: Option<PendingTransfer>,
 
-    save_account(account_name, &account)?;
-    println!("INFO: Account saved");
+    // Content tag of the last download, unknown for uploads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ctag: Option<String>,
 
-    Ok(())
-}
+    // Cloud id of the extended attributes sidecar
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    xattrs: Option<String>,
 
-fn config_path() -> String {
-    // TODO: figure out home dir for windows
-    let home = std::env!("HOME");
-    format!("{home}/.config/cloudsync.json")
+    // Hashes of the uploaded contents for --delta-upload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blocks: Option<blocks::BlockHashes>,
+
+    // Local user of the last upload for --record-owner
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owner: Option<FileOwner>,
 }
 
-// NOTE: We're cloning the entire account struct
-// So this will be a costly operation
-fn save_account(account_name: &str, account: &Account) -> Result<(), String> {
-    let config_path = config_path();
-    let mut config_file = std::fs::File::options()
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(config_path)
-        .map_err(|err| format!("Cannot create config file: {}", err))?;
-
-    let mut config_data = String::new();
-    config_file
-        .read_to_string(&mut config_data)
-        .map_err(|err| format!("Cannot read config file: {}", err))?;
-
-    let mut config = match serde_json::from_str::<Config>(config_data.as_str()) {
-        Ok(config) => config,
-        Err(_) => Config {
-            accounts: HashMap::new(),
-        },
-    };
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+struct FileOwner {
+    uid: u32,
+    gid: u32,
 
-    config
-        .accounts
-        .insert(account_name.to_owned(), account.clone());
-    config_data = serde_json::to_string(&config).unwrap();
+    // Users without a passwd entry only have ids
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+}
 
-    config_file
-        .write_all_at(config_data.as_bytes(), 0)
-        .map_err(|err| format!("Cannot write config to file: {}", err))?;
+// Bumped whenever the cloudstate layout changes, older
+// files are migrated and newer ones refused
+const CLOUDSTATE_VERSION: u32 = 1;
 
-    Ok(())
-}
+#[derive(Serialize, Deserialize)]
+struct CloudState {
+    // Files from before versioning have none
+    #[serde(default)]
+    version: u32,
 
-fn refresh_token(account: &mut Account) -> Result<(), String> {
-    let token = match account.service {
-        SyncService::GDrive => todo!(),
-        SyncService::Onedrive => {
-            onedrive::get_token(account.token.refresh_token.as_str(), "refresh_token")
-        }
-    }?;
+    entries: BTreeMap<String, CloudStateEntry>,
 
-    account.token = token;
-    Ok(())
+    // Interrupted large uploads by relative path
+    #[serde(default)]
+    uploads: HashMap<String, onedrive::UploadSession>,
+
+    // Written gzip compressed, read files keep how they were stored
+    #[serde(skip)]
+    compressed: bool,
 }
 
-// Recursively walk through
-fn read_dir_rec(folder: &str, files: &mut HashMap<String, u64>) -> std::io::Result<()> {
-    let dir_entries = std::fs::read_dir(folder)?;
+pub fn urlencode(data: &str) -> String {
+    data.replace(' ', "%20")
+}
 
-    for entry in dir_entries.flatten() {
-        let metadata = entry.metadata()?;
-        let file_path = entry.path().to_str().unwrap().to_string();
+// Query string values, '+' is a space
+pub fn urldecode(data: &str) -> String {
+    let bytes = data.as_bytes();
+    let mut decoded = Vec::new();
 
-        if metadata.is_dir() {
-            read_dir_rec(&file_path, files)?;
-        } else {
-            let last_modified = metadata
-                .modified()?
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = data
+            .get(index + 1..index + 3)
+            .filter(|_| bytes[index] == b'%')
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
 
-            files.insert(file_path, last_modified);
+        match (escaped, bytes[index]) {
+            (Some(byte), _) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            (None, b'+') => {
+                decoded.push(b' ');
+                index += 1;
+            }
+            (None, byte) => {
+                decoded.push(byte);
+                index += 1;
+            }
         }
     }
 
-    Ok(())
+    String::from_utf8_lossy(&decoded).to_string()
 }
 
-fn timestamp() -> u64 {
-    let start = SystemTime::now();
-    start.duration_since(UNIX_EPOCH).unwrap().as_secs()
-}
+// Filesystem mtimes and our own timestamps are both truncated
+// to seconds, anything within this window is treated as unchanged
+const DEFAULT_MTIME_TOLERANCE: u64 = 0;
+const DEFAULT_JOBS: usize = 4;
+const DEFAULT_DELETE_JOBS: usize = 4;
+const DEFAULT_STATE_BACKUPS: usize = 3;
 
-fn sync_files(
-    account: &mut Account,
-    account_name: &str,
-    folder_to_sync: &String,
-    sync_flags: &SyncFlags,
-) -> Result<(), String> {
-    println!("Syncing {} to {}", folder_to_sync, account_name);
+// Change detection compares local mtimes with cloud
+// timestamps so a wrong clock leads to wrong decisions
+const CLOCK_SKEW_THRESHOLD: u64 = 120;
 
-    let now = timestamp();
-    if now > account.token.valid_till {
-        println!("INFO: Token refreshed");
-        refresh_token(account)?;
-    }
+// Seconds between two rounds of serve or daemon
+const DEFAULT_SERVE_INTERVAL: u64 = 300;
 
-    if sync_flags.fresh {
-        account.last_synced = 0;
-        account.attributes = HashMap::new();
-    }
+// Longest wait of daemon after repeated failures
+const MAX_DAEMON_BACKOFF: u64 = 60 * 60;
 
-    // Getting local changes
-    let mut local_files = HashMap::new();
-    read_dir_rec(folder_to_sync, &mut local_files)
-        .map_err(|err| format!("Cannot walk folder to sync: {}", err))?;
+// Seconds watch waits for changes to settle, and
+// at most after the first change of a burst
+const DEFAULT_WATCH_DEBOUNCE: u64 = 2;
+const MAX_WATCH_DELAY: u64 = 60;
 
-    // Deleting local files incase of
-    // fresh sync
-    if sync_flags.fresh {
-        println!("INFO: Cleaning up local files {}", local_files.len());
+// Side kept when a file changed both locally
+// and in the cloud since the last sync
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Prefer {
+    // Later modification wins
+    Newer,
+    Local,
+    Remote,
+}
 
-        for file_path in local_files.keys() {
-            std::fs::remove_file(file_path)
-                .map_err(|err| format!("Cannot remove file: {}", err))?;
+impl Prefer {
+    fn parse(value: &str) -> Result<Self, SyncError> {
+        match value {
+            "local" => Ok(Prefer::Local),
+            "remote" => Ok(Prefer::Remote),
+            _ => Err(SyncError::Usage(format!(
+                "--prefer expects local or remote, got {}",
+                value
+            ))),
         }
+    }
 
-        local_files = HashMap::new();
+    // Fresh syncs take the cloud unless told otherwise
+    fn effective(prefer: Option<Prefer>, fresh: bool) -> Self {
+        match prefer {
+            Some(prefer) => prefer,
+            None if fresh => Prefer::Remote,
+            None => Prefer::Newer,
+        }
     }
 
-    // Creating a scope so file is closed
-    // Before we update the last sync
-    {
-        println!("INFO: Reading cloudstate");
+    // Missing local files are modified at 0
+    // @Returns whether the cloud change is applied locally
+    fn cloud_wins(
+        self,
+        cloud_modified: u64,
+        local_modified: u64,
+        last_synced: u64,
+        tolerance: u64,
+    ) -> bool {
+        match self {
+            Prefer::Newer => cloud_modified > local_modified,
+            Prefer::Remote => true,
+            Prefer::Local => !is_newer(local_modified, last_synced, tolerance),
+        }
+    }
+}
 
-        let cloudstate_file_path = format!("{}/.cloudstate", folder_to_sync);
-        let mut cloudstate_file = std::fs::File::options()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(cloudstate_file_path)
-            .map_err(|err| err.to_string())?;
-
-        let mut cloudstate = if !sync_flags.fresh {
-            match serde_json::from_reader(&cloudstate_file) {
-                Ok(state) => state,
-                Err(_) => CloudState {
-                    entries: HashMap::new(),
-                },
-            }
-        } else {
-            CloudState {
-                entries: HashMap::new(),
-            }
-        };
+struct SyncFlags {
+    fresh: bool,
+    mtime_tolerance: u64,
+    since: Option<u64>,
+    jobs: usize,
+    delete_jobs: usize,
+    includes: Vec<String>,
 
-        // Getting cloud changes
-        let deltas = match account.service {
-            SyncService::GDrive => todo!(),
-            SyncService::Onedrive => onedrive::get_drive_delta(account)?,
-        };
+    // Cloud paths under these are out of the sync on both sides
+    remote_ignores: Vec<String>,
 
-        println!("INFO: Cloud Delta {}", deltas.len());
-        println!("INFO: Cloud files {}", cloudstate.entries.len());
-        println!("INFO: Local files {}", local_files.len());
+    // Only cloud changes of matching paths are applied,
+    // uploads still cover everything else
+    download_includes: Vec<String>,
+    strict_clock: bool,
+    state_dir: Option<String>,
+    stateless: bool,
+    max_depth: Option<usize>,
 
-        for delta in &deltas {
-            // Skip the cloud sync cloud we have
-            // already have this file from the last sync
-            if account.last_synced >= delta.last_modified {
-                continue;
-            }
+    // Local is the source of truth, nothing is
+    // downloaded or deleted locally
+    backup: bool,
 
-            let (folder, _) = delta.file_path.rsplit_once('/').unwrap();
-            let file_path = delta.file_path.clone();
-            let full_file_path = format!("{}{}", folder_to_sync, file_path);
-            let local_modified = local_files.get(&full_file_path).map_or(0, |val| *val);
+    // Syncs the contents of shared folders
+    // mounted into a onedrive
+    follow_shared: bool,
 
-            // Making sure cloud files get priotity on
-            // fresh fetch
-            let cloud_modified = if sync_flags.fresh {
-                timestamp()
-            } else {
-                delta.last_modified
-            };
+    // Line endings of text files are normalized
+    // on upload and download when set
+    line_endings: Option<LineEndings>,
 
-            match delta.delta_type {
-                DriveDeltaType::Deleted => {
-                    if cloud_modified > local_modified {
-                        println!("INFO: Deleting local file {}", full_file_path);
+    // Leading UTF-8 BOMs are left out of downloaded text files
+    bom_strip: Option<BomStrip>,
 
-                        match std::fs::remove_file(&full_file_path) {
-                            Ok(_) => {
-                                local_files.remove(&full_file_path);
-                            }
-                            Err(err) => {
-                                println!("ERROR: Cannot remove file: {}", err)
-                            }
-                        };
+    // Allows syncing folders like / or home
+    force: bool,
 
-                        cloudstate.entries.remove(&file_path);
-                    }
-                }
-                DriveDeltaType::CreatedOrModifiled => {
-                    if cloud_modified > local_modified {
-                        println!("INFO: Downloading {}", file_path);
+    // Number of earlier cloudstates kept
+    state_backups: usize,
 
-                        let full_folder_path = format!("{}/{}", folder_to_sync, folder);
-                        std::fs::create_dir_all(&full_folder_path)
-                            .map_err(|err| err.to_string())?;
+    // Local deletes are moved to the trash folder
+    trash: bool,
 
-                        let response = match account.service {
-                            SyncService::GDrive => todo!(),
-                            SyncService::Onedrive => onedrive::download_file(account, &file_path),
-                        };
+    // Report is printed as JSON
+    json: bool,
 
-                        match response {
-                            Ok(contents) => {
-                                std::fs::write(&full_file_path, contents)
-                                    .map_err(|err| err.to_string())?;
+    // Extended attributes are kept in sidecars
+    sync_xattrs: bool,
 
-                                let ts = timestamp();
-                                cloudstate.entries.insert(
-                                    file_path,
-                                    CloudStateEntry {
-                                        cloud_id: delta.cloud_id.to_string(),
-                                        last_modified: ts,
-                                    },
-                                );
-                                local_files.insert(full_file_path, ts);
-                            }
-                            Err(err) => {
-                                println!("ERROR: Downloading file {}", err);
-                            }
-                        };
-                    } else {
-                        cloudstate.entries.remove(&file_path);
-                    }
-                }
-            }
-        }
+    // Only changed blocks of large files are
+    // uploaded where the backend allows it
+    delta_upload: bool,
 
-        // Uploading locally modified files
-        for (file_path, local_modified) in &local_files {
-            let local_modified = *local_modified;
-            let drive_relative_path = file_path.split(folder_to_sync).last().unwrap();
+    // Downloads are written here before being
+    // moved over the file, the file's folder if unset
+    tmp_dir: Option<String>,
 
-            let result = cloudstate.entries.get(drive_relative_path);
-            let is_file_modified = result.is_some()
-                && local_modified > account.last_synced
-                && local_modified > result.unwrap().last_modified;
+    // Office documents with these extensions
+    // are downloaded as PDFs converted by onedrive
+    export_pdf: Option<PdfExport>,
 
-            if is_file_modified || result.is_none() {
-                match std::fs::read(file_path) {
-                    Ok(file_contents) => {
-                        println!("INFO: Uploading {}", file_path);
+    // Retries all requests of the sync may make together
+    retry_budget: u32,
 
-                        let response = match account.service {
-                            SyncService::GDrive => todo!(),
-                            SyncService::Onedrive => onedrive::upload_new_file(
-                                account,
-                                drive_relative_path,
-                                &file_contents,
-                            ),
-                        };
+    // Retries of each failed request, 0 fails fast
+    max_retries: u32,
 
-                        match response {
-                            Ok(cloud_id) => {
-                                let ts = timestamp();
-                                cloudstate.entries.insert(
-                                    drive_relative_path.to_string(),
-                                    CloudStateEntry {
-                                        cloud_id,
-                                        last_modified: ts,
-                                    },
-                                );
-                            }
-                            Err(err) => {
-                                println!("ERROR: Uploading file: {}", err);
-                            }
-                        };
-                    }
-                    Err(err) => {
-                        println!("ERROR: Reading file {}: {}", file_path, err);
-                    }
-                }
-            }
-        }
+    // Tiebreak of conflicting changes, by default
+    // the newer one or the cloud on fresh syncs
+    prefer: Option<Prefer>,
 
-        // Removing cloud files
-        let mut cloudfiles_to_deleted = Vec::new();
-        {
-            for file_path in cloudstate.entries.keys() {
-                let entry = &cloudstate.entries.get(file_path).unwrap();
-                let full_file_path = format!("{}{}", folder_to_sync, file_path);
+    // Cloud folders left empty by deletes are removed
+    prune_empty_dirs: bool,
 
-                if local_files.get(&full_file_path).is_none() {
-                    println!("INFO: Cloud deleting file {}", file_path);
+    // Uploads and downloads done by one sync,
+    // the rest are done by the next ones
+    max_files: Option<usize>,
 
-                    let response = match account.service {
-                        SyncService::GDrive => todo!(),
-                        SyncService::Onedrive => onedrive::delete_file(account, &entry.cloud_id),
-                    };
+    // Downloads must fit in the free space of the
+    // folder, fresh syncs check it if unset
+    check_space: Option<bool>,
 
-                    match response {
-                        Ok(_) => {}
-                        Err(err) => {
-                            println!("ERROR: Cloud deleting file: {}", err);
-                        }
-                    };
-                    cloudfiles_to_deleted.push(file_path.clone());
-                }
-            }
-        }
+    // Files done out of all and an ETA are
+    // printed while transferring
+    progress: bool,
 
-        for file_path in cloudfiles_to_deleted {
-            cloudstate.entries.remove(&file_path);
-        }
+    // Time spent per phase is printed at the end
+    profile: bool,
 
-        // Truncating the file
-        cloudstate_file
-            .set_len(0)
-            .map_err(|err| format!("Cannot write to file: {}", err))?;
-        cloudstate_file.seek(std::io::SeekFrom::Start(0)).unwrap();
+    // Cloudstate is written gzip compressed
+    compress_state: bool,
 
-        serde_json::to_writer(cloudstate_file, &cloudstate).map_err(|err| err.to_string())?;
-    }
+    // Onedrive delta link or token the cloud changes
+    // are read from instead of the stored delta link
+    since_delta: Option<String>,
 
-    // Save changes to account
-    account.last_synced = timestamp();
-    save_account(account_name, account)?;
+    // Uploads note the local user in the cloudstate
+    record_owner: bool,
 
-    Ok(())
+    // The account is saved as it was before the sync, so
+    // the next one sees the same cloud changes again
+    no_advance: bool,
 }
 
-// Assuming date 2023-08-06T13:23:00.093Z (ISO format)
-// @Returns unix timestamp
-fn parse_iso_date(date_time_str: &str) -> u64 {
-    let (date_str, time_str) = date_time_str.split_once('T').unwrap();
-    let date_tokens: Vec<&str> = date_str.split('-').collect();
-
-    let year: u64 = date_tokens[0].parse().unwrap();
-    let month: u64 = date_tokens[1].parse().unwrap();
-    let date: u64 = date_tokens[2].parse().unwrap();
+impl Default for SyncFlags {
+    fn default() -> Self {
+        SyncFlags {
+            fresh: false,
+            mtime_tolerance: DEFAULT_MTIME_TOLERANCE,
+            since: None,
+            jobs: DEFAULT_JOBS,
+            delete_jobs: DEFAULT_DELETE_JOBS,
+            includes: Vec::new(),
+            remote_ignores: Vec::new(),
+            download_includes: Vec::new(),
+            strict_clock: false,
+            state_dir: None,
+            stateless: false,
+            max_depth: None,
+            backup: false,
+            follow_shared: false,
+            line_endings: None,
+            bom_strip: None,
+            force: false,
+            state_backups: DEFAULT_STATE_BACKUPS,
+            trash: false,
+            json: false,
+            sync_xattrs: false,
+            delta_upload: false,
+            tmp_dir: None,
+            export_pdf: None,
+            retry_budget: http::DEFAULT_RETRY_BUDGET,
+            max_retries: http::DEFAULT_MAX_RETRIES,
+            prefer: None,
+            prune_empty_dirs: false,
+            max_files: None,
+            check_space: None,
+            progress: false,
+            profile: false,
+            compress_state: false,
+            since_delta: None,
+            record_owner: false,
+            no_advance: false,
+        }
+    }
+}
+
+// Download decided on while walking the delta
+struct PendingDownload {
+    cloud_id: String,
+    full_file_path: String,
+    full_folder_path: String,
+    created: Option<u64>,
+    ctag: Option<String>,
+
+    // Unknown for retries of failed downloads
+    size: u64,
+}
+
+// Assuming args
+// clousync sync [folder] <account_name> [--fresh/-f] [--mtime-tolerance <secs>] [--since <date>]
+//                [--jobs/-j <n>] [--include <glob>]... [--strict-clock] [--state-dir <path>]
+//                [--stateless] [--delete-jobs <n>] [--max-depth <n>] [--backup]
+//                [--follow-shared] [--crlf-normalize [--text-ext <ext>]...] [--force]
+//                [--state-backups <n>] [--trash] [--json] [--sync-xattrs]
+//                [--delta-upload] [--tmp-dir <path>] [--export-pdf <ext>]...
+//                [--retry-budget <n>] [--prune-empty-dirs] [--max-files <n>]
+//                [--check-space/--no-check-space] [--progress] [--profile]
+//                [--remote-ignore <prefix>]... [--no-advance] [--compress-state]
+//                [--since-delta <link_or_token>] [--record-owner] [--max-retries <n>]
+//                [--prefer <local|remote>] [--strip-bom [--text-ext <ext>]...]
+//                [--download-include <glob>]...
+pub fn sync(args: &[String]) -> Result<SyncReport, SyncError> {
+    let (folder_path, account_name, flags) = folder_and_account(args)?;
+
+    let sync_flags = parse_sync_flags(flags, &folder_path)?;
+    let report = sync_folder(&folder_path, account_name, &sync_flags)?;
+    print_report(&report, sync_flags.json);
+    Ok(report)
+}
+
+// JSON reports also list the uploaded
+// files with their cloud ids
+fn print_report(report: &SyncReport, json: bool) {
+    if json {
+        println!("{}", serde_json::to_string(report).unwrap());
+    } else {
+        println!("{}", report);
+    }
+}
+
+// Flags come after the positional arguments
+fn parse_sync_flags(args: &[String], folder_path: &Path) -> Result<SyncFlags, SyncError> {
+    let mut sync_flags = SyncFlags::default();
+    let mut crlf_normalize = false;
+    let mut strip_bom = false;
+    let mut text_extensions = Vec::new();
+    let mut export_extensions = Vec::new();
+
+    let mut flags = args.iter();
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--fresh" | "-f" => sync_flags.fresh = true,
+            "--strict-clock" => sync_flags.strict_clock = true,
+            "--stateless" | "--no-cloudstate" => sync_flags.stateless = true,
+            "--backup" => sync_flags.backup = true,
+            "--follow-shared" => sync_flags.follow_shared = true,
+            "--crlf-normalize" => crlf_normalize = true,
+            "--strip-bom" => strip_bom = true,
+            "--force" => sync_flags.force = true,
+            "--trash" => sync_flags.trash = true,
+            "--json" => sync_flags.json = true,
+            "--sync-xattrs" => sync_flags.sync_xattrs = true,
+            "--delta-upload" => sync_flags.delta_upload = true,
+            "--prune-empty-dirs" => sync_flags.prune_empty_dirs = true,
+            "--text-ext" => {
+                let extension = flags.next().ok_or(SyncError::Usage(
+                    "--text-ext expects an extension".to_string(),
+                ))?;
+                text_extensions.push(extension.clone());
+            }
+            "--export-pdf" => {
+                let extension = flags.next().ok_or(SyncError::Usage(
+                    "--export-pdf expects an extension".to_string(),
+                ))?;
+                export_extensions.push(extension.clone());
+            }
+            "--mtime-tolerance" => {
+                sync_flags.mtime_tolerance =
+                    flags
+                        .next()
+                        .and_then(|val| val.parse().ok())
+                        .ok_or(SyncError::Usage(
+                            "--mtime-tolerance expects seconds".to_string(),
+                        ))?;
+            }
+            "--jobs" | "-j" => {
+                sync_flags.jobs = flags
+                    .next()
+                    .and_then(|val| val.parse().ok())
+                    .filter(|jobs| *jobs > 0)
+                    .ok_or(SyncError::Usage(
+                        "--jobs expects a number greater than 0".to_string(),
+                    ))?;
+            }
+            "--delete-jobs" => {
+                sync_flags.delete_jobs = flags
+                    .next()
+                    .and_then(|val| val.parse().ok())
+                    .filter(|jobs| *jobs > 0)
+                    .ok_or(SyncError::Usage(
+                        "--delete-jobs expects a number greater than 0".to_string(),
+                    ))?;
+            }
+            "--state-backups" => {
+                sync_flags.state_backups =
+                    flags
+                        .next()
+                        .and_then(|val| val.parse().ok())
+                        .ok_or(SyncError::Usage(
+                            "--state-backups expects a number".to_string(),
+                        ))?;
+            }
+            "--progress" => sync_flags.progress = true,
+            "--profile" => sync_flags.profile = true,
+            "--no-advance" => sync_flags.no_advance = true,
+            "--compress-state" => sync_flags.compress_state = true,
+            "--record-owner" => sync_flags.record_owner = true,
+            "--prefer" => {
+                let prefer = flags.next().ok_or(SyncError::Usage(
+                    "--prefer expects local or remote".to_string(),
+                ))?;
+                sync_flags.prefer = Some(Prefer::parse(prefer)?);
+            }
+            flag if flag.starts_with("--prefer=") => {
+                sync_flags.prefer = Some(Prefer::parse(&flag["--prefer=".len()..])?);
+            }
+            "--since-delta" => {
+                let since_delta = flags.next().ok_or(SyncError::Usage(
+                    "--since-delta expects a delta link or token".to_string(),
+                ))?;
+                sync_flags.since_delta = Some(since_delta.clone());
+            }
+            "--check-space" => sync_flags.check_space = Some(true),
+            "--no-check-space" => sync_flags.check_space = Some(false),
+            "--max-files" => {
+                let max_files = flags
+                    .next()
+                    .and_then(|val| val.parse().ok())
+                    .filter(|max_files| *max_files > 0)
+                    .ok_or(SyncError::Usage(
+                        "--max-files expects a number greater than 0".to_string(),
+                    ))?;
+                sync_flags.max_files = Some(max_files);
+            }
+            "--retry-budget" => {
+                sync_flags.retry_budget =
+                    flags
+                        .next()
+                        .and_then(|val| val.parse().ok())
+                        .ok_or(SyncError::Usage(
+                            "--retry-budget expects a number".to_string(),
+                        ))?;
+            }
+            "--max-retries" => {
+                sync_flags.max_retries =
+                    flags
+                        .next()
+                        .and_then(|val| val.parse().ok())
+                        .ok_or(SyncError::Usage(
+                            "--max-retries expects a number".to_string(),
+                        ))?;
+            }
+            "--max-depth" => {
+                let max_depth = flags
+                    .next()
+                    .and_then(|val| val.parse().ok())
+                    .ok_or(SyncError::Usage("--max-depth expects a number".to_string()))?;
+                sync_flags.max_depth = Some(max_depth);
+            }
+            "--include" => {
+                let pattern = flags
+                    .next()
+                    .ok_or(SyncError::Usage("--include expects a glob".to_string()))?;
+                sync_flags.includes.push(pattern.clone());
+            }
+            "--download-include" => {
+                let pattern = flags.next().ok_or(SyncError::Usage(
+                    "--download-include expects a glob".to_string(),
+                ))?;
+                sync_flags.download_includes.push(pattern.clone());
+            }
+            "--remote-ignore" => {
+                let prefix = flags.next().ok_or(SyncError::Usage(
+                    "--remote-ignore expects a cloud path".to_string(),
+                ))?;
+                sync_flags.remote_ignores.push(prefix.clone());
+            }
+            "--state-dir" => {
+                let state_dir = flags
+                    .next()
+                    .ok_or(SyncError::Usage("--state-dir expects a path".to_string()))?;
+                sync_flags.state_dir = Some(state_dir_path(state_dir, folder_path)?);
+            }
+            "--tmp-dir" => {
+                let tmp_dir = flags
+                    .next()
+                    .ok_or(SyncError::Usage("--tmp-dir expects a path".to_string()))?;
+                std::fs::create_dir_all(tmp_dir).map_err(|err| {
+                    SyncError::Io(format!("Cannot create tmp dir {}: {}", tmp_dir, err))
+                })?;
+                sync_flags.tmp_dir = Some(tmp_dir.clone());
+            }
+            "--since" => {
+                let since = flags
+                    .next()
+                    .ok_or(SyncError::Usage("--since expects a date".to_string()))?;
+
+                // Plain dates start at midnight
+                let since = if since.contains('T') {
+                    parse_iso_date(since)?
+                } else {
+                    parse_iso_date(&format!("{}T00:00:00Z", since))?
+                };
+                sync_flags.since = Some(since);
+            }
+            _ => {
+                return Err(SyncError::Usage("Invalid flags".to_string()));
+            }
+        };
+    }
+
+    if strip_bom {
+        sync_flags.bom_strip = Some(BomStrip::new(text_extensions.clone()));
+    }
+    if crlf_normalize {
+        sync_flags.line_endings = Some(LineEndings::new(text_extensions));
+    } else if !strip_bom && !text_extensions.is_empty() {
+        return Err(SyncError::Usage(
+            "--text-ext only applies with --crlf-normalize or --strip-bom".to_string(),
+        ));
+    }
 
-    let time_tokens: Vec<&str> = time_str.split(':').collect();
+    if !export_extensions.is_empty() {
+        sync_flags.export_pdf = Some(PdfExport::new(export_extensions)?);
+    }
+
+    Ok(sync_flags)
+}
+
+// Without a folder the account's default folder is synced, the
+// first argument is the account when the second one is a flag
+// @Returns canonical folder, account name and the flags after them
+fn folder_and_account(args: &[String]) -> Result<(PathBuf, &String, &[String]), SyncError> {
+    let (folder, account_name, flags) = match (args.get(2), args.get(3)) {
+        (Some(folder), Some(account_name)) if !account_name.starts_with('-') => {
+            (Some(folder), account_name, &args[4..])
+        }
+        (Some(account_name), _) => (None, account_name, &args[3..]),
+        _ => return Err(SyncError::Usage("Incorrect no of arguments".to_string())),
+    };
+
+    let folder = match folder {
+        Some(folder) => folder.clone(),
+        None => load_config()?
+            .accounts
+            .get(account_name)
+            .ok_or(SyncError::Usage(
+                "Unknown account name please login first".to_string(),
+            ))?
+            .attributes
+            .get(DEFAULT_FOLDER_KEY)
+            .cloned()
+            .ok_or(SyncError::Usage(format!(
+                "Account {} has no default folder, pass the folder or set it with account set {} {} <folder>",
+                account_name, account_name, DEFAULT_FOLDER_KEY
+            )))?,
+    };
 
-    let hours: u64 = time_tokens[0].parse().unwrap();
-    let minutes: u64 = time_tokens[1].parse().unwrap();
+    let folder_path = std::fs::canonicalize(&folder)
+        .map_err(|err| SyncError::Io(format!("Cannot sync to {} because: {}", folder, err)))?;
+    Ok((folder_path, account_name, flags))
+}
 
-    let seconds_str = &time_tokens[2][0..2];
-    let seconds: u64 = seconds_str.parse().unwrap();
+// Assuming args
+// clousync daemon [folder] <account_name> [--interval <secs>] [SYNC OPTIONS]
+pub fn daemon(args: &[String]) -> Result<(), SyncError> {
+    let (folder_path, account_name, flags) = folder_and_account(args)?;
+    let folder_path_str = folder_path.to_string_lossy().to_string();
 
-    fn days_per_year(year: u64) -> u64 {
-        if year % 4 == 0 && year % 100 != 0 || year % 400 == 0 {
-            366
+    // Taking out --interval, the rest are sync flags
+    let mut interval = DEFAULT_SERVE_INTERVAL;
+    let mut sync_args = Vec::new();
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        if flag == "--interval" {
+            interval = parse_interval(flags.next())?;
         } else {
-            365
+            sync_args.push(flag.clone());
         }
     }
+    let mut sync_flags = parse_sync_flags(&sync_args, &folder_path)?;
+    check_sync_folder(&folder_path, sync_flags.force)?;
 
-    fn days_per_month(month: u64, year: u64) -> u64 {
-        match month {
-            1 => 31,
-            2 => {
-                if days_per_year(year) == 365 {
-                    28
-                } else {
-                    29
+    // The account stays in memory so the token and delta
+    // link of the last good sync carry over between iterations
+    let mut account = load_config()?
+        .accounts
+        .remove(account_name)
+        .ok_or(SyncError::Usage(
+            "Unknown account name please login first".to_string(),
+        ))?;
+
+    shutdown::install();
+
+    let mut failures = 0;
+    while !shutdown::requested() {
+        let synced = sync_or_rewind(&mut account, |account| {
+            sync_files(account, account_name, &folder_path_str, &sync_flags)
+        });
+        let wait = match synced {
+            Ok(report) => {
+                print_report(&report, sync_flags.json);
+                failures = 0;
+
+                // Only the first good iteration starts from scratch
+                // or replays an old delta link, later ones go on
+                // from the link it stored
+                sync_flags.fresh = false;
+                sync_flags.since_delta = None;
+                interval
+            }
+            Err(err) => {
+                failures += 1;
+                let wait = backoff(interval, failures);
+                println!("ERROR: Sync failed: {}, retrying in {}s", err, wait);
+                wait
+            }
+        };
+
+        shutdown::sleep(Duration::from_secs(wait));
+    }
+
+    println!("INFO: Shutting down");
+    Ok(())
+}
+
+// Assuming args
+// clousync watch [folder] <account_name> [--debounce <secs>] [--interval <secs>] [SYNC OPTIONS]
+pub fn watch(args: &[String]) -> Result<(), SyncError> {
+    let (folder_path, account_name, flags) = folder_and_account(args)?;
+    let folder_path_str = folder_path.to_string_lossy().to_string();
+
+    // Taking out --debounce and --interval, the rest are sync flags
+    let mut debounce = DEFAULT_WATCH_DEBOUNCE;
+    let mut interval = DEFAULT_SERVE_INTERVAL;
+    let mut sync_args = Vec::new();
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--debounce" => {
+                debounce = flags
+                    .next()
+                    .and_then(|val| val.parse().ok())
+                    .ok_or(SyncError::Usage("--debounce expects seconds".to_string()))?
+            }
+            "--interval" => interval = parse_interval(flags.next())?,
+            _ => sync_args.push(flag.clone()),
+        }
+    }
+    let mut sync_flags = parse_sync_flags(&sync_args, &folder_path)?;
+    check_sync_folder(&folder_path, sync_flags.force)?;
+
+    // Same as daemon, the token and delta link of the
+    // last good sync carry over between triggered syncs
+    let mut account = load_config()?
+        .accounts
+        .remove(account_name)
+        .ok_or(SyncError::Usage(
+            "Unknown account name please login first".to_string(),
+        ))?;
+
+    let (sender, events) = std::sync::mpsc::channel();
+    let watch_error =
+        |err: notify::Error| SyncError::Io(format!("Cannot watch {}: {}", folder_path_str, err));
+    let mut watcher = notify::recommended_watcher(sender).map_err(watch_error)?;
+    watcher
+        .watch(&folder_path, RecursiveMode::Recursive)
+        .map_err(watch_error)?;
+
+    shutdown::install();
+
+    let mut debouncer = watch::Debouncer::new(
+        Duration::from_secs(debounce),
+        Duration::from_secs(MAX_WATCH_DELAY.max(debounce)),
+    );
+    let mut next_sync = Instant::now();
+    let mut failures = 0;
+
+    while !shutdown::requested() {
+        match events.recv_timeout(Duration::from_millis(200)) {
+            Ok(Ok(event)) => {
+                if watch::is_relevant(&event) {
+                    debouncer.event(Instant::now());
                 }
             }
-            3 => 31,
-            4 => 30,
-            5 => 31,
-            6 => 30,
-            7 => 31,
-            8 => 31,
-            9 => 30,
-            10 => 31,
-            11 => 30,
-            12 => 31,
-            _ => unreachable!(),
+            Ok(Err(err)) => {
+                // Events may have been dropped, syncing
+                // is the only way to catch up with them
+                println!("ERROR: Watching {} failed: {}", folder_path_str, err);
+                debouncer.event(Instant::now());
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(SyncError::Io(format!(
+                    "Stopped watching {}",
+                    folder_path_str
+                )));
+            }
         }
+
+        // Changes don't cut the wait after a failure short
+        let now = Instant::now();
+        let triggered = failures == 0 && debouncer.due(now);
+        if !triggered && now < next_sync {
+            continue;
+        }
+
+        debouncer.clear();
+        let synced = sync_or_rewind(&mut account, |account| {
+            sync_files(account, account_name, &folder_path_str, &sync_flags)
+        });
+        let wait = match synced {
+            Ok(report) => {
+                print_report(&report, sync_flags.json);
+                failures = 0;
+                sync_flags.fresh = false;
+                sync_flags.since_delta = None;
+                interval
+            }
+            Err(err) => {
+                failures += 1;
+                let wait = backoff(interval, failures);
+                println!("ERROR: Sync failed: {}, retrying in {}s", err, wait);
+                wait
+            }
+        };
+
+        // A full sync still runs every interval to pick
+        // up cloud changes and missed local events
+        next_sync = Instant::now() + Duration::from_secs(wait);
     }
 
-    let mut days_since_epoch = 0;
-    for y in 1970..year {
-        days_since_epoch += days_per_year(y);
+    println!("INFO: Shutting down");
+    Ok(())
+}
+
+// The delta link, shared mounts and listing are stored in the
+// account while the sync runs. A failed sync puts it back as it was
+// with only the refreshed token kept, like a single sync which never
+// saves the account, so the next one sees the same cloud changes
+fn sync_or_rewind(
+    account: &mut Account,
+    sync: impl FnOnce(&mut Account) -> Result<SyncReport, SyncError>,
+) -> Result<SyncReport, SyncError> {
+    let before = account.clone();
+    let result = sync(account);
+    if result.is_err() {
+        let token = account.token.clone();
+        *account = before;
+        account.token = token;
     }
+    result
+}
 
-    let mut days_in_year_so_far = 0;
-    for m in 1..month {
-        days_in_year_so_far += days_per_month(m, year);
+// Doubles the wait after every consecutive failure
+fn backoff(interval: u64, failures: u32) -> u64 {
+    let wait = interval.saturating_mul(1 << failures.min(16));
+    wait.min(MAX_DAEMON_BACKOFF.max(interval))
+}
+
+fn parse_interval(value: Option<&String>) -> Result<u64, SyncError> {
+    value
+        .and_then(|val| val.parse().ok())
+        .filter(|interval| *interval > 0)
+        .ok_or(SyncError::Usage(
+            "--interval expects seconds greater than 0".to_string(),
+        ))
+}
+
+fn sync_folder(
+    folder_path: &Path,
+    account_name: &str,
+    sync_flags: &SyncFlags,
+) -> Result<SyncReport, SyncError> {
+    check_sync_folder(folder_path, sync_flags.force)?;
+    let folder_path_str = folder_path.to_string_lossy().to_string();
+
+    let mut config = load_config()?;
+    if let Some(account) = config.accounts.get_mut(account_name) {
+        sync_files(account, account_name, &folder_path_str, sync_flags)
+    } else {
+        Err(SyncError::Usage(
+            "Unknown account name please login first".to_string(),
+        ))
     }
-    days_since_epoch += days_in_year_so_far + (date - 1);
+}
 
-    let seconds_in_hour = 60 * 60;
+fn check_sync_folder(folder_path: &Path, force: bool) -> Result<(), SyncError> {
+    let home = std::env::var("HOME")
+        .ok()
+        .and_then(|home| std::fs::canonicalize(home).ok());
+
+    if !force && is_dangerous_folder(folder_path, home.as_deref()) {
+        return Err(SyncError::Usage(format!(
+            "Refusing to sync {}, pass --force to sync it anyway",
+            folder_path.display()
+        )));
+    }
 
-    (days_since_epoch * 24 * seconds_in_hour) + hours * seconds_in_hour + minutes * 60 + seconds
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::parse_iso_date;
+// A typo could sync the whole disk, filesystem roots, folders
+// right below them like /usr or /home and the home folder
+// are refused. Assumes a canonical path
+fn is_dangerous_folder(folder_path: &Path, home: Option<&Path>) -> bool {
+    let is_top_level = folder_path
+        .parent()
+        .is_none_or(|parent| parent.parent().is_none());
 
-    #[test]
-    fn test_date_parsing() {
-        assert_eq!(parse_iso_date("2023-08-06T13:23:00Z"), 1691328180);
+    is_top_level || home.is_some_and(|home| home == folder_path)
+}
+
+// Assuming args
+// clousync serve <addr> [--interval <secs>]
+pub fn serve(args: &[String]) -> Result<(), SyncError> {
+    if args.len() < 3 {
+        return Err(SyncError::Usage("Incorrect no of arguments".to_string()));
+    }
+
+    let addr = &args[2];
+    let mut interval = DEFAULT_SERVE_INTERVAL;
+
+    let mut flags = args.iter().skip(3);
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--interval" => interval = parse_interval(flags.next())?,
+            _ => {
+                return Err(SyncError::Usage("Invalid flags".to_string()));
+            }
+        };
+    }
+
+    let folders = load_config()?.folders;
+    if folders.is_empty() {
+        return Err(SyncError::Usage(
+            "No folders configured, add them to the folders section of the config".to_string(),
+        ));
+    }
+
+    let board = serve::StatusBoard::default();
+    for watched in &folders {
+        let status = serve::FolderStatus {
+            account: watched.account.clone(),
+            ..Default::default()
+        };
+        board.lock().unwrap().insert(watched.folder.clone(), status);
+    }
+    serve::spawn_server(addr, board.clone())?;
+    shutdown::install();
+
+    // Errors are only reported, the
+    // folder is tried again next round
+    while !shutdown::requested() {
+        for watched in &folders {
+            let result = std::fs::canonicalize(&watched.folder)
+                .map_err(|err| {
+                    SyncError::Io(format!(
+                        "Cannot sync to {} because: {}",
+                        watched.folder, err
+                    ))
+                })
+                .and_then(|folder_path| {
+                    sync_folder(&folder_path, &watched.account, &SyncFlags::default())
+                });
+
+            match &result {
+                Ok(report) => println!("{}", report),
+                Err(err) => println!("ERROR: Sync of {} failed: {}", watched.folder, err),
+            }
+
+            if let Some(status) = board.lock().unwrap().get_mut(&watched.folder) {
+                status.record(&result, timestamp());
+            }
+        }
+
+        shutdown::sleep(Duration::from_secs(interval));
+    }
+
+    println!("INFO: Shutting down");
+    Ok(())
+}
+
+// Assuming args
+// clousync login <gdrive|onedrive>
+pub fn login(args: &[String]) -> Result<(), SyncError> {
+    if args.len() < 3 {
+        return Err(SyncError::Usage("Incorrect no of arguments".to_string()));
+    }
+
+    if args[3..].iter().any(|arg| arg == "--device") {
+        return login_device(args);
+    }
+
+    match args[2].as_str() {
+        "onedrive" => {
+            let config = load_config().unwrap_or_default();
+            let login_url = onedrive::get_oauth_url(&config.onedrive);
+            println!(
+                "{}: \n\n{}",
+                bold("Copy paste this url to browser"),
+                login_url
+            );
+        }
+        "gdrive" => todo!(),
+        "webdav" => {
+            println!("INFO: WebDAV needs no login, save the account with");
+            println!("  cloudsync save webdav <account_name> <url> <username> <password>");
+        }
+        _ => {
+            return Err(SyncError::Usage("Please specify a service".to_string()));
+        }
+    };
+
+    Ok(())
+}
+
+// Signs in on another device with a code instead of pasting
+// the redirect back, then saves the account like save does
+// Assuming args
+// clousync login onedrive --device <account_name> [--keyring] [--folder <path>]
+fn login_device(args: &[String]) -> Result<(), SyncError> {
+    let (options, args) = save_options(args)?;
+    let args: Vec<String> = args.into_iter().filter(|arg| arg != "--device").collect();
+    if args.len() < 4 {
+        return Err(SyncError::Usage(
+            "login --device expects an account name".to_string(),
+        ));
+    }
+    if args[2] != "onedrive" {
+        return Err(SyncError::Usage(
+            "login --device only works with onedrive".to_string(),
+        ));
+    }
+
+    let account_name = &normalize_account_name(&args[3])?;
+    let config = load_config().unwrap_or_default();
+    let client = &config.onedrive;
+
+    let device_code = onedrive::get_device_code(client)?;
+    println!(
+        "{}: {}\n\n{}",
+        bold("Open this url on any device and enter the code"),
+        device_code.user_code,
+        device_code.verification_uri
+    );
+    println!("INFO: Waiting for the sign in");
+
+    let token = onedrive::poll_device_token(client, &device_code)?;
+    let account =
+        Account::new(SyncService::Onedrive, token).with_attributes(client.to_attributes());
+    save_new_account(account_name, account, options)
+}
+
+struct SaveOptions {
+    keyring: bool,
+    default_folder: Option<String>,
+}
+
+// Options can go anywhere, the rest are positional
+// @Returns the options and the positional args
+fn save_options(args: &[String]) -> Result<(SaveOptions, Vec<String>), SyncError> {
+    let mut options = SaveOptions {
+        keyring: false,
+        default_folder: None,
+    };
+    let mut positional = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--keyring" => options.keyring = true,
+            "--folder" => {
+                let folder = rest
+                    .next()
+                    .ok_or(SyncError::Usage("--folder expects a path".to_string()))?;
+                options.default_folder = Some(canonical_folder(folder)?);
+            }
+            _ => positional.push(arg.clone()),
+        }
+    }
+    Ok((options, positional))
+}
+
+fn save_new_account(
+    account_name: &str,
+    mut account: Account,
+    options: SaveOptions,
+) -> Result<(), SyncError> {
+    if options.keyring {
+        account
+            .attributes
+            .insert(KEYRING_KEY.to_string(), "true".to_string());
+    }
+    if let Some(default_folder) = options.default_folder {
+        account
+            .attributes
+            .insert(DEFAULT_FOLDER_KEY.to_string(), default_folder);
+    }
+
+    save_account(account_name, &account)?;
+    println!("INFO: Account saved");
+
+    Ok(())
+}
+
+// Assuming args
+// clousync save <gdrive|onedrive> <account_name> <auth_code|redirect_url> [--keyring]
+// clousync save webdav <account_name> <url> <username> <password> [--keyring]
+pub fn save(args: &[String]) -> Result<(), SyncError> {
+    let (options, args) = save_options(args)?;
+
+    if args.len() < 5 {
+        return Err(SyncError::Usage("Incorrect no of arguments".to_string()));
+    }
+
+    let service = match args[2].as_str() {
+        "gdrive" => SyncService::GDrive,
+        "onedrive" => SyncService::Onedrive,
+        "webdav" => SyncService::WebDav,
+        _ => {
+            return Err(SyncError::Usage("Incorrect sync service".to_string()));
+        }
+    };
+
+    // Before the auth code is spent on a name we can't keep
+    let account_name = &normalize_account_name(&args[3])?;
+    let config = load_config().unwrap_or_default();
+    let (token, attributes) = match service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive => {
+            let auth_code = onedrive::parse_redirect(&args[4])?;
+            let client = &config.onedrive;
+            let token = onedrive::get_token(client, &auth_code, "authorization_code")?;
+            (token, client.to_attributes())
+        }
+        SyncService::WebDav => {
+            if args.len() < 7 {
+                return Err(SyncError::Usage("Incorrect no of arguments".to_string()));
+            }
+            webdav::get_token(&args[4], &args[5], &args[6])?
+        }
+    };
+
+    let account = Account::new(service, token).with_attributes(attributes);
+    save_new_account(account_name, account, options)
+}
+
+// Names are config keys and name keyring entries and files, so
+// they're kept to a single path component without control characters
+// @Returns the name without surrounding whitespace
+fn normalize_account_name(account_name: &str) -> Result<String, SyncError> {
+    let account_name = account_name.trim();
+    let invalid = |reason: &str| {
+        Err(SyncError::Usage(format!(
+            "Invalid account name {:?}, {}",
+            account_name, reason
+        )))
+    };
+
+    if account_name.is_empty() {
+        return invalid("it is empty");
+    }
+    if account_name == "." || account_name == ".." {
+        return invalid("it names a folder");
+    }
+    if account_name.contains(['/', '\\']) {
+        return invalid("it can't contain / or \\");
+    }
+    if account_name.chars().any(char::is_control) {
+        return invalid("it can't contain control characters");
+    }
+
+    Ok(account_name.to_string())
+}
+
+// Assuming args
+// clousync rename <old_name> <new_name>
+pub fn rename(args: &[String]) -> Result<(), SyncError> {
+    if args.len() < 4 {
+        return Err(SyncError::Usage("Incorrect no of arguments".to_string()));
+    }
+
+    let old_name = &args[2];
+    let new_name = &normalize_account_name(&args[3])?;
+
+    // Secrets were stored under the new name by the write
+    let uses_keyring = update_config(&config_path(), |config| {
+        rename_account(config, old_name, new_name)
+    })?;
+    if uses_keyring {
+        forget_secrets(old_name);
+    }
+    println!("INFO: Account {} renamed to {}", old_name, new_name);
+
+    Ok(())
+}
+
+// @Returns whether the account keeps its secrets in the keyring
+fn rename_account(config: &mut Config, old_name: &str, new_name: &str) -> Result<bool, SyncError> {
+    if config.accounts.contains_key(new_name) {
+        return Err(SyncError::Usage(format!(
+            "Account {} already exists",
+            new_name
+        )));
+    }
+
+    let mut account = config
+        .accounts
+        .remove(old_name)
+        .ok_or(SyncError::Usage(format!(
+            "Unknown account name {}",
+            old_name
+        )))?;
+    let uses_keyring = account.uses_keyring();
+    if uses_keyring {
+        restore_keyring_secrets(old_name, &mut account)?;
+    }
+    config.accounts.insert(new_name.to_owned(), account);
+    Ok(uses_keyring)
+}
+
+// Reads and changes account attributes without editing the config
+// Assuming args
+// clousync account set <account_name> <key> <value>
+// clousync account get <account_name> [key]
+// clousync account unset <account_name> <key>
+pub fn account(args: &[String]) -> Result<(), SyncError> {
+    if args.len() < 4 {
+        return Err(SyncError::Usage("Incorrect no of arguments".to_string()));
+    }
+
+    let action = &args[2];
+    let account_name = &args[3];
+    let unknown_account = || SyncError::Usage(format!("Unknown account name {}", account_name));
+
+    if action != "get" {
+        let leaves_keyring = update_config(&config_path(), |config| {
+            let account = config
+                .accounts
+                .get_mut(account_name)
+                .ok_or_else(unknown_account)?;
+            change_attribute(account, account_name, action, args.get(4), args.get(5))
+        })?;
+        if leaves_keyring {
+            forget_secrets(account_name);
+        }
+        return Ok(());
+    }
+
+    let config = load_config()?;
+    let account = config
+        .accounts
+        .get(account_name)
+        .ok_or_else(unknown_account)?;
+    match args.get(4) {
+        None => {
+            let mut keys: Vec<_> = account.attributes.keys().collect();
+            keys.sort();
+
+            let (_, managed) = attribute_keys(&account.service);
+            for key in keys
+                .into_iter()
+                .filter(|key| !managed.contains(&key.as_str()))
+            {
+                println!("{}={}", key, account.attributes[key]);
+            }
+        }
+        Some(key) => {
+            let value = account
+                .attributes
+                .get(key)
+                .ok_or(SyncError::Usage(format!("Attribute {} is not set", key)))?;
+            println!("{}", value);
+        }
+    }
+    Ok(())
+}
+
+// Sets or unsets one attribute of an account loaded for an update
+// @Returns whether the account left the keyring
+fn change_attribute(
+    account: &mut Account,
+    account_name: &str,
+    action: &str,
+    key: Option<&String>,
+    value: Option<&String>,
+) -> Result<bool, SyncError> {
+    // Secrets are written back to the config
+    // once the account leaves the keyring
+    let keyring_key = key.is_some_and(|key| key == KEYRING_KEY);
+    if keyring_key && account.uses_keyring() {
+        restore_keyring_secrets(account_name, account)?;
+    }
+
+    match (action, key, value) {
+        ("set", Some(key), Some(value)) => {
+            check_attribute(&account.service, key, value)?;
+
+            // Relative folders would depend on where sync runs
+            let value = match key.as_str() {
+                DEFAULT_FOLDER_KEY => canonical_folder(value)?,
+                _ => value.to_owned(),
+            };
+            account.attributes.insert(key.to_owned(), value);
+            if is_root_attribute(&account.service, key) {
+                onedrive::reset_root(account);
+            }
+            println!("INFO: Set {} of account {}", key, account_name);
+        }
+        ("unset", Some(key), _) => {
+            // Managed attributes can be unset, dropping
+            // delta_link makes the next sync a full one
+            if account.attributes.remove(key).is_none() {
+                return Err(SyncError::Usage(format!("Attribute {} is not set", key)));
+            }
+            if is_root_attribute(&account.service, key) {
+                onedrive::reset_root(account);
+            }
+            println!("INFO: Unset {} of account {}", key, account_name);
+        }
+        _ => return Err(SyncError::Usage("Incorrect no of arguments".to_string())),
+    }
+
+    Ok(keyring_key && !account.uses_keyring())
+}
+
+// @Returns absolute path of an existing folder
+fn canonical_folder(folder: &str) -> Result<String, SyncError> {
+    let folder_path = std::fs::canonicalize(folder)
+        .map_err(|err| SyncError::Io(format!("Cannot use folder {}: {}", folder, err)))?;
+    if !folder_path.is_dir() {
+        return Err(SyncError::Usage(format!("{} is not a folder", folder)));
+    }
+
+    Ok(folder_path.to_string_lossy().to_string())
+}
+
+// A secret left behind is only reported, the
+// config no longer refers to it
+fn forget_secrets(account_name: &str) {
+    KEYRING_CACHE.lock().unwrap().remove(account_name);
+    if let Err(err) = keyring::delete(account_name) {
+        println!(
+            "ERROR: Cannot remove secrets of account {} from the keyring: {}",
+            account_name, err
+        );
+    }
+}
+
+// @Returns keys users may set and keys managed by sync
+fn attribute_keys(service: &SyncService) -> (&'static [&'static str], &'static [&'static str]) {
+    match service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive => (onedrive::ACCOUNT_ATTRIBUTES, onedrive::MANAGED_ATTRIBUTES),
+        SyncService::WebDav => (webdav::ACCOUNT_ATTRIBUTES, webdav::MANAGED_ATTRIBUTES),
+    }
+}
+
+// Attributes changing which folder of the cloud is synced
+fn is_root_attribute(service: &SyncService, key: &str) -> bool {
+    matches!(service, SyncService::Onedrive) && key == onedrive::APP_FOLDER_KEY
+}
+
+fn is_boolean_attribute(service: &SyncService, key: &str) -> bool {
+    is_root_attribute(service, key) || key == KEYRING_KEY
+}
+
+// Unknown keys are only warned about so attributes
+// of newer versions can still be set
+fn check_attribute(service: &SyncService, key: &str, value: &str) -> Result<(), SyncError> {
+    let (known, managed) = attribute_keys(service);
+    if managed.contains(&key) {
+        return Err(SyncError::Usage(format!(
+            "Attribute {} is managed by sync and can only be unset",
+            key
+        )));
+    }
+
+    if !known.contains(&key) {
+        match closest_key(key, known) {
+            Some(closest) => println!(
+                "WARNING: Unknown attribute {}, did you mean {}?",
+                key, closest
+            ),
+            None => println!(
+                "WARNING: Unknown attribute {}, known ones are {}",
+                key,
+                known.join(", ")
+            ),
+        }
+    }
+
+    let is_url = matches!(key, "url" | "redirect_url");
+    if is_url && !(value.starts_with("https://") || value.starts_with("http://")) {
+        return Err(SyncError::Usage(format!(
+            "Attribute {} has to be an http(s) url",
+            key
+        )));
+    }
+
+    if is_boolean_attribute(service, key) && !matches!(value, "true" | "false") {
+        return Err(SyncError::Usage(format!(
+            "Attribute {} has to be true or false",
+            key
+        )));
+    }
+
+    if value.is_empty() {
+        return Err(SyncError::Usage(format!(
+            "Attribute {} cannot be empty, use unset instead",
+            key
+        )));
+    }
+
+    Ok(())
+}
+
+// Known key within two edits of the given one
+fn closest_key<'a>(key: &str, known: &[&'a str]) -> Option<&'a str> {
+    known
+        .iter()
+        .map(|candidate| (edit_distance(key, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= 2)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(lhs: &str, rhs: &str) -> usize {
+    let rhs: Vec<char> = rhs.chars().collect();
+    let mut previous: Vec<usize> = (0..=rhs.len()).collect();
+
+    for (i, lhs_char) in lhs.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, rhs_char) in rhs.iter().enumerate() {
+            let substitution = previous[j] + usize::from(lhs_char != *rhs_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[rhs.len()]
+}
+
+// Lists the versions of a cloud file, with --version restores one
+// in the cloud or with --output only downloads it to a local file
+// Assuming args
+// clousync restore <account_name> <remote_path> [--version <id>] [--output <file>]
+pub fn restore(args: &[String]) -> Result<(), SyncError> {
+    if args.len() < 4 {
+        return Err(SyncError::Usage("Incorrect no of arguments".to_string()));
+    }
+
+    let account_name = &args[2];
+    let remote_path = &args[3];
+
+    let mut version_id = None;
+    let mut output = None;
+    let mut flags = args.iter().skip(4);
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--version" => {
+                version_id = Some(
+                    flags
+                        .next()
+                        .ok_or(SyncError::Usage("--version expects an id".to_string()))?,
+                );
+            }
+            "--output" | "-o" => {
+                output = Some(
+                    flags
+                        .next()
+                        .ok_or(SyncError::Usage("--output expects a path".to_string()))?,
+                );
+            }
+            _ => {
+                return Err(SyncError::Usage("Invalid flags".to_string()));
+            }
+        };
+    }
+
+    if output.is_some() && version_id.is_none() {
+        return Err(SyncError::Usage("--output needs --version".to_string()));
+    }
+
+    let mut config = load_config()?;
+    let account = config
+        .accounts
+        .get_mut(account_name)
+        .ok_or(SyncError::Usage(
+            "Unknown account name please login first".to_string(),
+        ))?;
+
+    let valid_till = account.token.valid_till;
+    ensure_token(account)?;
+    if account.token.valid_till != valid_till {
+        save_account(account_name, account)?;
+    }
+
+    match account.service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive => {}
+        SyncService::WebDav => {
+            return Err(SyncError::Usage(
+                "File versions are only kept by onedrive".to_string(),
+            ))
+        }
+    }
+
+    let cloud_id = onedrive::get_item_id(account, remote_path)?;
+    match (version_id, output) {
+        (None, _) => {
+            for version in onedrive::list_versions(account, &cloud_id)? {
+                println!(
+                    "{}\t{}\t{}",
+                    version.id,
+                    format_iso_date(version.last_modified),
+                    version.size
+                );
+            }
+        }
+        (Some(version_id), Some(output)) => {
+            let contents = onedrive::download_version(account, &cloud_id, version_id)?;
+            std::fs::write(output, contents)
+                .map_err(|err| SyncError::Io(format!("Cannot write {}: {}", output, err)))?;
+            println!(
+                "INFO: Version {} of {} written to {}",
+                version_id, remote_path, output
+            );
+        }
+        (Some(version_id), None) => {
+            onedrive::restore_version(account, &cloud_id, version_id)?;
+            println!(
+                "INFO: Restored version {} of {}, the next sync downloads it",
+                version_id, remote_path
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Problems found by doctor, each printed with a fix
+#[derive(Default)]
+struct Checkup {
+    problems: u32,
+}
+
+impl Checkup {
+    fn ok(&self, message: &str) {
+        println!("OK: {}", message);
+    }
+
+    fn problem(&mut self, message: &str, fix: &str) {
+        self.problems += 1;
+        println!("ERROR: {}", message);
+        println!("  Fix: {}", fix);
+    }
+}
+
+// The config path is fixed when cloudsync is built, a binary
+// built by another user looks for the config in their home
+fn check_home(built_home: &str, home: Option<&str>) -> Result<(), String> {
+    match home {
+        Some(home) if home == built_home => Ok(()),
+        Some(home) => Err(format!(
+            "Config is read from {}/.config but HOME is {}",
+            built_home, home
+        )),
+        None => Err(format!(
+            "HOME is not set, config is read from {}/.config",
+            built_home
+        )),
+    }
+}
+
+// @Returns what to do about a failed account check
+fn account_fix(account_name: &str, service: &SyncService, err: &SyncError) -> String {
+    let login = match service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive => format!(
+            "Log in again with `cloudsync login onedrive` and `cloudsync save onedrive {} <redirect_url>`",
+            account_name
+        ),
+        SyncService::WebDav => format!(
+            "Check the credentials with `cloudsync account get {}` or save the account again with `cloudsync save webdav {} <url> <username> <password>`",
+            account_name, account_name
+        ),
+    };
+
+    match err {
+        SyncError::Auth(_)
+        | SyncError::Api { code: 401, .. }
+        | SyncError::Api { code: 403, .. } => login,
+        SyncError::Network(_) => "Check the network connection and proxy settings".to_string(),
+        SyncError::Api { code: 404, .. } => format!(
+            "Check the url of the account with `cloudsync account get {} url`",
+            account_name
+        ),
+        _ => login,
+    }
+}
+
+// Contents go to stdout as they are, nothing in the
+// synced folder or its cloudstate is read or written
+// Assuming args
+// clousync cat <account_name> <remote_path>
+pub fn cat(args: &[String]) -> Result<(), SyncError> {
+    use std::io::Write;
+
+    if args.len() < 4 {
+        return Err(SyncError::Usage("Incorrect no of arguments".to_string()));
+    }
+
+    MESSAGES_TO_STDERR.store(true, Ordering::Relaxed);
+
+    // Spooled so a retry doesn't repeat what was already
+    // written and large files don't have to fit in memory
+    let remote_path = format!("/{}", args[3].trim_start_matches('/'));
+    let account = signed_in_account(&args[2])?;
+    let mut spool = http::Spool::new()?;
+    match account.service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive => {
+            onedrive::download_file_spooled(&account, &remote_path, &mut spool)?
+        }
+        SyncService::WebDav => webdav::download_file_spooled(&account, &remote_path, &mut spool)?,
+    };
+
+    // Readers like head closing the pipe early are fine
+    let mut stdout = std::io::stdout().lock();
+    match std::io::copy(&mut spool.reader()?, &mut stdout).and_then(|_| stdout.flush()) {
+        Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => {
+            Err(SyncError::Io(format!("Cannot write to stdout: {}", err)))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Account of commands working on single cloud items, cloudstate
+// and last_synced are left alone, only a refreshed token is saved
+fn signed_in_account(account_name: &str) -> Result<Account, SyncError> {
+    let mut account = load_config()?
+        .accounts
+        .remove(account_name)
+        .ok_or(SyncError::Usage(
+            "Unknown account name please login first".to_string(),
+        ))?;
+
+    let valid_till = account.token.valid_till;
+    ensure_token(&mut account)?;
+    if account.token.valid_till != valid_till {
+        save_account(account_name, &account)?;
+    }
+
+    Ok(account)
+}
+
+// Downloads the current contents of one cloud file
+fn download_remote(account_name: &str, remote_path: &str) -> Result<Vec<u8>, SyncError> {
+    let remote_path = format!("/{}", remote_path.trim_start_matches('/'));
+
+    let account = signed_in_account(account_name)?;
+    match account.service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive => onedrive::download_file(&account, &remote_path, None),
+        SyncService::WebDav => webdav::download_file(&account, &remote_path),
+    }
+}
+
+// Writes one cloud file anywhere whatever the cloudstate says,
+// a folder as destination gets the file under its cloud name
+// Assuming args
+// clousync pull <account_name> <remote_path> <local_dest>
+pub fn pull(args: &[String]) -> Result<(), SyncError> {
+    if args.len() < 5 {
+        return Err(SyncError::Usage("Incorrect no of arguments".to_string()));
+    }
+
+    let account_name = &args[2];
+    let remote_path = &args[3];
+    let local_dest = pull_destination(remote_path, &args[4])?;
+
+    let contents = download_remote(account_name, remote_path)?;
+    write_atomic(&local_dest, &contents, None)
+        .map_err(|err| SyncError::Io(format!("Cannot write {}: {}", local_dest, err)))?;
+    println!("INFO: Pulled {} to {}", remote_path, local_dest);
+
+    Ok(())
+}
+
+fn pull_destination(remote_path: &str, local_dest: &str) -> Result<String, SyncError> {
+    if !Path::new(local_dest).is_dir() {
+        return Ok(local_dest.to_string());
+    }
+
+    let trimmed = remote_path.trim_end_matches('/');
+    let (_, file_name) = trimmed.rsplit_once('/').unwrap_or(("", trimmed));
+    if file_name.is_empty() {
+        return Err(SyncError::Usage(format!(
+            "{} is not a file to pull",
+            remote_path
+        )));
+    }
+
+    Ok(format!(
+        "{}/{}",
+        local_dest.trim_end_matches('/'),
+        file_name
+    ))
+}
+
+// Uploads one local file over whatever is at the remote path, a
+// remote path ending in / gets the file under its local name
+// Assuming args
+// clousync push <account_name> <local_path> <remote_path>
+pub fn push(args: &[String]) -> Result<(), SyncError> {
+    if args.len() < 5 {
+        return Err(SyncError::Usage("Incorrect no of arguments".to_string()));
+    }
+
+    let account_name = &args[2];
+    let local_path = &args[3];
+    let remote_path = push_destination(local_path, &args[4])?;
+
+    let contents = std::fs::read(long_path(local_path).as_ref())
+        .map_err(|err| SyncError::Io(format!("Cannot read {}: {}", local_path, err)))?;
+    let created = local_created(local_path);
+
+    let account = signed_in_account(account_name)?;
+    let cloud_id = match account.service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive if contents.len() > onedrive::SIMPLE_UPLOAD_LIMIT => {
+            onedrive::upload_large_file(&account, &remote_path, &contents, created, None, |_| {})?
+        }
+        SyncService::Onedrive => {
+            onedrive::upload_new_file(&account, &remote_path, &contents, created)?
+        }
+        SyncService::WebDav => webdav::upload_new_file(&account, &remote_path, &contents)?,
+    };
+    println!(
+        "INFO: Pushed {} to {} ({})",
+        local_path, remote_path, cloud_id
+    );
+
+    Ok(())
+}
+
+fn push_destination(local_path: &str, remote_path: &str) -> Result<String, SyncError> {
+    let remote_path = format!("/{}", remote_path.trim_start_matches('/'));
+    if !remote_path.ends_with('/') {
+        return Ok(remote_path);
+    }
+
+    let file_name = Path::new(local_path)
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .ok_or(SyncError::Usage(format!(
+            "{} is not a file to push",
+            local_path
+        )))?;
+    Ok(format!("{}{}", remote_path, file_name))
+}
+
+// Lists the folder itself, not the folders below it
+// Assuming args
+// clousync ls <account_name> [remote_path]
+pub fn ls(args: &[String]) -> Result<(), SyncError> {
+    if args.len() < 3 {
+        return Err(SyncError::Usage("Incorrect no of arguments".to_string()));
+    }
+
+    let account_name = &args[2];
+    let folder_path = args.get(3).map_or("", |path| path.trim_matches('/'));
+    let folder_path = format!("/{}", folder_path);
+
+    let account = signed_in_account(account_name)?;
+    let mut entries = match account.service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive => onedrive::list_children(&account, &folder_path)?,
+        SyncService::WebDav => webdav::list_children(&account, &folder_path)?,
+    };
+    entries.sort_by(|entry, other| entry.name.cmp(&other.name));
+
+    for entry in &entries {
+        println!(
+            "{}\t{}\t{}\t{}",
+            entry.name,
+            entry.size.map_or("-".to_string(), |size| size.to_string()),
+            if entry.is_folder { "folder" } else { "file" },
+            entry.last_modified.map_or("-".to_string(), format_iso_date)
+        );
+    }
+
+    Ok(())
+}
+
+// Checks the config, every account and the folders of serve
+// and prints a fix for every problem found, tokens which
+// expired are refreshed and saved like a sync would
+// Assuming args
+// clousync doctor
+pub fn doctor(_args: &[String]) -> Result<(), SyncError> {
+    let mut checkup = Checkup::default();
+
+    let home = std::env::var("HOME").ok();
+    match check_home(std::env!("HOME"), home.as_deref()) {
+        Ok(_) => checkup.ok(&format!("HOME is {}", std::env!("HOME"))),
+        Err(message) => checkup.problem(
+            &message,
+            "Build cloudsync as the user running it, the config path is fixed at build time",
+        ),
+    }
+
+    let config_path = config_path();
+    let config_dir = Path::new(&config_path).parent().unwrap();
+    if !config_dir.is_dir() {
+        checkup.problem(
+            &format!("Config folder {} does not exist", config_dir.display()),
+            &format!("Create it with `mkdir -p {}`", config_dir.display()),
+        );
+    } else {
+        // Saves write a temporary file next to the config
+        let probe_path = format!("{}.doctor", config_path);
+        match std::fs::write(&probe_path, b"") {
+            Ok(_) => {
+                let _ = std::fs::remove_file(&probe_path);
+                checkup.ok(&format!(
+                    "Config folder {} is writable",
+                    config_dir.display()
+                ));
+            }
+            Err(err) => checkup.problem(
+                &format!(
+                    "Config folder {} is not writable: {}",
+                    config_dir.display(),
+                    err
+                ),
+                &format!("Give this user write access to {}", config_dir.display()),
+            ),
+        }
+    }
+
+    let config_data = match std::fs::read(&config_path) {
+        Ok(config_data) => Some(config_data),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            checkup.problem(
+                &format!("No config at {}", config_path),
+                "Add an account with `cloudsync login` and `cloudsync save`",
+            );
+            None
+        }
+        Err(err) => {
+            checkup.problem(
+                &format!("Cannot read config {}: {}", config_path, err),
+                &format!("Give this user read access to {}", config_path),
+            );
+            None
+        }
+    };
+
+    let config =
+        config_data.and_then(
+            |config_data| match serde_json::from_slice::<Config>(&config_data) {
+                Ok(config) => {
+                    checkup.ok(&format!(
+                        "Config {} has {} accounts",
+                        config_path,
+                        config.accounts.len()
+                    ));
+                    Some(config)
+                }
+                Err(err) => {
+                    checkup.problem(
+                        &format!("Config {} is corrupt: {}", config_path, err),
+                        &format!(
+                        "Fix it by hand, restore {}.bak or remove it and save the accounts again",
+                        config_path
+                    ),
+                    );
+                    None
+                }
+            },
+        );
+
+    let Some(config) = config else {
+        return doctor_result(&checkup);
+    };
+
+    let mut account_names: Vec<&String> = config.accounts.keys().collect();
+    account_names.sort();
+
+    let mut drive_ids = Vec::new();
+    for account_name in account_names {
+        let mut account = config.accounts[account_name].clone();
+
+        if timestamp() > account.token.valid_till {
+            match refresh_token(&mut account) {
+                Ok(_) => {
+                    checkup.ok(&format!("Account {} token refreshed", account_name));
+                    save_account(account_name, &account)?;
+                }
+                Err(err) => {
+                    checkup.problem(
+                        &format!(
+                            "Account {} token expired and cannot be refreshed: {}",
+                            account_name, err
+                        ),
+                        &account_fix(account_name, &account.service, &err),
+                    );
+                    continue;
+                }
+            }
+        }
+
+        let signed_in = match account.service {
+            SyncService::GDrive => todo!(),
+            SyncService::Onedrive => onedrive::get_user(&account)
+                .map(|user| format!("Account {} is signed in as {}", account_name, user)),
+            SyncService::WebDav => webdav::check_account(&account)
+                .map(|_| format!("Account {} can reach its server", account_name)),
+        };
+
+        match signed_in {
+            Ok(message) => checkup.ok(&message),
+            Err(err) => {
+                checkup.problem(
+                    &format!("Account {} check failed: {}", account_name, err),
+                    &account_fix(account_name, &account.service, &err),
+                );
+                continue;
+            }
+        }
+
+        let server_time = match account.service {
+            SyncService::GDrive => todo!(),
+            SyncService::Onedrive => onedrive::get_server_time(&account),
+            SyncService::WebDav => webdav::get_server_time(&account),
+        };
+        if let Ok(server_time) = server_time {
+            let skew = timestamp().abs_diff(server_time);
+            if skew > CLOCK_SKEW_THRESHOLD {
+                checkup.problem(
+                    &SyncError::ClockSkew { seconds: skew }.to_string(),
+                    "Sync the system clock, e.g. by enabling NTP",
+                );
+            }
+        }
+
+        if matches!(account.service, SyncService::Onedrive) {
+            match onedrive::get_drive_id(&account) {
+                Ok(drive_id) => drive_ids.push((account_name.as_str(), drive_id)),
+                Err(err) => println!(
+                    "WARNING: Cannot get the drive of account {}: {}",
+                    account_name, err
+                ),
+            }
+        }
+    }
+
+    // Every account keeps a delta link of its own, two of them
+    // syncing one drive undo each other's changes
+    for (drive_id, account_names) in shared_drives(&drive_ids) {
+        checkup.problem(
+            &format!(
+                "Accounts {} are saved for the same drive {}",
+                account_names.join(", "),
+                drive_id
+            ),
+            &format!(
+                "Sync the drive with {} only and remove {} from {}",
+                account_names[0],
+                account_names[1..].join(", "),
+                config_path
+            ),
+        );
+    }
+
+    for watched in &config.folders {
+        if !config.accounts.contains_key(&watched.account) {
+            checkup.problem(
+                &format!(
+                    "Folder {} uses unknown account {}",
+                    watched.folder, watched.account
+                ),
+                "Save the account or change the folder in the config",
+            );
+        }
+
+        match std::fs::read_dir(&watched.folder) {
+            Ok(_) => checkup.ok(&format!("Folder {} is readable", watched.folder)),
+            Err(err) => checkup.problem(
+                &format!("Cannot read folder {}: {}", watched.folder, err),
+                "Create the folder, give this user access or remove it from the config",
+            ),
+        }
+    }
+
+    doctor_result(&checkup)
+}
+
+// @Returns drives with the accounts saved for them,
+// only those saved more than once
+fn shared_drives<'a>(drive_ids: &[(&'a str, String)]) -> Vec<(String, Vec<&'a str>)> {
+    let mut drives: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (account_name, drive_id) in drive_ids {
+        drives.entry(drive_id).or_default().push(account_name);
+    }
+
+    drives
+        .into_iter()
+        .filter(|(_, account_names)| account_names.len() > 1)
+        .map(|(drive_id, account_names)| (drive_id.to_string(), account_names))
+        .collect()
+}
+
+fn doctor_result(checkup: &Checkup) -> Result<(), SyncError> {
+    if checkup.problems == 0 {
+        println!("INFO: No problems found");
+        return Ok(());
+    }
+
+    Err(SyncError::Usage(format!(
+        "Found {} problems",
+        checkup.problems
+    )))
+}
+
+// Debugging aid, prints what the delta api returns
+// without syncing or advancing the stored delta link
+// Assuming args
+// clousync delta <account_name>
+pub fn delta(args: &[String]) -> Result<(), SyncError> {
+    if args.len() < 3 {
+        return Err(SyncError::Usage("Incorrect no of arguments".to_string()));
+    }
+
+    let account_name = &args[2];
+
+    let mut config = load_config()?;
+    let account = config
+        .accounts
+        .get_mut(account_name)
+        .ok_or(SyncError::Usage(
+            "Unknown account name please login first".to_string(),
+        ))?;
+
+    let valid_till = account.token.valid_till;
+    ensure_token(account)?;
+    if account.token.valid_till != valid_till {
+        save_account(account_name, account)?;
+    }
+
+    match account.attributes.get("delta_link") {
+        Some(delta_link) => println!("INFO: Using stored delta link {}", delta_link),
+        None => println!("INFO: No stored delta link, fetching root delta"),
+    }
+
+    let mut delta_account = account.clone();
+    let deltas = match delta_account.service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive => onedrive::get_drive_delta(&mut delta_account, false)?,
+        SyncService::WebDav => webdav::get_drive_delta(&mut delta_account)?,
+    };
+
+    for delta in &deltas {
+        let delta_type = match delta.delta_type {
+            DriveDeltaType::Deleted => "deleted",
+            DriveDeltaType::CreatedOrModifiled => "modified",
+        };
+
+        println!(
+            "{}\t{}\t{}\t{}",
+            delta.file_path, delta_type, delta.last_modified, delta.cloud_id
+        );
+    }
+    println!("INFO: Cloud Delta {}", deltas.len());
+
+    Ok(())
+}
+
+// Compares two cloudstates like .cloudstate.1 and .cloudstate to see
+// what a sync changed, only the files are read and nothing is synced
+// Assuming args
+// clousync state-diff <old_cloudstate> <new_cloudstate>
+pub fn state_diff(args: &[String]) -> Result<(), SyncError> {
+    if args.len() < 4 {
+        return Err(SyncError::Usage("Incorrect no of arguments".to_string()));
+    }
+
+    // A missing file would read as an empty cloudstate
+    let read = |cloudstate_file_path: &str| {
+        if !Path::new(cloudstate_file_path).is_file() {
+            return Err(SyncError::Usage(format!(
+                "No cloudstate at {}",
+                cloudstate_file_path
+            )));
+        }
+        read_cloudstate(cloudstate_file_path)
+    };
+    let old = read(&args[2])?;
+    let new = read(&args[3])?;
+
+    let changes = cloudstate_changes(&old, &new);
+    for change in &changes {
+        println!("{}", change);
+    }
+    println!("INFO: Changed entries {}", changes.len());
+
+    Ok(())
+}
+
+// Entries count as modified when they point to another
+// cloud file or were synced at another time
+// @Returns path, change and last_modified of both sides, by path
+fn cloudstate_changes(old: &CloudState, new: &CloudState) -> Vec<String> {
+    let mut file_paths: Vec<&String> = old.entries.keys().chain(new.entries.keys()).collect();
+    file_paths.sort();
+    file_paths.dedup();
+
+    let last_modified = |entry: Option<&CloudStateEntry>| {
+        entry.map_or("-".to_string(), |entry| entry.last_modified.to_string())
+    };
+
+    file_paths
+        .into_iter()
+        .filter_map(|file_path| {
+            let old_entry = old.entries.get(file_path);
+            let new_entry = new.entries.get(file_path);
+            let change = match (old_entry, new_entry) {
+                (None, Some(_)) => "added",
+                (Some(_), None) => "removed",
+                (Some(old_entry), Some(new_entry))
+                    if old_entry.last_modified != new_entry.last_modified
+                        || old_entry.cloud_id != new_entry.cloud_id =>
+                {
+                    "modified"
+                }
+                _ => return None,
+            };
+
+            Some(format!(
+                "{}\t{}\t{}\t{}",
+                file_path,
+                change,
+                last_modified(old_entry),
+                last_modified(new_entry)
+            ))
+        })
+        .collect()
+}
+
+// Assuming args
+// clousync reconcile <folder> <account_name> [--dry-run] [--state-dir <path>]
+pub fn reconcile(args: &[String]) -> Result<(), SyncError> {
+    if args.len() < 4 {
+        return Err(SyncError::Usage("Incorrect no of arguments".to_string()));
+    }
+
+    let folder = &args[2];
+    let account_name = &args[3];
+
+    let folder_path = std::fs::canonicalize(folder)
+        .map_err(|err| SyncError::Io(format!("Cannot reconcile {} because: {}", folder, err)))?;
+
+    let mut dry_run = false;
+    let mut state_dir = None;
+    let mut flags = args.iter().skip(4);
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--dry-run" | "-n" => dry_run = true,
+            "--state-dir" => {
+                let dir = flags
+                    .next()
+                    .ok_or(SyncError::Usage("--state-dir expects a path".to_string()))?;
+                state_dir = Some(state_dir_path(dir, &folder_path)?);
+            }
+            _ => {
+                return Err(SyncError::Usage("Invalid flags".to_string()));
+            }
+        };
+    }
+
+    let mut config = load_config()?;
+    let account = config
+        .accounts
+        .get_mut(account_name)
+        .ok_or(SyncError::Usage(
+            "Unknown account name please login first".to_string(),
+        ))?;
+
+    ensure_token(account)?;
+
+    let folder_path_str = folder_path.to_string_lossy().to_string();
+    let cloudstate_file_path = cloudstate_path(&folder_path_str, state_dir.as_deref());
+    let mut cloudstate = read_cloudstate(&cloudstate_file_path)?;
+
+    // Full listing of the drive, unlike the delta this
+    // reflects what is actually on the remote right now
+    let listing = match account.service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive => onedrive::get_drive_listing(account)?,
+        SyncService::WebDav => webdav::get_drive_listing(account)?,
+    };
+
+    let remote_files: HashMap<&str, &DriveDelta> = listing
+        .iter()
+        .map(|item| (item.file_path.as_str(), item))
+        .collect();
+
+    let mut stale_entries = Vec::new();
+    let mut updated_entries = 0;
+    for (file_path, entry) in cloudstate.entries.iter_mut() {
+        match remote_files.get(file_path.as_str()) {
+            Some(remote) => {
+                if remote.cloud_id != entry.cloud_id {
+                    println!("INFO: Cloud id changed {}", file_path);
+                    entry.cloud_id = remote.cloud_id.clone();
+                    updated_entries += 1;
+                }
+            }
+            None => {
+                println!("INFO: Stale entry {}", file_path);
+                stale_entries.push(file_path.clone());
+            }
+        }
+    }
+
+    for file_path in &stale_entries {
+        cloudstate.entries.remove(file_path);
+    }
+
+    println!(
+        "INFO: Stale entries {}, updated entries {}",
+        stale_entries.len(),
+        updated_entries
+    );
+
+    if dry_run {
+        println!("INFO: Dry run, cloudstate not modified");
+    } else {
+        write_cloudstate(&cloudstate_file_path, &cloudstate)?;
+    }
+
+    // Token might have been refreshed
+    save_account(account_name, account)?;
+
+    Ok(())
+}
+
+fn config_path() -> String {
+    // TODO: figure out home dir for windows
+    let home = std::env!("HOME");
+    format!("{home}/.config/cloudsync.json")
+}
+
+// Assuming args
+// clousync export <file> [--encrypt]
+pub fn export(args: &[String]) -> Result<(), SyncError> {
+    if args.len() < 3 {
+        return Err(SyncError::Usage("Incorrect no of arguments".to_string()));
+    }
+
+    let export_path = &args[2];
+    let encrypt = match args.get(3).map(|flag| flag.as_str()) {
+        None => false,
+        Some("--encrypt") => true,
+        Some(_) => return Err(SyncError::Usage("Invalid flags".to_string())),
+    };
+
+    let config = load_config()?;
+    let mut export_data = serde_json::to_vec_pretty(&config).unwrap();
+    if encrypt {
+        let passphrase = read_passphrase("Passphrase for the export: ")?;
+        if passphrase != read_passphrase("Repeat passphrase: ")? {
+            return Err(SyncError::Usage("Passphrases do not match".to_string()));
+        }
+        export_data = crypto::seal(&passphrase, &export_data);
+    }
+
+    write_private(export_path, &export_data)
+        .map_err(|err| SyncError::Io(format!("Cannot write export: {}", err)))?;
+
+    println!(
+        "INFO: Exported {} accounts to {}",
+        config.accounts.len(),
+        export_path
+    );
+    Ok(())
+}
+
+// Assuming args
+// clousync import <file> [--overwrite]
+pub fn import(args: &[String]) -> Result<(), SyncError> {
+    if args.len() < 3 {
+        return Err(SyncError::Usage("Incorrect no of arguments".to_string()));
+    }
+
+    let import_path = &args[2];
+    let overwrite = match args.get(3).map(|flag| flag.as_str()) {
+        None => false,
+        Some("--overwrite") => true,
+        Some(_) => return Err(SyncError::Usage("Invalid flags".to_string())),
+    };
+
+    let mut import_data = std::fs::read(import_path)
+        .map_err(|err| SyncError::Io(format!("Cannot read {}: {}", import_path, err)))?;
+    if crypto::is_sealed(&import_data) {
+        let passphrase = read_passphrase("Passphrase of the export: ")?;
+        import_data = crypto::open(&passphrase, &import_data)?;
+    }
+
+    let imported: Config = serde_json::from_slice(&import_data)
+        .map_err(|err| SyncError::Parse(format!("Cannot read {}: {}", import_path, err)))?;
+
+    let count = update_config(&config_path(), |config| {
+        merge_config(config, imported, overwrite)
+    })?;
+
+    println!("INFO: Imported {} accounts", count);
+    Ok(())
+}
+
+// Existing accounts are only replaced with overwrite, configured
+// folders are added and the client is kept unless none is set
+// @Returns number of imported accounts
+fn merge_config(
+    config: &mut Config,
+    imported: Config,
+    overwrite: bool,
+) -> Result<usize, SyncError> {
+    if !overwrite {
+        let mut collisions: Vec<&String> = imported
+            .accounts
+            .keys()
+            .filter(|name| config.accounts.contains_key(*name))
+            .collect();
+
+        if !collisions.is_empty() {
+            collisions.sort();
+            let collisions: Vec<&str> = collisions.iter().map(|name| name.as_str()).collect();
+            return Err(SyncError::Usage(format!(
+                "Accounts {} already exist, pass --overwrite to replace them",
+                collisions.join(", ")
+            )));
+        }
+    }
+
+    let count = imported.accounts.len();
+    config.accounts.extend(imported.accounts);
+
+    for folder in imported.folders {
+        if !config.folders.contains(&folder) {
+            config.folders.push(folder);
+        }
+    }
+
+    if config.onedrive.client_id.is_none() {
+        config.onedrive = imported.onedrive;
+    }
+
+    Ok(count)
+}
+
+// Taken from CLOUDSYNC_PASSPHRASE when set, otherwise
+// read from the terminal without echoing it
+fn read_passphrase(prompt: &str) -> Result<String, SyncError> {
+    if let Ok(passphrase) = std::env::var("CLOUDSYNC_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+
+    eprint!("{}", prompt);
+    let _echo = EchoOff::new();
+
+    let mut passphrase = String::new();
+    std::io::stdin()
+        .read_line(&mut passphrase)
+        .map_err(|err| SyncError::Io(format!("Cannot read passphrase: {}", err)))?;
+    eprintln!();
+
+    let passphrase = passphrase.trim_end_matches(['\r', '\n']).to_string();
+    if passphrase.is_empty() {
+        return Err(SyncError::Usage("Passphrase cannot be empty".to_string()));
+    }
+    Ok(passphrase)
+}
+
+// Turns off terminal echo until dropped, does
+// nothing when stdin isn't a terminal
+struct EchoOff {
+    #[cfg(unix)]
+    original: Option<libc::termios>,
+}
+
+impl EchoOff {
+    fn new() -> Self {
+        #[cfg(unix)]
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                return EchoOff { original: None };
+            }
+
+            let original = termios;
+            termios.c_lflag &= !libc::ECHO;
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+            EchoOff {
+                original: Some(original),
+            }
+        }
+
+        #[cfg(not(unix))]
+        EchoOff {}
+    }
+}
+
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(original) = &self.original {
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+            }
+        }
+    }
+}
+
+// Exports hold tokens, only the owner may read them
+fn write_private(file_path: &str, data: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(file_path)?.write_all(data)
+}
+
+// Written to a temporary file first and then renamed
+// so a crash never leaves a half written config. Secrets
+// of keyring accounts go to the keyring first
+fn write_config(config_path: &str, config: &Config) -> Result<(), SyncError> {
+    let mut config = config.clone();
+    let mut cache = KEYRING_CACHE.lock().unwrap();
+    for (account_name, account) in &mut config.accounts {
+        if !account.uses_keyring() {
+            continue;
+        }
+
+        // Loaded for an update, the secrets are only in the keyring
+        let has_secrets = account.has_secrets();
+        let secrets = account.take_secrets();
+        if !has_secrets || cache.get(account_name) == Some(&secrets) {
+            continue;
+        }
+
+        keyring::store(account_name, &secrets)?;
+        cache.insert(account_name.clone(), secrets);
+    }
+    drop(cache);
+
+    let temp_path = format!("{}.tmp", config_path);
+    let config_data = serde_json::to_string_pretty(&config).unwrap();
+
+    std::fs::write(&temp_path, config_data)
+        .map_err(|err| SyncError::Io(format!("Cannot write config to file: {}", err)))?;
+    std::fs::rename(&temp_path, config_path)
+        .map_err(|err| SyncError::Io(format!("Cannot write config to file: {}", err)))
+}
+
+// Held until the returned file is dropped, the lock belongs
+// to the open file so it keeps threads apart as well
+fn lock_file(lock_path: &str) -> Result<std::fs::File, SyncError> {
+    let lock_error = |err| SyncError::Io(format!("Cannot lock {}: {}", lock_path, err));
+
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path)
+        .map_err(lock_error)?;
+    file.lock().map_err(lock_error)?;
+    Ok(file)
+}
+
+// Reads, changes and writes back the config while holding its
+// lock, so processes saving different accounts at the same
+// time don't overwrite each other's changes
+fn update_config<T>(
+    config_path: &str,
+    update: impl FnOnce(&mut Config) -> Result<T, SyncError>,
+) -> Result<T, SyncError> {
+    let _lock = lock_file(&format!("{}.lock", config_path))?;
+
+    let mut config = load_config_for_update(config_path)?;
+    let updated = update(&mut config)?;
+    write_config(config_path, &config)?;
+    Ok(updated)
+}
+
+// Secrets of keyring accounts are filled in, accounts whose
+// secrets can't be read are left with blanks
+fn load_config() -> Result<Config, SyncError> {
+    let config_data = std::fs::read(config_path())
+        .map_err(|err| SyncError::Io(format!("Cannot read config: {}", err)))?;
+
+    let mut config: Config = serde_json::from_slice(&config_data)
+        .map_err(|err| SyncError::Parse(format!("Cannot read config: {}", err)))?;
+
+    let mut cache = KEYRING_CACHE.lock().unwrap();
+    for (account_name, account) in &mut config.accounts {
+        if !account.uses_keyring() {
+            continue;
+        }
+
+        let secrets = match cache.get(account_name) {
+            Some(secrets) => Ok(secrets.clone()),
+            None => keyring::load(account_name),
+        };
+        match secrets.and_then(|secrets| {
+            account.restore_secrets(&secrets)?;
+            Ok(secrets)
+        }) {
+            Ok(secrets) => {
+                cache.insert(account_name.clone(), secrets);
+            }
+            Err(err) => message!(
+                "ERROR: Cannot read secrets of account {} from the keyring: {}",
+                account_name,
+                err
+            ),
+        }
+    }
+
+    Ok(config)
+}
+
+// Accounts loaded for an update have blanks in place of the
+// secrets they keep in the keyring
+fn restore_keyring_secrets(account_name: &str, account: &mut Account) -> Result<(), SyncError> {
+    let cached = KEYRING_CACHE.lock().unwrap().get(account_name).cloned();
+    let secrets = match cached {
+        Some(secrets) => secrets,
+        None => keyring::load(account_name)?,
+    };
+    account.restore_secrets(&secrets)
+}
+
+// Config which is about to be rewritten, a missing config starts
+// empty and a corrupt one is backed up before being replaced
+fn load_config_for_update(config_path: &str) -> Result<Config, SyncError> {
+    let config_data = match std::fs::read(config_path) {
+        Ok(config_data) => config_data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(err) => return Err(SyncError::Io(format!("Cannot read config: {}", err))),
+    };
+
+    match serde_json::from_slice(&config_data) {
+        Ok(config) => Ok(config),
+        Err(err) => {
+            let backup_path = format!("{}.bak", config_path);
+            std::fs::rename(config_path, &backup_path)
+                .map_err(|err| SyncError::Io(format!("Cannot back up corrupt config: {}", err)))?;
+
+            message!(
+                "ERROR: Config is corrupt ({}), backed up to {} and starting a fresh one",
+                err,
+                backup_path
+            );
+            Ok(Config::default())
+        }
+    }
+}
+
+// State kept outside the folder is named after a
+// hash of the folder path so folders don't collide
+fn cloudstate_path(folder: &str, state_dir: Option<&str>) -> String {
+    match state_dir {
+        Some(state_dir) => format!("{}/{:016x}.json", state_dir, fnv1a(folder.as_bytes())),
+        None => format!("{}/.cloudstate", folder),
+    }
+}
+
+// Creates the state directory, which must not be inside
+// the synced folder or the state would be synced too
+// @Returns canonical path of the state directory
+fn state_dir_path(state_dir: &str, folder_path: &std::path::Path) -> Result<String, SyncError> {
+    std::fs::create_dir_all(state_dir)
+        .map_err(|err| SyncError::Io(format!("Cannot create state dir {}: {}", state_dir, err)))?;
+    let state_dir_path = std::fs::canonicalize(state_dir)
+        .map_err(|err| SyncError::Io(format!("Cannot use state dir {}: {}", state_dir, err)))?;
+
+    if state_dir_path.starts_with(folder_path) {
+        return Err(SyncError::Usage(
+            "--state-dir must be outside the synced folder".to_string(),
+        ));
+    }
+
+    Ok(state_dir_path.to_string_lossy().to_string())
+}
+
+impl Default for CloudState {
+    fn default() -> Self {
+        CloudState {
+            version: CLOUDSTATE_VERSION,
+            entries: BTreeMap::new(),
+            uploads: HashMap::new(),
+            compressed: false,
+        }
+    }
+}
+
+// Only a missing cloudstate starts empty, a corrupt or unknown
+// one fails the sync since starting over re-syncs every file
+fn read_cloudstate(cloudstate_file_path: &str) -> Result<CloudState, SyncError> {
+    let data = match std::fs::read(cloudstate_file_path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(CloudState::default()),
+        Err(err) => return Err(SyncError::Io(format!("Cannot read cloudstate: {}", err))),
+    };
+
+    let compressed = gzip::is_gzip(&data);
+    let data = if compressed {
+        gzip::decompress(&data).map_err(|err| {
+            SyncError::Parse(format!(
+                "Cloudstate {} is corrupt ({}), restore it from a .cloudstate.N backup or sync with --fresh",
+                cloudstate_file_path, err
+            ))
+        })?
+    } else {
+        data
+    };
+
+    let mut cloudstate: CloudState = serde_json::from_slice(&data).map_err(|err| {
+        SyncError::Parse(format!(
+            "Cloudstate {} is corrupt ({}), restore it from a .cloudstate.N backup or sync with --fresh",
+            cloudstate_file_path, err
+        ))
+    })?;
+
+    if cloudstate.version > CLOUDSTATE_VERSION {
+        return Err(SyncError::Parse(format!(
+            "Cloudstate {} has version {} but only up to {} is supported, it was written by a newer cloudsync",
+            cloudstate_file_path, cloudstate.version, CLOUDSTATE_VERSION
+        )));
+    }
+
+    if cloudstate.version < CLOUDSTATE_VERSION {
+        println!(
+            "INFO: Migrating cloudstate from version {} to {}",
+            cloudstate.version, CLOUDSTATE_VERSION
+        );
+        migrate_cloudstate(&mut cloudstate);
+    }
+
+    cloudstate.compressed = compressed;
+    Ok(cloudstate)
+}
+
+// Version 0 is the unversioned layout,
+// which version 1 only adds the field to
+fn migrate_cloudstate(cloudstate: &mut CloudState) {
+    cloudstate.version = CLOUDSTATE_VERSION;
+}
+
+// Keeps the cloudstate of the last keep syncs, .1 being the
+// newest, so a bad sync can be traced back. The file is copied
+// rather than moved so it is never missing
+fn rotate_cloudstate(cloudstate_file_path: &str, keep: usize) {
+    if keep == 0 || !Path::new(cloudstate_file_path).exists() {
+        return;
+    }
+
+    // Oldest one is overwritten
+    for index in (1..keep).rev() {
+        let _ = std::fs::rename(
+            format!("{}.{}", cloudstate_file_path, index),
+            format!("{}.{}", cloudstate_file_path, index + 1),
+        );
+    }
+
+    if let Err(err) = std::fs::copy(cloudstate_file_path, format!("{}.1", cloudstate_file_path)) {
+        println!("ERROR: Cannot back up cloudstate: {}", err);
+    }
+}
+
+// Compressed cloudstates aren't read by people, so they skip the
+// indentation. Written next to the old one and renamed over it, so
+// a crash mid-write leaves the last complete cloudstate
+fn write_cloudstate(cloudstate_file_path: &str, cloudstate: &CloudState) -> Result<(), SyncError> {
+    let data = if cloudstate.compressed {
+        serde_json::to_vec(cloudstate)
+            .map_err(|err| err.to_string())
+            .and_then(|data| gzip::compress(&data))
+    } else {
+        serde_json::to_vec_pretty(cloudstate).map_err(|err| err.to_string())
+    }
+    .map_err(SyncError::Parse)?;
+    write_atomic(cloudstate_file_path, &data, None)
+        .map_err(|err| SyncError::Io(format!("Cannot write cloudstate: {}", err)))
+}
+
+// NOTE: We're cloning the entire account struct
+// So this will be a costly operation
+fn save_account(account_name: &str, account: &Account) -> Result<(), SyncError> {
+    // Keeping a token refreshed during a request
+    let mut account = account.clone();
+    account.token = http::current_token(&account.token);
+
+    update_config(&config_path(), |config| {
+        config.accounts.insert(account_name.to_owned(), account);
+        Ok(())
+    })
+}
+
+fn refresh_token(account: &mut Account) -> Result<(), SyncError> {
+    let token = match account.service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive => {
+            let client = onedrive::ClientConfig::default().with_attributes(&account.attributes);
+            onedrive::get_token(
+                &client,
+                account.token.refresh_token.as_str(),
+                "refresh_token",
+            )
+        }
+        SyncService::WebDav => Err(SyncError::Auth(
+            "WebDAV accounts have no token to refresh".to_string(),
+        )),
+    }?;
+
+    account.token = token;
+    Ok(())
+}
+
+fn ensure_token(account: &mut Account) -> Result<(), SyncError> {
+    // Requests may have refreshed it after a 401
+    account.token = http::current_token(&account.token);
+
+    if timestamp() > account.token.valid_till {
+        message!("INFO: Token refreshed");
+        refresh_token(account)?;
+    }
+
+    Ok(())
+}
+
+fn is_reserved(file_name: &str) -> bool {
+    let is_backup = file_name
+        .strip_prefix(".cloudstate.")
+        .is_some_and(|index| !index.is_empty() && index.bytes().all(|byte| byte.is_ascii_digit()));
+
+    RESERVED_NAMES.contains(&file_name)
+        || is_backup
+        || xattr::is_sidecar(file_name)
+        || file_name.ends_with(TEMP_SUFFIX)
+}
+
+// Whole components of the folder are taken off, a folder
+// further down named like the root stays in the path
+// @Returns path below the folder with a leading /, e.g. /docs/a.txt
+fn drive_relative_path<'a>(folder_to_sync: &str, full_file_path: &'a str) -> &'a str {
+    let rest = Path::new(full_file_path)
+        .strip_prefix(folder_to_sync)
+        .ok()
+        .and_then(|rest| rest.to_str())
+        .unwrap_or_default();
+
+    // Keeping the separator in front of the rest
+    match rest.len() {
+        0 => "",
+        len => &full_file_path[full_file_path.len() - len - 1..],
+    }
+}
+
+// Trashed files keep their path below the trash
+// folder with the time of the delete appended
+fn trash_path(folder: &str, full_file_path: &str, now: u64) -> String {
+    let file_path = full_file_path
+        .strip_prefix(folder)
+        .unwrap_or(full_file_path);
+    let stamp = format_iso_date(now).replace(':', "-");
+
+    let trash_path = format!("{}/{}{}.{}", folder, TRASH_DIR, file_path, stamp);
+    let mut candidate = trash_path.clone();
+    let mut index = 1;
+    while Path::new(&candidate).exists() {
+        candidate = format!("{}.{}", trash_path, index);
+        index += 1;
+    }
+    candidate
+}
+
+fn remove_local_file(folder: &str, full_file_path: &str, trash: bool) -> std::io::Result<()> {
+    if !trash {
+        return std::fs::remove_file(long_path(full_file_path).as_ref());
+    }
+
+    let trash_path = trash_path(folder, full_file_path, timestamp());
+    if let Some(trash_folder) = Path::new(&trash_path).parent() {
+        std::fs::create_dir_all(long_path(&trash_folder.to_string_lossy()).as_ref())?;
+    }
+    std::fs::rename(
+        long_path(full_file_path).as_ref(),
+        long_path(&trash_path).as_ref(),
+    )
+}
+
+// Windows only opens paths past 260 characters with the extended
+// length prefix, which in turn takes neither forward slashes nor
+// repeated separators. Relative paths can't have it
+fn extended_path(path: &str) -> String {
+    let normalize = |path: &str| {
+        path.split(['/', '\\'])
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("\\")
+    };
+
+    let is_unc = path.starts_with(r"\\") || path.starts_with("//");
+    let is_absolute = path.as_bytes().get(1) == Some(&b':')
+        && matches!(path.as_bytes().get(2), Some(b'/' | b'\\'));
+
+    if let Some(prefixed) = path.strip_prefix(r"\\?\") {
+        format!(r"\\?\{}", normalize(prefixed))
+    } else if is_unc {
+        format!(r"\\?\UNC\{}", normalize(path))
+    } else if is_absolute {
+        format!(r"\\?\{}", normalize(path))
+    } else {
+        path.to_string()
+    }
+}
+
+fn long_path(path: &str) -> Cow<'_, str> {
+    if cfg!(windows) {
+        Cow::Owned(extended_path(path))
+    } else {
+        Cow::Borrowed(path)
+    }
+}
+
+// Contents go to a temporary file which is only renamed over the
+// target once all of it is on disk, so readers see either the old
+// or the new file. Renames can't cross filesystems, a tmp_dir on
+// another one is copied next to the target first
+fn write_atomic(
+    full_file_path: &str,
+    contents: &[u8],
+    tmp_dir: Option<&str>,
+) -> std::io::Result<()> {
+    let target = long_path(full_file_path);
+    let target = Path::new(target.as_ref());
+    let file_name = target
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy())
+        .unwrap_or_default();
+    let temp_name = format!(".{}.{}{}", file_name, std::process::id(), TEMP_SUFFIX);
+
+    let local_temp = target.with_file_name(&temp_name);
+    let temp_path = match tmp_dir {
+        Some(tmp_dir) => Path::new(long_path(tmp_dir).as_ref()).join(&temp_name),
+        None => local_temp.clone(),
+    };
+
+    let written = write_checked(&temp_path, contents).and_then(|_| {
+        match std::fs::rename(&temp_path, target) {
+            Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+                if std::fs::copy(&temp_path, &local_temp)? != contents.len() as u64 {
+                    return Err(std::io::Error::other("Short copy of temporary file"));
+                }
+                std::fs::remove_file(&temp_path)?;
+                std::fs::rename(&local_temp, target)
+            }
+            renamed => renamed,
+        }
+    });
+
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+        let _ = std::fs::remove_file(&local_temp);
+    }
+    written
+}
+
+// Flushed to disk and checked against the
+// expected size before anything is replaced
+fn write_checked(file_path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut file = std::fs::File::create(file_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    if file.metadata()?.len() != contents.len() as u64 {
+        return Err(std::io::Error::other("Short write of temporary file"));
+    }
+    Ok(())
+}
+
+// Files get a new mtime when written and folders when entries
+// are added, removed or renamed, so anything newer than since
+// is a change. Stops at the first one, errors count as changes
+fn has_local_changes(folder: &str, since: u64, tolerance: u64, max_depth: Option<usize>) -> bool {
+    let is_changed = |metadata: &std::fs::Metadata| {
+        metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .is_none_or(|modified| is_newer(modified.as_secs(), since, tolerance))
+    };
+
+    let Ok(metadata) = std::fs::metadata(folder) else {
+        return true;
+    };
+    if is_changed(&metadata) {
+        return true;
+    }
+
+    let Ok(dir_entries) = std::fs::read_dir(folder) else {
+        return true;
+    };
+
+    for entry in dir_entries {
+        let Ok(entry) = entry else {
+            return true;
+        };
+
+        // Edited ignore patterns can bring in old files
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if is_reserved(&file_name) && file_name != ".cloudignore" {
+            continue;
+        }
+
+        // Skipped by the sync as well
+        let Some(file_path) = entry.path().to_str().map(str::to_string) else {
+            continue;
+        };
+
+        let Ok(metadata) = entry.metadata() else {
+            return true;
+        };
+
+        let changed = if metadata.is_dir() {
+            max_depth != Some(0)
+                && has_local_changes(
+                    &file_path,
+                    since,
+                    tolerance,
+                    max_depth.map(|depth| depth - 1),
+                )
+        } else {
+            is_changed(&metadata)
+        };
+
+        if changed {
+            return true;
+        }
+    }
+
+    false
+}
+
+// Recursively walk through, at most max_depth
+// folders below, 0 means files in folder only. Entries below
+// folder which can't be read, like folders without permission,
+// go to skipped instead of failing the walk
+fn read_dir_rec(
+    folder: &str,
+    files: &mut BTreeMap<String, u64>,
+    max_depth: Option<usize>,
+    skipped: &mut Vec<String>,
+) -> std::io::Result<()> {
+    let dir_entries = std::fs::read_dir(folder)?;
+
+    for entry in dir_entries.flatten() {
+        if is_reserved(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+
+        // Names which aren't UTF-8 can't be sent to the cloud and
+        // a lossy one would point at another file, they are skipped
+        let Some(file_path) = entry.path().to_str().map(str::to_string) else {
+            println!(
+                "WARNING: Skipping {}: file name is not valid UTF-8",
+                entry.path().to_string_lossy()
+            );
+            continue;
+        };
+
+        let skip = |err: std::io::Error, skipped: &mut Vec<String>| {
+            println!("WARNING: Skipping {}: {}", file_path, err);
+            skipped.push(file_path.clone());
+        };
+
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                skip(err, skipped);
+                continue;
+            }
+        };
+
+        if metadata.is_dir() {
+            if max_depth != Some(0) {
+                let depth = max_depth.map(|depth| depth - 1);
+                if let Err(err) = read_dir_rec(&file_path, files, depth, skipped) {
+                    skip(err, skipped);
+                }
+            }
+        } else {
+            match metadata.modified() {
+                Ok(modified) => {
+                    let last_modified = modified.duration_since(UNIX_EPOCH).unwrap().as_secs();
+                    files.insert(file_path, last_modified);
+                }
+                Err(err) => skip(err, skipped),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Not every platform or filesystem records it
+fn local_created(file_path: &str) -> Option<u64> {
+    let created = std::fs::metadata(file_path).ok()?.created().ok()?;
+    created
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|created| created.as_secs())
+}
+
+// Only windows and macos allow setting the creation
+// time, elsewhere the file keeps the time of the download
+#[cfg(any(windows, target_os = "macos"))]
+fn set_created(file_path: &str, created: u64) -> std::io::Result<()> {
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::FileTimesExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::FileTimesExt;
+
+    let created = UNIX_EPOCH + Duration::from_secs(created);
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(long_path(file_path).as_ref())?;
+    file.set_times(std::fs::FileTimes::new().set_created(created))
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn set_created(_file_path: &str, _created: u64) -> std::io::Result<()> {
+    Ok(())
+}
+
+// @Returns user the sync runs as, uploads are done by it
+#[cfg(unix)]
+fn upload_owner() -> Option<FileOwner> {
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0; 4096];
+    let mut found = std::ptr::null_mut();
+    let status = unsafe {
+        libc::getpwuid_r(
+            uid,
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+    let user = (status == 0 && !found.is_null())
+        .then(|| unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) })
+        .and_then(|name| name.to_str().ok())
+        .map(str::to_string);
+
+    Some(FileOwner { uid, gid, user })
+}
+
+// Windows has no uid or gid to note
+#[cfg(not(unix))]
+fn upload_owner() -> Option<FileOwner> {
+    None
+}
+
+// @Returns bytes free for the user on the filesystem of the path
+#[cfg(unix)]
+fn free_space(path: &str) -> std::io::Result<u64> {
+    let path = std::ffi::CString::new(path)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // Field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+#[cfg(windows)]
+fn free_space(path: &str) -> std::io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetDiskFreeSpaceExW(
+            directory: *const u16,
+            free_for_caller: *mut u64,
+            total: *mut u64,
+            total_free: *mut u64,
+        ) -> i32;
+    }
+
+    let path: Vec<u16> = std::ffi::OsStr::new(long_path(path).as_ref())
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+    let mut free = 0;
+    let result = unsafe {
+        GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &mut free,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if result == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(free)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn free_space(_path: &str) -> std::io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+// Downloads are written to the tmp dir first when it is set,
+// so it needs the space as well as the folder
+fn check_free_space(
+    folder_to_sync: &str,
+    tmp_dir: Option<&str>,
+    needed: u64,
+) -> Result<(), SyncError> {
+    for path in std::iter::once(folder_to_sync).chain(tmp_dir) {
+        let free = free_space(path).map_err(|err| {
+            SyncError::Io(format!("Cannot get the free space of {}: {}", path, err))
+        })?;
+
+        if free < needed {
+            return Err(SyncError::Io(format!(
+                "Not enough space in {}, downloads need {} but {} are free. Free up space or sync with --no-check-space",
+                path,
+                format_size(needed),
+                format_size(free)
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn timestamp() -> u64 {
+    let start = SystemTime::now();
+    start.duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+// Cloud changes of filtered files are neither downloaded nor
+// deleted locally, the same as their local changes aren't uploaded.
+// Paths only the download filter leaves out are still uploaded
+fn applies_cloud_change(
+    filter: &PathFilter,
+    download_filter: &PathFilter,
+    file_path: &str,
+) -> bool {
+    let file_name = file_path.rsplit('/').next().unwrap_or(file_path);
+    !is_reserved(file_name)
+        && filter.is_included(file_path)
+        && download_filter.is_included(file_path)
+}
+
+// Timestamps within tolerance of each other
+// are considered the same
+fn is_newer(lhs: u64, rhs: u64, tolerance: u64) -> bool {
+    lhs > rhs.saturating_add(tolerance)
+}
+
+// FNV-1a, stable across runs and rust versions
+// unlike std's DefaultHasher
+fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn check_clock_skew(account: &Account, sync_flags: &SyncFlags) -> Result<(), SyncError> {
+    let server_time = match account.service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive => onedrive::get_server_time(account),
+        SyncService::WebDav => webdav::get_server_time(account),
+    };
+
+    let server_time = match server_time {
+        Ok(server_time) => server_time,
+        // Strict syncs don't go on with a clock that wasn't checked
+        Err(err) if sync_flags.strict_clock => return Err(err),
+        Err(err) => {
+            println!("ERROR: Cannot check clock skew: {}", err);
+            return Ok(());
+        }
+    };
+
+    let skew = timestamp().abs_diff(server_time);
+    if skew > CLOCK_SKEW_THRESHOLD {
+        let error = SyncError::ClockSkew { seconds: skew };
+
+        if sync_flags.strict_clock {
+            return Err(error);
+        }
+        println!("WARNING: {}", error);
+    }
+
+    Ok(())
+}
+
+fn sync_files(
+    account: &mut Account,
+    account_name: &str,
+    folder_to_sync: &String,
+    sync_flags: &SyncFlags,
+) -> Result<SyncReport, SyncError> {
+    println!("Syncing {} to {}", folder_to_sync, account_name);
+
+    let started = std::time::Instant::now();
+    let unadvanced = sync_flags.no_advance.then(|| account.clone());
+    let mut profile = Profile::new(sync_flags.profile);
+    let mut report = SyncReport::default();
+    http::reset_breaker(sync_flags.retry_budget, sync_flags.max_retries);
+
+    ensure_token(account)?;
+    check_clock_skew(account, sync_flags)?;
+    profile.lap("auth");
+
+    if sync_flags.fresh {
+        account.last_synced = 0;
+        account.attributes.remove("delta_link");
+        account.attributes.remove(onedrive::SHARED_MOUNTS_KEY);
+    }
+
+    // Replaces the stored link for this run, the link the delta ends
+    // with is only saved once the sync got through
+    if let Some(since_delta) = &sync_flags.since_delta {
+        let delta_link = match account.service {
+            SyncService::GDrive => todo!(),
+            SyncService::Onedrive => onedrive::replay_delta_link(account, since_delta)?,
+            SyncService::WebDav => {
+                return Err(SyncError::Usage(
+                    "WebDAV has no delta links, --since-delta only works with onedrive".to_string(),
+                ))
+            }
+        };
+        println!("INFO: Reading cloud changes from {}", delta_link);
+        account
+            .attributes
+            .insert("delta_link".to_string(), delta_link);
+    }
+
+    // Getting cloud changes
+    let deltas = match account.service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive => onedrive::get_drive_delta(account, sync_flags.follow_shared)?,
+        SyncService::WebDav => webdav::get_drive_delta(account)?,
+    };
+    profile.lap("delta");
+
+    // Failed fetches are errors, only an empty drive gets here
+    if account.last_synced == 0 && deltas.is_empty() {
+        println!("INFO: Cloud is empty, uploading every local file");
+    }
+
+    // Nothing changed on either side and nothing is left over
+    // from the last sync, so the cloudstate needn't be touched
+    let cloud_changed = deltas
+        .iter()
+        .any(|delta| delta.last_modified > account.last_synced);
+    let unfinished = account.attributes.contains_key(SYNC_ERRORS_KEY);
+    if !sync_flags.fresh
+        && sync_flags.since_delta.is_none()
+        && account.last_synced > 0
+        && !cloud_changed
+        && !unfinished
+        && !has_local_changes(
+            folder_to_sync,
+            account.last_synced,
+            sync_flags.mtime_tolerance,
+            sync_flags.max_depth,
+        )
+    {
+        println!("INFO: No changes since the last sync");
+        profile.lap("local walk");
+
+        // Keeping the delta link and token
+        save_synced_account(account_name, account, unadvanced.as_ref())?;
+        profile.lap("saving");
+        profile.print();
+        report.duration_ms = started.elapsed().as_millis();
+        return Ok(report);
+    }
+
+    // Getting local changes
+    let mut local_files = BTreeMap::new();
+    let mut unreadable = Vec::new();
+    read_dir_rec(
+        folder_to_sync,
+        &mut local_files,
+        sync_flags.max_depth,
+        &mut unreadable,
+    )
+    .map_err(|err| SyncError::Io(format!("Cannot walk folder to sync: {}", err)))?;
+
+    // Files we couldn't read would look deleted locally,
+    // they are left out like remote ignores instead
+    if !unreadable.is_empty() {
+        println!(
+            "WARNING: {} unreadable paths are left out of this sync",
+            unreadable.len()
+        );
+    }
+    let left_out = sync_flags
+        .remote_ignores
+        .iter()
+        .cloned()
+        .chain(
+            unreadable
+                .iter()
+                .map(|file_path| drive_relative_path(folder_to_sync, file_path).to_string()),
+        )
+        .collect();
+
+    // Files left out by --include or .cloudignore are
+    // neither transferred nor deleted on either side
+    let ignore_file_path = format!("{}/.cloudignore", folder_to_sync);
+    let filter = PathFilter::new(
+        sync_flags.includes.clone(),
+        PathFilter::read_ignore_file(&ignore_file_path),
+    )
+    .with_max_depth(sync_flags.max_depth)
+    .with_remote_ignores(left_out);
+    let download_filter = PathFilter::new(sync_flags.download_includes.clone(), Vec::new());
+    local_files
+        .retain(|file_path, _| filter.is_included(drive_relative_path(folder_to_sync, file_path)));
+    profile.lap("local walk");
+
+    // Deleting local files incase of fresh sync, backups
+    // and syncs preferring local upload everything instead
+    let prefer = Prefer::effective(sync_flags.prefer, sync_flags.fresh);
+    if sync_flags.fresh && !sync_flags.backup && prefer != Prefer::Local {
+        println!("INFO: Cleaning up local files {}", local_files.len());
+
+        for file_path in local_files.keys() {
+            remove_local_file(folder_to_sync, file_path, sync_flags.trash)
+                .map_err(|err| SyncError::Io(format!("Cannot remove file: {}", err)))?;
+        }
+
+        local_files = BTreeMap::new();
+    }
+
+    // Stateless syncs start from an empty cloudstate
+    // every time and never write it back
+    let cloudstate_file_path = cloudstate_path(folder_to_sync, sync_flags.state_dir.as_deref());
+    let mut cloudstate = if !sync_flags.fresh && !sync_flags.stateless {
+        println!("INFO: Reading cloudstate");
+        read_cloudstate(&cloudstate_file_path)?
+    } else {
+        CloudState::default()
+    };
+
+    // Before anything is written, uploads save
+    // their progress during the sync
+    if !sync_flags.stateless {
+        rotate_cloudstate(&cloudstate_file_path, sync_flags.state_backups);
+    }
+
+    // Without the flag a compressed cloudstate is written as JSON again
+    cloudstate.compressed = sync_flags.compress_state;
+
+    // Sessions past the server timeout can't be resumed
+    cloudstate
+        .uploads
+        .retain(|_, session| !session.is_expired());
+
+    profile.lap("cloudstate");
+
+    println!("INFO: Cloud Delta {}", deltas.len());
+    println!("INFO: Cloud files {}", cloudstate.entries.len());
+    println!("INFO: Local files {}", local_files.len());
+
+    // Downloads are collected and run together on the
+    // transfer pool, later deltas for a path replace earlier ones
+    let mut pending_downloads: HashMap<String, PendingDownload> = HashMap::new();
+
+    // Cloud ids of changed sidecars by the path of their file
+    let mut changed_sidecars: HashMap<String, String> = HashMap::new();
+
+    let export_pdf = match account.service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive => sync_flags.export_pdf.as_ref(),
+        SyncService::WebDav => {
+            if sync_flags.export_pdf.is_some() {
+                println!("INFO: WebDAV servers can't convert files, downloading originals");
+            }
+            None
+        }
+    };
+
+    if sync_flags.backup {
+        backup_pending_downloads(&mut cloudstate, &local_files, folder_to_sync);
+    }
+
+    // Downloads which failed last time
+    for (file_path, entry) in &cloudstate.entries {
+        if entry.pending != Some(PendingTransfer::Download)
+            || !applies_cloud_change(&filter, &download_filter, file_path)
+        {
+            continue;
+        }
+
+        let (folder, _) = file_path.rsplit_once('/').unwrap();
+        pending_downloads.insert(
+            file_path.clone(),
+            PendingDownload {
+                cloud_id: entry.cloud_id.clone(),
+                full_file_path: format!("{}{}", folder_to_sync, file_path),
+                full_folder_path: format!("{}/{}", folder_to_sync, folder),
+                created: None,
+                ctag: None,
+                size: 0,
+            },
+        );
+    }
+
+    for delta in &deltas {
+        // Skip the cloud sync cloud we have
+        // already have this file from the last sync,
+        // replays go through the changes again
+        if sync_flags.since_delta.is_none() && account.last_synced >= delta.last_modified {
+            continue;
+        }
+
+        if sync_flags
+            .since
+            .is_some_and(|since| delta.last_modified < since)
+        {
+            continue;
+        }
+
+        if sync_flags.sync_xattrs && !sync_flags.backup {
+            let target = xattr::sidecar_target(&delta.file_path);
+            if let (Some(target), DriveDeltaType::CreatedOrModifiled) = (target, &delta.delta_type)
+            {
+                changed_sidecars.insert(target.to_string(), delta.cloud_id.clone());
+            }
+        }
+
+        let (folder, _) = delta.file_path.rsplit_once('/').unwrap();
+        if !applies_cloud_change(&filter, &download_filter, &delta.file_path) {
+            continue;
+        }
+
+        // Files deleted in the cloud are uploaded again,
+        // cloud changes are left to be overwritten
+        if sync_flags.backup {
+            if let DriveDeltaType::Deleted = delta.delta_type {
+                cloudstate.entries.remove(&delta.file_path);
+            }
+            continue;
+        }
+
+        // Exports are synced by their local path, the
+        // cloudstate entry keeps the id of the original
+        let file_path = match export_pdf {
+            Some(export_pdf) => export_pdf.local_path(&delta.file_path),
+            None => delta.file_path.clone(),
+        };
+        let full_file_path = format!("{}{}", folder_to_sync, file_path);
+        let local_modified = local_files.get(&full_file_path).map_or(0, |val| *val);
+        let cloud_wins = prefer.cloud_wins(
+            delta.last_modified,
+            local_modified,
+            account.last_synced,
+            sync_flags.mtime_tolerance,
+        );
+
+        match delta.delta_type {
+            DriveDeltaType::Deleted => {
+                if cloud_wins {
+                    println!("INFO: Deleting local file {}", full_file_path);
+
+                    match remove_local_file(folder_to_sync, &full_file_path, sync_flags.trash) {
+                        Ok(_) => {
+                            local_files.remove(&full_file_path);
+                            report.deleted_local += 1;
+                        }
+                        Err(err) => {
+                            println!("ERROR: Cannot remove file: {}", err);
+                            report.errors += 1;
+                        }
+                    };
+
+                    cloudstate.entries.remove(&file_path);
+                    pending_downloads.remove(&file_path);
+                }
+            }
+            DriveDeltaType::CreatedOrModifiled => {
+                let unchanged = local_files.contains_key(&full_file_path)
+                    && same_contents(cloudstate.entries.get(&file_path), delta.ctag.as_deref());
+
+                if unchanged {
+                    pending_downloads.remove(&file_path);
+                } else if cloud_wins {
+                    pending_downloads.insert(
+                        file_path,
+                        PendingDownload {
+                            cloud_id: delta.cloud_id.to_string(),
+                            full_file_path,
+                            full_folder_path: format!("{}/{}", folder_to_sync, folder),
+                            created: delta.created,
+                            ctag: delta.ctag.clone(),
+                            size: delta.size.unwrap_or(0),
+                        },
+                    );
+                } else {
+                    cloudstate.entries.remove(&file_path);
+                }
+            }
+        }
+    }
+
+    // Downloading cloud changes
+    let mut pending_downloads: Vec<(String, PendingDownload)> =
+        pending_downloads.into_iter().collect();
+
+    // Downloads past --max-files are picked up
+    // from the cloudstate by the next sync
+    if let Some(max_files) = sync_flags.max_files {
+        pending_downloads.sort_by(|(path, _), (other, _)| path.cmp(other));
+        let kept = max_files.min(pending_downloads.len());
+        for (file_path, pending) in pending_downloads.split_off(kept) {
+            mark_download_pending(&mut cloudstate, file_path, pending.cloud_id);
+            report.deferred += 1;
+        }
+    }
+
+    // Stopping before the disk fills up half way
+    if sync_flags.check_space.unwrap_or(sync_flags.fresh) {
+        let needed = pending_downloads
+            .iter()
+            .map(|(_, pending)| pending.size)
+            .sum();
+        check_free_space(folder_to_sync, sync_flags.tmp_dir.as_deref(), needed)?;
+    }
+
+    // Deciding on uploads before the downloads so the total is known,
+    // downloaded files and failed downloads are never uploaded
+    let last_synced = account.last_synced;
+    let wants_upload = |cloudstate: &CloudState, drive_relative_path: &str, local_modified| {
+        // Exports are only downloaded, edits stay local
+        if export_pdf.is_some_and(|export_pdf| export_pdf.is_export(drive_relative_path)) {
+            return false;
+        }
+
+        if sync_flags.stateless {
+            is_newer(local_modified, last_synced, sync_flags.mtime_tolerance)
+        } else {
+            needs_upload(
+                cloudstate.entries.get(drive_relative_path),
+                local_modified,
+                last_synced,
+                sync_flags.mtime_tolerance,
+            )
+        }
+    };
+    let mut progress = if sync_flags.progress {
+        let downloading: HashSet<&str> = pending_downloads
+            .iter()
+            .map(|(file_path, _)| file_path.as_str())
+            .collect();
+        let mut upload_sizes: Vec<u64> = local_files
+            .iter()
+            .filter(|(file_path, local_modified)| {
+                let drive_relative_path = drive_relative_path(folder_to_sync, file_path);
+                !downloading.contains(drive_relative_path)
+                    && wants_upload(&cloudstate, drive_relative_path, **local_modified)
+            })
+            .map(|(file_path, _)| {
+                std::fs::metadata(long_path(file_path).as_ref()).map_or(0, |meta| meta.len())
+            })
+            .collect();
+
+        // Uploads past --max-files are left for the next sync
+        if let Some(max_files) = sync_flags.max_files {
+            upload_sizes.truncate(max_files.saturating_sub(pending_downloads.len()));
+        }
+
+        let download_bytes: u64 = pending_downloads
+            .iter()
+            .map(|(_, pending)| pending.size)
+            .sum();
+        Progress::new(
+            true,
+            pending_downloads.len() + upload_sizes.len(),
+            download_bytes + upload_sizes.iter().sum::<u64>(),
+        )
+    } else {
+        Progress::new(false, 0, 0)
+    };
+
+    profile.lap("planning");
+
+    let requests: Vec<_> = pending_downloads
+        .iter()
+        .map(|(file_path, pending)| {
+            // Unchanged contents of files we
+            // have come back as not modified
+            let cached_ctag = cloudstate
+                .entries
+                .get(file_path)
+                .filter(|_| local_files.contains_key(&pending.full_file_path))
+                .and_then(|entry| entry.ctag.as_deref());
+
+            // Converted contents have no ctag of their own
+            let export_of = export_pdf.and_then(|export_pdf| export_pdf.cloud_path(file_path));
+            match account.service {
+                SyncService::GDrive => todo!(),
+                SyncService::Onedrive => match export_of {
+                    Some(cloud_path) => {
+                        onedrive::download_request(account, cloud_path, None, Some("pdf"))
+                    }
+                    None => onedrive::download_request(account, file_path, cached_ctag, None),
+                },
+                SyncService::WebDav => webdav::download_request(account, file_path),
+            }
+        })
+        .collect();
+
+    println!(
+        "INFO: Downloading {} files, {} at a time",
+        requests.len(),
+        sync_flags.jobs
+    );
+    // Downloads are written as they come in
+    // instead of holding every body until the end
+    let mut pending_downloads: Vec<Option<(String, PendingDownload)>> =
+        pending_downloads.into_iter().map(Some).collect();
+    transfer::download_all(&requests, sync_flags.jobs, |index, response| {
+        let bytes = match &response {
+            Ok(Some(contents)) => contents.len() as u64,
+            _ => 0,
+        };
+        progress.file_done(bytes);
+
+        let Some((file_path, pending)) = pending_downloads[index].take() else {
+            return;
+        };
+
+        match response {
+            Ok(None) => {
+                println!("INFO: Not modified {}", file_path);
+
+                if let Some(entry) = cloudstate.entries.get_mut(&file_path) {
+                    entry.cloud_id = pending.cloud_id;
+                    entry.pending = None;
+                    entry.ctag = pending.ctag.or(entry.ctag.take());
+                }
+            }
+            Ok(Some(contents)) => {
+                println!("INFO: Downloaded {}", file_path);
+
+                let contents = match &sync_flags.bom_strip {
+                    Some(bom_strip) => bom_strip.to_local(&file_path, contents),
+                    None => contents,
+                };
+                let contents = match &sync_flags.line_endings {
+                    Some(line_endings) => line_endings.to_local(&file_path, contents),
+                    None => contents,
+                };
+
+                let written =
+                    std::fs::create_dir_all(long_path(&pending.full_folder_path).as_ref())
+                        .and_then(|_| {
+                            write_atomic(
+                                &pending.full_file_path,
+                                &contents,
+                                sync_flags.tmp_dir.as_deref(),
+                            )
+                        });
+                // A full disk or a locked file fails only this
+                // download, the next sync tries it again
+                if let Err(err) = written {
+                    println!("ERROR: Cannot write {}: {}", pending.full_file_path, err);
+                    report.errors += 1;
+                    mark_download_pending(&mut cloudstate, file_path, pending.cloud_id);
+                    return;
+                }
+
+                if let Some(created) = pending.created {
+                    if let Err(err) = set_created(&pending.full_file_path, created) {
+                        println!("ERROR: Cannot set creation time of {}: {}", file_path, err);
+                    }
+                }
+
+                let ts = timestamp();
+                cloudstate.entries.insert(
+                    file_path,
+                    CloudStateEntry {
+                        cloud_id: pending.cloud_id,
+                        last_modified: ts,
+                        pending: None,
+                        ctag: pending.ctag,
+                        xattrs: None,
+                        blocks: None,
+                        owner: None,
+                    },
+                );
+                local_files.insert(pending.full_file_path, ts);
+                report.downloaded += 1;
+            }
+            // Deleted outside cloudsync since the entry was made, without
+            // it a local copy looks new and is uploaded again
+            Err(SyncError::Api { code: 404, .. }) => {
+                println!("INFO: {} is gone from the cloud", file_path);
+                cloudstate.entries.remove(&file_path);
+            }
+            Err(err) => {
+                println!("ERROR: Downloading file {}: {}", file_path, err);
+                report.errors += 1;
+
+                mark_download_pending(&mut cloudstate, file_path, pending.cloud_id);
+            }
+        };
+    });
+
+    // Attributes are set on files we have, whether
+    // they were downloaded or only their attributes changed
+    for (file_path, sidecar_id) in changed_sidecars {
+        let full_file_path = format!("{}{}", folder_to_sync, file_path);
+        let Some(entry) = cloudstate.entries.get_mut(&file_path) else {
+            continue;
+        };
+        if entry.pending.is_some() || !local_files.contains_key(&full_file_path) {
+            continue;
+        }
+
+        match restore_xattrs(account, &file_path, &full_file_path) {
+            Ok(_) => entry.xattrs = Some(sidecar_id),
+            Err(err) => {
+                println!("ERROR: Restoring attributes of {}: {}", file_path, err);
+                report.errors += 1;
+            }
+        }
+    }
+
+    profile.lap("downloads");
+
+    // Uploading locally modified files
+    let mut uploaded_contents: HashMap<u64, (String, String)> = HashMap::new();
+
+    // Graph upload sessions always replace the whole
+    // file, only some WebDAV servers take partial updates
+    let partial_update = sync_flags.delta_upload
+        && match account.service {
+            SyncService::GDrive => todo!(),
+            SyncService::Onedrive => {
+                println!("INFO: OneDrive can't update files in place, uploading whole files");
+                false
+            }
+            SyncService::WebDav => match webdav::supports_partial_update(account) {
+                Ok(true) => true,
+                Ok(false) => {
+                    println!("INFO: Server can't update files in place, uploading whole files");
+                    false
+                }
+                Err(err) => {
+                    println!("ERROR: Cannot check for partial updates: {}", err);
+                    false
+                }
+            },
+        };
+    let read_for_upload = |file_path: &str| {
+        std::fs::read(file_path).map(|contents| match &sync_flags.line_endings {
+            Some(line_endings) => line_endings.to_cloud(file_path, contents),
+            None => contents,
+        })
+    };
+    let owner = sync_flags.record_owner.then(upload_owner).flatten();
+    for (file_path, local_modified) in &local_files {
+        let local_modified = *local_modified;
+        let drive_relative_path = drive_relative_path(folder_to_sync, file_path);
+        let should_upload = wants_upload(&cloudstate, drive_relative_path, local_modified);
+
+        // Files left out are uploaded by the next sync
+        if should_upload && stop_on_breaker(&mut report) {
+            mark_upload_failed(&mut cloudstate, drive_relative_path);
+            continue;
+        }
+
+        let transferred = (report.uploaded + report.downloaded) as usize;
+        if should_upload && sync_flags.max_files.is_some_and(|max| transferred >= max) {
+            mark_upload_failed(&mut cloudstate, drive_relative_path);
+            report.deferred += 1;
+            continue;
+        }
+
+        if should_upload {
+            match read_for_upload(file_path) {
+                Ok(file_contents) => {
+                    let created = local_created(file_path);
+                    let copy = |original_id: &str| {
+                        println!("INFO: Copying {} from duplicate", file_path);
+
+                        match account.service {
+                            SyncService::GDrive => todo!(),
+                            SyncService::Onedrive => {
+                                // Copies get the creation time of the original
+                                onedrive::copy_item(account, original_id, drive_relative_path)
+                                    .and_then(|cloud_id| match created {
+                                        Some(created) => {
+                                            onedrive::set_created(account, &cloud_id, created)
+                                                .map(|_| cloud_id)
+                                        }
+                                        None => Ok(cloud_id),
+                                    })
+                            }
+                            SyncService::WebDav => {
+                                webdav::copy_item(account, original_id, drive_relative_path)
+                            }
+                        }
+                    };
+
+                    let mut blocks = None;
+                    let response = upload_deduplicated(
+                        &mut uploaded_contents,
+                        file_path,
+                        &file_contents,
+                        read_for_upload,
+                        copy,
+                        || {
+                            println!("INFO: Uploading {}", file_path);
+
+                            match account.service {
+                                SyncService::GDrive => todo!(),
+                                SyncService::Onedrive
+                                    if file_contents.len() > onedrive::SIMPLE_UPLOAD_LIMIT =>
+                                {
+                                    // Session is saved after every chunk so an
+                                    // interrupted upload resumes on the next sync
+                                    let stored_session =
+                                        cloudstate.uploads.remove(drive_relative_path);
+                                    onedrive::upload_large_file(
+                                        account,
+                                        drive_relative_path,
+                                        &file_contents,
+                                        created,
+                                        stored_session,
+                                        |session| {
+                                            if sync_flags.stateless {
+                                                return;
+                                            }
+
+                                            cloudstate.uploads.insert(
+                                                drive_relative_path.to_string(),
+                                                session.clone(),
+                                            );
+                                            if let Err(err) =
+                                                write_cloudstate(&cloudstate_file_path, &cloudstate)
+                                            {
+                                                println!("ERROR: {}", err);
+                                            }
+                                        },
+                                    )
+                                }
+                                SyncService::Onedrive => onedrive::upload_new_file(
+                                    account,
+                                    drive_relative_path,
+                                    &file_contents,
+                                    created,
+                                ),
+                                SyncService::WebDav if partial_update => {
+                                    let previous = cloudstate
+                                        .entries
+                                        .get(drive_relative_path)
+                                        .and_then(|entry| entry.blocks.clone());
+                                    upload_changed_blocks(
+                                        account,
+                                        drive_relative_path,
+                                        &file_contents,
+                                        previous,
+                                    )
+                                    .map(
+                                        |(cloud_id, new_blocks)| {
+                                            blocks = new_blocks;
+                                            cloud_id
+                                        },
+                                    )
+                                }
+                                SyncService::WebDav => webdav::upload_new_file(
+                                    account,
+                                    drive_relative_path,
+                                    &file_contents,
+                                ),
+                            }
+                        },
+                    );
+
+                    match response {
+                        Ok(cloud_id) => {
+                            cloudstate.uploads.remove(drive_relative_path);
+
+                            let mut xattrs = cloudstate
+                                .entries
+                                .get(drive_relative_path)
+                                .and_then(|entry| entry.xattrs.clone());
+                            if sync_flags.sync_xattrs {
+                                match upload_xattrs(
+                                    account,
+                                    file_path,
+                                    drive_relative_path,
+                                    xattrs.as_deref(),
+                                ) {
+                                    Ok(sidecar_id) => xattrs = sidecar_id,
+                                    Err(err) => {
+                                        println!("ERROR: Uploading attributes: {}", err);
+                                        report.errors += 1;
+                                    }
+                                }
+                            }
+
+                            let ts = timestamp();
+                            report.uploads.push(UploadedFile {
+                                path: drive_relative_path.to_string(),
+                                cloud_id: cloud_id.clone(),
+                            });
+                            cloudstate.entries.insert(
+                                drive_relative_path.to_string(),
+                                CloudStateEntry {
+                                    cloud_id,
+                                    last_modified: ts,
+                                    pending: None,
+                                    ctag: None,
+                                    xattrs,
+                                    blocks,
+                                    owner: owner.clone(),
+                                },
+                            );
+                            report.uploaded += 1;
+                            progress.file_done(file_contents.len() as u64);
+                        }
+                        Err(err) => {
+                            println!("ERROR: Uploading file: {}", err);
+                            report.errors += 1;
+                            mark_upload_failed(&mut cloudstate, drive_relative_path);
+                            progress.file_done(0);
+                        }
+                    };
+                }
+                Err(err) => {
+                    println!("ERROR: Reading file {}: {}", file_path, err);
+                    report.errors += 1;
+                    mark_upload_failed(&mut cloudstate, drive_relative_path);
+                    progress.file_done(0);
+                }
+            }
+        }
+    }
+
+    // Deleted exports are only forgotten, the original stays
+    // in the cloud and is exported again once it changes
+    if let Some(export_pdf) = export_pdf {
+        cloudstate.entries.retain(|file_path, entry| {
+            !export_pdf.is_export(file_path)
+                || entry.pending.is_some()
+                || local_files.contains_key(&format!("{}{}", folder_to_sync, file_path))
+        });
+    }
+
+    profile.lap("uploads");
+
+    // Removing cloud files
+    if !stop_on_breaker(&mut report) {
+        let deleted = delete_removed_files(
+            &mut cloudstate,
+            &local_files,
+            folder_to_sync,
+            &filter,
+            &mut report,
+            |cloud_ids| delete_cloud_files(account, cloud_ids, sync_flags.delete_jobs),
+        );
+
+        if sync_flags.prune_empty_dirs {
+            prune_empty_folders(
+                &deleted,
+                folder_to_sync,
+                &mut report,
+                |folder_path| match account.service {
+                    SyncService::GDrive => todo!(),
+                    SyncService::Onedrive => onedrive::delete_empty_folder(account, folder_path),
+                    SyncService::WebDav => webdav::delete_empty_folder(account, folder_path),
+                },
+            );
+        }
+    }
+
+    profile.lap("deletes");
+
+    if !sync_flags.stateless {
+        write_cloudstate(&cloudstate_file_path, &cloudstate)?;
+    }
+
+    if report.deferred > 0 {
+        println!(
+            "INFO: Reached --max-files, {} transfers are left for the next sync",
+            report.deferred
+        );
+    }
+
+    // Save changes to account
+    if !sync_flags.no_advance {
+        account.last_synced = timestamp();
+    }
+    if report.errors > 0 || report.deferred > 0 || !cloudstate.uploads.is_empty() {
+        account
+            .attributes
+            .insert(SYNC_ERRORS_KEY.to_string(), report.errors.to_string());
+    } else {
+        account.attributes.remove(SYNC_ERRORS_KEY);
+    }
+    save_synced_account(account_name, account, unadvanced.as_ref())?;
+    profile.lap("saving");
+    profile.print();
+
+    report.duration_ms = started.elapsed().as_millis();
+    Ok(report)
+}
+
+// Accounts kept by --no-advance only take the refreshed
+// token, a revoked one would fail the next sync otherwise
+fn save_synced_account(
+    account_name: &str,
+    account: &Account,
+    unadvanced: Option<&Account>,
+) -> Result<(), SyncError> {
+    match unadvanced {
+        Some(unadvanced) => {
+            let mut unadvanced = unadvanced.clone();
+            unadvanced.token = account.token.clone();
+            save_account(account_name, &unadvanced)
+        }
+        None => save_account(account_name, account),
+    }
+}
+
+// Files with identical contents are copied server side from the
+// first upload instead of sending the bytes again. The hash only
+// finds the candidate, contents are compared before copying and
+// a failed copy falls back to the upload
+fn upload_deduplicated(
+    uploaded_contents: &mut HashMap<u64, (String, String)>,
+    file_path: &str,
+    file_contents: &[u8],
+    read: impl Fn(&str) -> std::io::Result<Vec<u8>>,
+    copy: impl FnOnce(&str) -> Result<String, SyncError>,
+    upload: impl FnOnce() -> Result<String, SyncError>,
+) -> Result<String, SyncError> {
+    let content_hash = fnv1a(file_contents);
+    let copied_id = uploaded_contents
+        .get(&content_hash)
+        .filter(|(original_path, _)| {
+            read(original_path).is_ok_and(|original| original == file_contents)
+        })
+        .and_then(|(_, original_id)| {
+            copy(original_id)
+                .map_err(|err| println!("ERROR: Copying file: {}", err))
+                .ok()
+        });
+
+    let cloud_id = match copied_id {
+        Some(cloud_id) => cloud_id,
+        None => upload()?,
+    };
+    uploaded_contents
+        .entry(content_hash)
+        .or_insert_with(|| (file_path.to_string(), cloud_id.clone()));
+    Ok(cloud_id)
+}
+
+// Entries without a local file were deleted locally, their cloud
+// files go too. Deletes are independent so they run concurrently,
+// cloudstate is only updated once they all finished
+fn delete_removed_files(
+    cloudstate: &mut CloudState,
+    local_files: &BTreeMap<String, u64>,
+    folder_to_sync: &str,
+    filter: &PathFilter,
+    report: &mut SyncReport,
+    delete: impl Fn(&[String]) -> Vec<Result<(), SyncError>>,
+) -> Vec<String> {
+    let mut cloudfiles_to_delete = Vec::new();
+    let mut sidecars_to_delete = Vec::new();
+    let joined = SortedJoin::new(
+        local_files
+            .iter()
+            .map(|(file_path, ts)| (drive_relative_path(folder_to_sync, file_path), ts)),
+        cloudstate
+            .entries
+            .iter()
+            .map(|(file_path, entry)| (file_path.as_str(), entry)),
+    );
+    for (file_path, local_modified, entry) in joined {
+        // Missing because its download failed, not deleted
+        let Some(entry) = entry.filter(|entry| entry.pending != Some(PendingTransfer::Download))
+        else {
+            continue;
+        };
+
+        if local_modified.is_none() && filter.is_included(file_path) {
+            println!("INFO: Cloud deleting file {}", file_path);
+            cloudfiles_to_delete.push((file_path.to_string(), entry.cloud_id.clone()));
+            sidecars_to_delete.extend(entry.xattrs.clone());
+        }
+    }
+
+    let cloud_ids: Vec<String> = cloudfiles_to_delete
+        .iter()
+        .map(|(_, cloud_id)| cloud_id.clone())
+        .collect();
+    let responses = delete(&cloud_ids);
+
+    let mut deleted = Vec::new();
+    for ((file_path, _), response) in cloudfiles_to_delete.into_iter().zip(responses) {
+        // Entry is kept on failure so the delete is retried
+        match response {
+            Ok(_) => {
+                report.deleted_remote += 1;
+                cloudstate.entries.remove(&file_path);
+                deleted.push(file_path);
+            }
+            // Deleted outside cloudsync, the entry is stale
+            Err(SyncError::Api { code: 404, .. }) => {
+                println!("INFO: {} was already gone from the cloud", file_path);
+                cloudstate.entries.remove(&file_path);
+                deleted.push(file_path);
+            }
+            Err(err) => {
+                println!("ERROR: Cloud deleting file {}: {}", file_path, err);
+                report.errors += 1;
+            }
+        };
+    }
+
+    // A sidecar left behind only keeps attributes
+    // nothing refers to, so failures aren't retried
+    for response in delete(&sidecars_to_delete) {
+        match response {
+            Ok(_) | Err(SyncError::Api { code: 404, .. }) => {}
+            Err(err) => println!("ERROR: Cloud deleting attributes: {}", err),
+        }
+    }
+
+    deleted
+}
+
+// Folders of deleted files which no longer exist locally are
+// deleted once empty in the cloud, then their parents in turn.
+// Deepest folders go first so a parent is only checked after
+// its children, the root is never deleted
+fn prune_empty_folders(
+    deleted: &[String],
+    folder_to_sync: &str,
+    report: &mut SyncReport,
+    delete_folder: impl Fn(&str) -> Result<bool, SyncError>,
+) {
+    let parent = |path: &str| path.rsplit_once('/').map(|(parent, _)| parent.to_string());
+    let depth = |path: &String| std::cmp::Reverse(path.matches('/').count());
+
+    let mut folders: BTreeSet<(std::cmp::Reverse<usize>, String)> = deleted
+        .iter()
+        .filter_map(|file_path| parent(file_path))
+        .map(|folder| (depth(&folder), folder))
+        .collect();
+
+    while let Some((_, folder_path)) = folders.pop_first() {
+        let full_folder_path = format!("{}{}", folder_to_sync, folder_path);
+        if folder_path.is_empty() || Path::new(long_path(&full_folder_path).as_ref()).exists() {
+            continue;
+        }
+
+        match delete_folder(&folder_path) {
+            Ok(true) => {
+                println!("INFO: Cloud deleted empty folder {}", folder_path);
+                if let Some(parent) = parent(&folder_path) {
+                    folders.insert((depth(&parent), parent));
+                }
+            }
+            Ok(false) => {}
+            Err(err) => {
+                println!("ERROR: Cloud deleting folder {}: {}", folder_path, err);
+                report.errors += 1;
+            }
+        }
+    }
+}
+
+// Onedrive deletes go through the batch endpoint, with
+// at most jobs batches or single deletes in flight
+// @Returns results in the same order as cloud_ids
+fn delete_cloud_files(
+    account: &Account,
+    cloud_ids: &[String],
+    jobs: usize,
+) -> Vec<Result<(), SyncError>> {
+    let batch_size = match account.service {
+        SyncService::Onedrive => onedrive::BATCH_LIMIT,
+        _ => 1,
+    };
+
+    let batches: Vec<&[String]> = cloud_ids.chunks(batch_size).collect();
+    let responses = transfer::run_all(&batches, jobs, |batch| match account.service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive => onedrive::delete_files(account, batch),
+        SyncService::WebDav => batch
+            .iter()
+            .map(|cloud_id| webdav::delete_file(account, cloud_id))
+            .collect(),
+    });
+
+    responses.into_iter().flatten().collect()
+}
+
+// New files, files modified since the last sync
+// and files whose last upload failed are uploaded
+fn needs_upload(
+    entry: Option<&CloudStateEntry>,
+    local_modified: u64,
+    last_synced: u64,
+    tolerance: u64,
+) -> bool {
+    match entry {
+        None => true,
+        Some(entry) => match entry.pending {
+            Some(PendingTransfer::Upload) => true,
+            Some(PendingTransfer::Download) => false,
+            None => {
+                is_newer(local_modified, last_synced, tolerance)
+                    && is_newer(local_modified, entry.last_modified, tolerance)
+            }
+        },
+    }
+}
+
+// Metadata changes like renames of a folder above bump
+// the timestamp of a file but leave its content tag
+fn same_contents(entry: Option<&CloudStateEntry>, ctag: Option<&str>) -> bool {
+    entry.is_some_and(|entry| {
+        entry.pending.is_none() && ctag.is_some() && entry.ctag.as_deref() == ctag
+    })
+}
+
+// Backups never download, files waiting on a download are
+// uploaded if they exist locally or else deleted from the cloud
+fn backup_pending_downloads(
+    cloudstate: &mut CloudState,
+    local_files: &BTreeMap<String, u64>,
+    folder_to_sync: &str,
+) {
+    let joined = SortedJoin::new(
+        local_files
+            .iter()
+            .map(|(file_path, ts)| (drive_relative_path(folder_to_sync, file_path), ts)),
+        cloudstate
+            .entries
+            .iter_mut()
+            .map(|(file_path, entry)| (file_path.as_str(), entry)),
+    );
+    for (_, local_modified, entry) in joined {
+        if let Some(entry) = entry.filter(|entry| entry.pending == Some(PendingTransfer::Download))
+        {
+            entry.pending = local_modified.map(|_| PendingTransfer::Upload);
+        }
+    }
+}
+
+fn restore_xattrs(
+    account: &Account,
+    file_path: &str,
+    full_file_path: &str,
+) -> Result<(), SyncError> {
+    let sidecar_path = xattr::sidecar_path(file_path);
+    let contents = match account.service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive => onedrive::download_file(account, &sidecar_path, None)?,
+        SyncService::WebDav => webdav::download_file(account, &sidecar_path)?,
+    };
+
+    let xattrs = xattr::from_sidecar(&contents)?;
+    for (name, err) in xattr::write(full_file_path, &xattrs) {
+        println!(
+            "ERROR: Cannot set attribute {} of {}: {}",
+            name, file_path, err
+        );
+    }
+    Ok(())
+}
+
+// Files without attributes have no sidecar, an
+// existing one is removed when they lose them all
+// @Returns cloud id of the sidecar
+fn upload_xattrs(
+    account: &Account,
+    file_path: &str,
+    drive_relative_path: &str,
+    sidecar_id: Option<&str>,
+) -> Result<Option<String>, SyncError> {
+    let xattrs = xattr::read(file_path).map_err(|err| {
+        SyncError::Io(format!("Cannot read attributes of {}: {}", file_path, err))
+    })?;
+
+    if xattrs.is_empty() {
+        if let Some(sidecar_id) = sidecar_id {
+            let response = match account.service {
+                SyncService::GDrive => todo!(),
+                SyncService::Onedrive => onedrive::delete_file(account, sidecar_id),
+                SyncService::WebDav => webdav::delete_file(account, sidecar_id),
+            };
+            match response {
+                Ok(_) | Err(SyncError::Api { code: 404, .. }) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        return Ok(None);
+    }
+
+    let sidecar_path = xattr::sidecar_path(drive_relative_path);
+    let contents = xattr::to_sidecar(&xattrs);
+    let sidecar_id = match account.service {
+        SyncService::GDrive => todo!(),
+        SyncService::Onedrive => {
+            onedrive::upload_new_file(account, &sidecar_path, &contents, None)?
+        }
+        SyncService::WebDav => webdav::upload_new_file(account, &sidecar_path, &contents)?,
+    };
+    Ok(Some(sidecar_id))
+}
+
+// Sends only the blocks which changed since the last upload as long
+// as the cloud file is still the version we uploaded, anything
+// else uploads the whole file. A failure part way leaves the cloud
+// file at another version, so the retry uploads it whole
+// @Returns cloud id and block hashes of the new contents
+fn upload_changed_blocks(
+    account: &Account,
+    item_path: &str,
+    contents: &[u8],
+    previous: Option<blocks::BlockHashes>,
+) -> Result<(String, Option<blocks::BlockHashes>), SyncError> {
+    let new_blocks = |etag: Option<String>| {
+        etag.filter(|_| contents.len() >= blocks::MIN_DELTA_SIZE)
+            .map(|etag| blocks::BlockHashes::new(contents, etag))
+    };
+
+    let changed = previous
+        .as_ref()
+        .and_then(|previous| Some((previous.etag.clone(), previous.changed_ranges(contents)?)));
+
+    if let Some((etag, ranges)) = changed {
+        let mut etag = Some(etag);
+        let mut patched = Ok(());
+        for range in &ranges {
+            // Servers which don't send an etag back leave
+            // the later ranges unguarded
+            match webdav::patch_file(
+                account,
+                item_path,
+                range.start,
+                &contents[range.clone()],
+                etag.as_deref(),
+            ) {
+                Ok(new_etag) => etag = new_etag,
+                Err(err) => {
+                    patched = Err(err);
+                    break;
+                }
+            }
+        }
+
+        match patched {
+            Ok(_) => {
+                let changed: usize = ranges.iter().map(|range| range.len()).sum();
+                println!(
+                    "INFO: Uploaded {} of {} bytes of {}",
+                    changed,
+                    contents.len(),
+                    item_path
+                );
+                return Ok((item_path.to_string(), new_blocks(etag)));
+            }
+            Err(SyncError::Api { code: 412, .. }) => {
+                println!(
+                    "INFO: {} changed in the cloud, uploading all of it",
+                    item_path
+                );
+            }
+            Err(SyncError::Api { code: 404, .. }) => {
+                println!(
+                    "INFO: {} is gone from the cloud, uploading all of it",
+                    item_path
+                );
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    let etag = webdav::upload_file(account, item_path, contents)?;
+    Ok((item_path.to_string(), new_blocks(etag)))
+}
+
+// Once the breaker tripped the rest of the sync is skipped,
+// what wasn't done is picked up by the next sync
+// @Returns whether to stop, telling why the first time
+fn stop_on_breaker(report: &mut SyncReport) -> bool {
+    if !http::breaker_tripped() {
+        return false;
+    }
+
+    if !report.breaker_tripped {
+        println!("ERROR: {}, stopping the sync", http::breaker_error());
+        report.breaker_tripped = true;
+        report.errors += 1;
+    }
+    true
+}
+
+// The delta link has moved past the change so the download is
+// retried from the cloudstate. Files re-created in the cloud come
+// with a new id, the retry and later deletes go to that one
+fn mark_download_pending(cloudstate: &mut CloudState, file_path: String, cloud_id: String) {
+    let entry = cloudstate
+        .entries
+        .entry(file_path)
+        .or_insert(CloudStateEntry {
+            cloud_id: String::new(),
+            last_modified: 0,
+            pending: None,
+            ctag: None,
+            xattrs: None,
+            blocks: None,
+            owner: None,
+        });
+    entry.cloud_id = cloud_id;
+    entry.pending = Some(PendingTransfer::Download);
+}
+
+// New files have no entry and are retried anyway, existing
+// ones would look unmodified once last_synced moves past them
+fn mark_upload_failed(cloudstate: &mut CloudState, file_path: &str) {
+    if let Some(entry) = cloudstate.entries.get_mut(file_path) {
+        entry.pending = Some(PendingTransfer::Upload);
+    }
+}
+
+// Assuming date Sun, 06 Aug 2023 13:23:00 GMT (HTTP format)
+// @Returns unix timestamp
+fn parse_http_date(date_time_str: &str) -> Result<u64, SyncError> {
+    let invalid = || SyncError::Parse(format!("Invalid date {}", date_time_str));
+
+    let (_, date_time) = date_time_str.split_once(", ").ok_or_else(invalid)?;
+    let tokens: Vec<&str> = date_time.split(' ').collect();
+    if tokens.len() != 5 || tokens[4] != "GMT" {
+        return Err(invalid());
+    }
+
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let month = MONTHS
+        .iter()
+        .position(|month| *month == tokens[1])
+        .ok_or_else(invalid)?;
+
+    parse_iso_date(&format!(
+        "{}-{:02}-{}T{}Z",
+        tokens[2],
+        month + 1,
+        tokens[0],
+        tokens[3]
+    ))
+}
+
+fn days_per_year(year: u64) -> u64 {
+    if year.is_multiple_of(4) && !year.is_multiple_of(100) || year.is_multiple_of(400) {
+        366
+    } else {
+        365
+    }
+}
+
+fn days_per_month(month: u64, year: u64) -> u64 {
+    match month {
+        1 => 31,
+        2 => {
+            if days_per_year(year) == 365 {
+                28
+            } else {
+                29
+            }
+        }
+        3 => 31,
+        4 => 30,
+        5 => 31,
+        6 => 30,
+        7 => 31,
+        8 => 31,
+        9 => 30,
+        10 => 31,
+        11 => 30,
+        12 => 31,
+        _ => unreachable!(),
+    }
+}
+
+// Assuming unix timestamp
+// @Returns date as 2023-08-06T13:23:00Z (ISO format)
+fn format_iso_date(timestamp: u64) -> String {
+    let seconds_in_day = 24 * 60 * 60;
+    let mut days = timestamp / seconds_in_day;
+    let seconds = timestamp % seconds_in_day;
+
+    let mut year = 1970;
+    while days >= days_per_year(year) {
+        days -= days_per_year(year);
+        year += 1;
+    }
+
+    let mut month = 1;
+    while days >= days_per_month(month, year) {
+        days -= days_per_month(month, year);
+        month += 1;
+    }
+
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        days + 1,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// Assuming date 2023-08-06T13:23:00.093Z (ISO format)
+// @Returns unix timestamp
+fn parse_iso_date(date_time_str: &str) -> Result<u64, SyncError> {
+    let invalid = || SyncError::Parse(format!("Invalid date {}", date_time_str));
+
+    // Components may drop their leading zeros, signs and
+    // more digits than a component can have are refused
+    let parse_up_to = |token: &str, max_digits: usize| {
+        if token.is_empty()
+            || token.len() > max_digits
+            || !token.bytes().all(|byte| byte.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+        token.parse::<u64>().map_err(|_| invalid())
+    };
+    let parse = |token: &str| parse_up_to(token, 2);
+
+    let (date_str, time_str) = date_time_str.split_once('T').ok_or_else(invalid)?;
+    let date_tokens: Vec<&str> = date_str.split('-').collect();
+    if date_tokens.len() != 3 {
+        return Err(invalid());
+    }
+
+    let year = parse_up_to(date_tokens[0], 4)?;
+    let month = parse(date_tokens[1])?;
+    let date = parse(date_tokens[2])?;
+
+    // Fractions of a second of any length are dropped, only
+    // UTC is accepted since offsets aren't applied
+    let clock_end = time_str
+        .find(|c: char| !c.is_ascii_digit() && c != ':')
+        .unwrap_or(time_str.len());
+    let (clock_str, zone_str) = time_str.split_at(clock_end);
+    let zone_str = match zone_str.strip_prefix(['.', ',']) {
+        Some(fraction) => {
+            let zone_str = fraction.trim_start_matches(|c: char| c.is_ascii_digit());
+            if zone_str.len() == fraction.len() {
+                return Err(invalid());
+            }
+            zone_str
+        }
+        None => zone_str,
+    };
+    if !matches!(zone_str, "" | "Z" | "z") {
+        return Err(invalid());
+    }
+
+    // Seconds are optional, some timestamps omit them
+    let time_tokens: Vec<&str> = clock_str.split(':').collect();
+    let (hours, minutes, seconds) = match time_tokens.as_slice() {
+        [hours, minutes] => (parse(hours)?, parse(minutes)?, 0),
+        [hours, minutes, seconds] => (parse(hours)?, parse(minutes)?, parse(seconds)?),
+        _ => return Err(invalid()),
+    };
+
+    let is_valid = year >= 1970
+        && (1..=12).contains(&month)
+        && date >= 1
+        && date <= days_per_month(month, year)
+        && hours < 24
+        && minutes < 60
+        && seconds < 61;
+
+    if !is_valid {
+        return Err(invalid());
+    }
+
+    let mut days_since_epoch = 0;
+    for y in 1970..year {
+        days_since_epoch += days_per_year(y);
+    }
+
+    let mut days_in_year_so_far = 0;
+    for m in 1..month {
+        days_in_year_so_far += days_per_month(m, year);
+    }
+    days_since_epoch += days_in_year_so_far + (date - 1);
+
+    let seconds_in_hour = 60 * 60;
+
+    Ok(
+        (days_since_epoch * 24 * seconds_in_hour)
+            + hours * seconds_in_hour
+            + minutes * 60
+            + seconds,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use crate::filter::PathFilter;
+    use crate::{
+        account_fix, applies_cloud_change, backoff, backup_pending_downloads, canonical_folder,
+        check_attribute, check_free_space, check_home, closest_key, cloudstate_changes,
+        cloudstate_path, color_enabled, delete_removed_files, drive_relative_path, extended_path,
+        fnv1a, folder_and_account, format_iso_date, format_size, has_local_changes,
+        is_dangerous_folder, is_newer, is_reserved, load_config_for_update, long_path,
+        mark_download_pending, mark_upload_failed, merge_config, needs_upload,
+        normalize_account_name, parse_http_date, parse_iso_date, parse_sync_flags,
+        prune_empty_folders, pull_destination, push_destination, read_cloudstate, read_dir_rec,
+        remove_local_file, rename_account, rotate_cloudstate, same_contents, shared_drives,
+        state_dir_path, sync_or_rewind, take_color_option, timestamp, trash_path, update_config,
+        upload_deduplicated, upload_owner, write_atomic, write_cloudstate, write_config, Account,
+        CloudState, CloudStateEntry, ColorMode, Config, FileOwner, PendingTransfer, Prefer,
+        SyncError, SyncReport, SyncService, Token, CLOUDSTATE_VERSION, DEFAULT_MTIME_TOLERANCE,
+        KEYRING_CACHE, KEYRING_KEY, RESERVED_NAMES,
+    };
+
+    #[test]
+    fn test_color_option() {
+        let args = |line: &str| -> Vec<String> { line.split(' ').map(String::from).collect() };
+
+        let mut line = args("cloudsync --color=never sync docs personal");
+        assert_eq!(take_color_option(&mut line).unwrap(), ColorMode::Never);
+        assert_eq!(line, args("cloudsync sync docs personal"));
+
+        let mut line = args("cloudsync sync docs personal --color always -f");
+        assert_eq!(take_color_option(&mut line).unwrap(), ColorMode::Always);
+        assert_eq!(line, args("cloudsync sync docs personal -f"));
+
+        let mut line = args("cloudsync login onedrive");
+        assert_eq!(take_color_option(&mut line).unwrap(), ColorMode::Auto);
+        assert!(take_color_option(&mut args("cloudsync --color=blue")).is_err());
+        assert!(take_color_option(&mut args("cloudsync --color")).is_err());
+
+        assert!(color_enabled(ColorMode::Auto, true, None));
+        assert!(color_enabled(ColorMode::Auto, true, Some("")));
+        assert!(!color_enabled(ColorMode::Auto, true, Some("1")));
+        assert!(!color_enabled(ColorMode::Auto, false, None));
+        assert!(color_enabled(ColorMode::Always, false, Some("1")));
+        assert!(!color_enabled(ColorMode::Never, true, None));
+    }
+
+    // Fresh directory under the system temp dir
+    fn test_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("cloudsync-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_check_attribute() {
+        let onedrive = SyncService::Onedrive;
+        assert!(check_attribute(&onedrive, "client_id", "abc").is_ok());
+        assert!(check_attribute(&onedrive, "proxy", "socks5://localhost").is_ok());
+        assert!(check_attribute(&onedrive, "delta_link", "https://example.com").is_err());
+        assert!(check_attribute(&onedrive, "redirect_url", "localhost").is_err());
+        assert!(check_attribute(&onedrive, "scopes", "").is_err());
+        assert!(check_attribute(&onedrive, "app_folder", "true").is_ok());
+        assert!(check_attribute(&onedrive, "app_folder", "yes").is_err());
+        assert!(check_attribute(&SyncService::WebDav, "keyring", "true").is_ok());
+        assert!(check_attribute(&onedrive, "keyring", "1").is_err());
+
+        let webdav = SyncService::WebDav;
+        assert!(check_attribute(&webdav, "url", "https://cloud.example.com/dav").is_ok());
+        assert!(check_attribute(&webdav, "listing", "{}").is_err());
+
+        let known = &["client_id", "client_secret", "redirect_url", "scopes"];
+        assert_eq!(closest_key("clientid", known), Some("client_id"));
+        assert_eq!(closest_key("scope", known), Some("scopes"));
+        assert_eq!(closest_key("proxy", known), None);
+    }
+
+    #[test]
+    fn test_date_parsing() {
+        assert_eq!(parse_iso_date("2023-08-06T13:23:00Z"), Ok(1691328180));
+        assert_eq!(parse_iso_date("2023-08-06T13:23Z"), Ok(1691328180));
+        assert_eq!(parse_iso_date("2023-08-06T13:23:05.123Z"), Ok(1691328185));
+        assert!(parse_iso_date("2023-08-06T13:23:00+01:00").is_err());
+        assert!(parse_iso_date("2023-08-06T13Z").is_err());
+        assert!(parse_iso_date("2023-08-06").is_err());
+        assert!(parse_iso_date("2023-13-06T13:23:00Z").is_err());
+        assert!(parse_iso_date("2023-02-29T13:23:00Z").is_err());
+    }
+
+    #[test]
+    fn test_date_parsing_variants() {
+        let expected = 1691328180;
+        for seconds in 0..60 {
+            for clock in [
+                format!("13:23:{:02}", seconds),
+                format!("13:23:{}", seconds),
+            ] {
+                for fraction in ["", ".5", ",5", ".123", ".1234567", ".123456789012"] {
+                    for zone in ["Z", "z", ""] {
+                        let date = format!("2023-08-06T{}{}{}", clock, fraction, zone);
+                        assert_eq!(parse_iso_date(&date), Ok(expected + seconds), "{}", date);
+                    }
+                }
+            }
+        }
+        assert_eq!(parse_iso_date("2023-8-6T13:23:00Z"), Ok(expected));
+
+        for date in [
+            "2023-08-06T13:23:00.Z",
+            "2023-08-06T13:23:.5Z",
+            "2023-08-06T13:23:+5Z",
+            "2023-08-06T13:23:005Z",
+            "2023-08-06T13::00Z",
+            "2023-+8-06T13:23:00Z",
+            "20230-08-06T13:23:00Z",
+            "99999999999999999999-08-06T13:23:00Z",
+            "2023-08-06T13:23:00ZZ",
+            "2023-08-06T13:23:00.5.5Z",
+            "2023-08-06T24:00:00Z",
+        ] {
+            assert!(parse_iso_date(date).is_err(), "{}", date);
+        }
+
+        // Truncated and corrupted dates are errors, never panics
+        let date = "2023-08-06T13:23:05.123Z";
+        for end in 0..date.len() {
+            let _ = parse_iso_date(&date[..end]);
+            for replacement in ["", "0", "9", "-", ":", "T", ".", "Z", "é", " "] {
+                let corrupted = format!("{}{}{}", &date[..end], replacement, &date[end + 1..]);
+                let _ = parse_iso_date(&corrupted);
+            }
+        }
+    }
+
+    #[test]
+    fn test_http_date_parsing() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Aug 2023 13:23:00 GMT"),
+            Ok(1691328180)
+        );
+        assert!(parse_http_date("Sun, 06 Foo 2023 13:23:00 GMT").is_err());
+    }
+
+    #[test]
+    fn test_same_second_mtime() {
+        let last_synced = 1691328180;
+
+        // Written in the same second as the sync
+        assert!(!is_newer(last_synced, last_synced, 1));
+        assert!(!is_newer(last_synced + 1, last_synced, 1));
+        assert!(is_newer(last_synced + 2, last_synced, 1));
+
+        // Without tolerance only strictly newer counts
+        assert!(!is_newer(last_synced, last_synced, 0));
+        assert!(is_newer(last_synced + 1, last_synced, 0));
+
+        // An edit a second after the sync is uploaded by default
+        let entry = CloudStateEntry {
+            cloud_id: "id".to_string(),
+            last_modified: last_synced,
+            pending: None,
+            ctag: None,
+            xattrs: None,
+            blocks: None,
+            owner: None,
+        };
+        assert!(needs_upload(
+            Some(&entry),
+            last_synced + 1,
+            last_synced,
+            DEFAULT_MTIME_TOLERANCE
+        ));
+        assert!(!needs_upload(
+            Some(&entry),
+            last_synced,
+            last_synced,
+            DEFAULT_MTIME_TOLERANCE
+        ));
+    }
+
+    #[test]
+    fn test_reserved_names_skipped() {
+        let dir = test_dir("reserved");
+        std::fs::create_dir_all(format!("{}/sub", dir)).unwrap();
+        std::fs::write(format!("{}/a.txt", dir), "a").unwrap();
+        std::fs::write(format!("{}/sub/b.txt", dir), "b").unwrap();
+        for name in RESERVED_NAMES {
+            std::fs::write(format!("{}/{}", dir, name), "{}").unwrap();
+        }
+
+        let mut local_files = BTreeMap::new();
+        read_dir_rec(&dir, &mut local_files, None, &mut Vec::new()).unwrap();
+
+        assert_eq!(local_files.len(), 2);
+        for name in RESERVED_NAMES {
+            assert!(!local_files.keys().any(|path| path.ends_with(name)));
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_trash() {
+        let dir = test_dir("trash");
+        std::fs::create_dir_all(format!("{}/sub", dir)).unwrap();
+        std::fs::write(format!("{}/sub/a.txt", dir), "a").unwrap();
+
+        let now = 1691328180;
+        let trashed = format!("{}/.cloudsync-trash/sub/a.txt.2023-08-06T13-23-00Z", dir);
+        assert_eq!(
+            trash_path(&dir, &format!("{}/sub/a.txt", dir), now),
+            trashed
+        );
+
+        remove_local_file(&dir, &format!("{}/sub/a.txt", dir), true).unwrap();
+        assert!(!std::path::Path::new(&format!("{}/sub/a.txt", dir)).exists());
+
+        // Deleting the same file twice in a second keeps both
+        std::fs::create_dir_all(format!("{}/.cloudsync-trash/sub", dir)).unwrap();
+        std::fs::write(&trashed, "a").unwrap();
+        assert_eq!(
+            trash_path(&dir, &format!("{}/sub/a.txt", dir), now),
+            format!("{}.1", trashed)
+        );
+
+        // The trash itself is never synced
+        let mut local_files = BTreeMap::new();
+        read_dir_rec(&dir, &mut local_files, None, &mut Vec::new()).unwrap();
+        assert!(local_files.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_cloudstate() {
+        let dir = test_dir("rotate");
+        let cloudstate_path = format!("{}/.cloudstate", dir);
+
+        // Nothing to back up yet
+        rotate_cloudstate(&cloudstate_path, 2);
+        assert!(!std::path::Path::new(&format!("{}.1", cloudstate_path)).exists());
+
+        for sync in ["1", "2", "3"] {
+            std::fs::write(&cloudstate_path, sync).unwrap();
+            rotate_cloudstate(&cloudstate_path, 2);
+        }
+
+        let read = |suffix: &str| std::fs::read_to_string(format!("{}{}", cloudstate_path, suffix));
+        assert_eq!(read("").unwrap(), "3");
+        assert_eq!(read(".1").unwrap(), "3");
+        assert_eq!(read(".2").unwrap(), "2");
+        assert!(read(".3").is_err());
+
+        assert!(is_reserved(".cloudstate.2"));
+        assert!(!is_reserved(".cloudstate.old"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_cloudstate() {
+        let dir = test_dir("read-cloudstate");
+        let cloudstate_path = format!("{}/.cloudstate", dir);
+
+        let cloudstate = read_cloudstate(&cloudstate_path).unwrap();
+        assert_eq!(cloudstate.version, CLOUDSTATE_VERSION);
+        assert!(cloudstate.entries.is_empty());
+
+        // Unversioned files are migrated with their entries
+        std::fs::write(
+            &cloudstate_path,
+            r#"{"entries":{"/a.txt":{"cloud_id":"A","last_modified":1}}}"#,
+        )
+        .unwrap();
+        let cloudstate = read_cloudstate(&cloudstate_path).unwrap();
+        assert_eq!(cloudstate.version, CLOUDSTATE_VERSION);
+        assert_eq!(cloudstate.entries["/a.txt"].cloud_id, "A");
+
+        std::fs::write(&cloudstate_path, r#"{"entries":{"/a.txt""#).unwrap();
+        assert!(matches!(
+            read_cloudstate(&cloudstate_path),
+            Err(SyncError::Parse(_))
+        ));
+
+        std::fs::write(&cloudstate_path, r#"{"version":99,"entries":{}}"#).unwrap();
+        assert!(read_cloudstate(&cloudstate_path).is_err());
+
+        // Writes replace the file whole and leave no temporary file
+        write_cloudstate(&cloudstate_path, &CloudState::default()).unwrap();
+        assert!(read_cloudstate(&cloudstate_path)
+            .unwrap()
+            .entries
+            .is_empty());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_entry_owner() {
+        // Entries from before --record-owner have none
+        let entry: CloudStateEntry =
+            serde_json::from_str(r#"{"cloud_id":"A","last_modified":1}"#).unwrap();
+        assert_eq!(entry.owner, None);
+        assert!(!serde_json::to_string(&entry).unwrap().contains("owner"));
+
+        let owner = FileOwner {
+            uid: 1000,
+            gid: 100,
+            user: None,
+        };
+        let entry = CloudStateEntry {
+            owner: Some(owner.clone()),
+            ..entry
+        };
+        let json = serde_json::to_string(&entry).unwrap();
+        assert!(json.contains(r#""owner":{"uid":1000,"gid":100}"#));
+        let entry: CloudStateEntry = serde_json::from_str(&json).unwrap();
+        assert_eq!(entry.owner, Some(owner));
+
+        #[cfg(unix)]
+        assert_eq!(upload_owner().unwrap().uid, unsafe { libc::getuid() });
+    }
+
+    #[test]
+    fn test_state_dir_outside_folder() {
+        let dir = test_dir("state-dir");
+        let folder = std::path::Path::new(&dir).join("synced");
+        std::fs::create_dir_all(&folder).unwrap();
+
+        assert!(state_dir_path(&format!("{}/state", dir), &folder).is_ok());
+        assert!(state_dir_path(&format!("{}/synced/state", dir), &folder).is_err());
+
+        let folder = folder.to_string_lossy().to_string();
+        let state_file = cloudstate_path(&folder, Some(&dir));
+        assert!(!state_file.starts_with(&folder));
+        assert_eq!(state_file, cloudstate_path(&folder, Some(&dir)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_upload_retried() {
+        let last_synced = 1691328180;
+        let mut cloudstate = CloudState::default();
+        cloudstate.entries.insert(
+            "/notes.txt".to_string(),
+            CloudStateEntry {
+                cloud_id: "id".to_string(),
+                last_modified: last_synced,
+                pending: None,
+                ctag: None,
+                xattrs: None,
+                blocks: None,
+                owner: None,
+            },
+        );
+
+        // Modified after the last sync but the upload fails
+        let local_modified = last_synced + 10;
+        let entry = cloudstate.entries.get("/notes.txt");
+        assert!(needs_upload(entry, local_modified, last_synced, 1));
+        mark_upload_failed(&mut cloudstate, "/notes.txt");
+
+        // Next run sees a later last_synced and still uploads it
+        let last_synced = last_synced + 60;
+        let entry = cloudstate.entries.get("/notes.txt");
+        assert_eq!(entry.unwrap().pending, Some(PendingTransfer::Upload));
+        assert!(needs_upload(entry, local_modified, last_synced, 1));
+
+        // Failed new uploads have no entry and are retried too
+        mark_upload_failed(&mut cloudstate, "/new.txt");
+        assert!(needs_upload(
+            cloudstate.entries.get("/new.txt"),
+            local_modified,
+            last_synced,
+            1
+        ));
+
+        // Once the last upload made it only newer changes count
+        cloudstate.entries.get_mut("/notes.txt").unwrap().pending = None;
+        let entry = cloudstate.entries.get("/notes.txt");
+        assert!(!needs_upload(entry, local_modified, last_synced, 1));
+    }
+
+    #[test]
+    fn test_long_paths() {
+        assert_eq!(
+            extended_path(r"C:\Users\me/docs//a.txt"),
+            r"\\?\C:\Users\me\docs\a.txt"
+        );
+        assert_eq!(
+            extended_path(r"\\?\C:\Users\me/docs/a.txt"),
+            r"\\?\C:\Users\me\docs\a.txt"
+        );
+        assert_eq!(
+            extended_path(r"\\server\share/docs/a.txt"),
+            r"\\?\UNC\server\share\docs\a.txt"
+        );
+        assert_eq!(extended_path("docs/a.txt"), "docs/a.txt");
+
+        // Past the 260 characters windows allows without the prefix
+        let dir = test_dir("long-paths");
+        let folder = format!("{}{}", dir, "/nested-folder-name".repeat(16));
+        let file_path = format!("{}/a.txt", folder);
+        assert!(file_path.len() > 260);
+
+        std::fs::create_dir_all(long_path(&folder).as_ref()).unwrap();
+        write_atomic(&file_path, b"deep", None).unwrap();
+        assert_eq!(
+            std::fs::read(long_path(&file_path).as_ref()).unwrap(),
+            b"deep"
+        );
+
+        std::fs::remove_dir_all(long_path(&dir).as_ref()).unwrap();
+    }
+
+    #[test]
+    fn test_has_local_changes() {
+        let dir = test_dir("local-changes");
+        let sub = format!("{}/sub", dir);
+        let file_path = format!("{}/a.txt", sub);
+        std::fs::create_dir_all(&sub).unwrap();
+        std::fs::write(&file_path, "a").unwrap();
+        std::fs::write(format!("{}/.cloudstate", dir), "{}").unwrap();
+
+        let set_modified = |path: &str, modified: u64| {
+            std::fs::File::open(path)
+                .unwrap()
+                .set_modified(UNIX_EPOCH + Duration::from_secs(modified))
+                .unwrap();
+        };
+
+        let last_synced = timestamp() - 60;
+        for path in [&dir, &sub, &file_path] {
+            set_modified(path, last_synced - 10);
+        }
+
+        // Reserved files are written by the sync itself
+        assert!(!has_local_changes(&dir, last_synced, 1, None));
+
+        set_modified(&file_path, last_synced + 10);
+        assert!(has_local_changes(&dir, last_synced, 1, None));
+        assert!(!has_local_changes(&dir, last_synced, 1, Some(0)));
+
+        // Deletes show up on their folder
+        std::fs::remove_file(&file_path).unwrap();
+        assert!(has_local_changes(&dir, last_synced, 1, None));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = test_dir("write-atomic");
+        let tmp_dir = format!("{}/tmp", dir);
+        std::fs::create_dir_all(&tmp_dir).unwrap();
+
+        let target = format!("{}/a.txt", dir);
+        std::fs::write(&target, "old").unwrap();
+        write_atomic(&target, b"new", None).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"new");
+
+        write_atomic(&target, b"newer", Some(&tmp_dir)).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"newer");
+
+        // A failed write keeps the old file and leaves nothing behind
+        assert!(write_atomic(&target, b"lost", Some(&format!("{}/missing", dir))).is_err());
+        assert_eq!(std::fs::read(&target).unwrap(), b"newer");
+
+        let mut left: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["a.txt", "tmp"]);
+        assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 0);
+
+        // Leftovers of a crash are never synced
+        assert!(is_reserved(".a.txt.123.cloudsync-tmp"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_upload_deduplicated() {
+        let local = HashMap::from([
+            ("/sync/a.txt", "same"),
+            ("/sync/b.txt", "same"),
+            ("/sync/c.txt", "other"),
+            ("/sync/d.txt", "other"),
+        ]);
+        let read = |file_path: &str| {
+            local
+                .get(file_path)
+                .map(|contents| contents.as_bytes().to_vec())
+                .ok_or(std::io::Error::from(std::io::ErrorKind::NotFound))
+        };
+        let calls = std::cell::RefCell::new(Vec::new());
+        let copy = |original_id: &str| {
+            calls.borrow_mut().push(format!("copy {}", original_id));
+            Ok::<_, SyncError>(format!("copy-of-{}", original_id))
+        };
+        let upload = |file_path: &str| {
+            calls.borrow_mut().push(format!("upload {}", file_path));
+            Ok::<_, SyncError>(format!("id{}", file_path))
+        };
+
+        // Identical files are uploaded once and copied after
+        let mut uploaded_contents = HashMap::new();
+        for file_path in ["/sync/a.txt", "/sync/b.txt"] {
+            let contents = read(file_path).unwrap();
+            let uploaded = upload_deduplicated(
+                &mut uploaded_contents,
+                file_path,
+                &contents,
+                read,
+                copy,
+                || upload(file_path),
+            );
+            assert!(uploaded.is_ok());
+        }
+        assert_eq!(
+            calls.take(),
+            vec!["upload /sync/a.txt", "copy id/sync/a.txt"]
+        );
+
+        // Same hash with different contents is still uploaded
+        let contents = read("/sync/c.txt").unwrap();
+        uploaded_contents.insert(
+            fnv1a(&contents),
+            ("/sync/a.txt".to_string(), "id/sync/a.txt".to_string()),
+        );
+        let uploaded = upload_deduplicated(
+            &mut uploaded_contents,
+            "/sync/c.txt",
+            &contents,
+            read,
+            copy,
+            || upload("/sync/c.txt"),
+        );
+        assert_eq!(uploaded.unwrap(), "id/sync/c.txt");
+        assert_eq!(calls.take(), vec!["upload /sync/c.txt"]);
+
+        // A failed copy falls back to the upload
+        let mut uploaded_contents = HashMap::from([(
+            fnv1a(&contents),
+            ("/sync/c.txt".to_string(), "id/sync/c.txt".to_string()),
+        )]);
+        let uploaded = upload_deduplicated(
+            &mut uploaded_contents,
+            "/sync/d.txt",
+            &contents,
+            read,
+            |original_id| {
+                calls.borrow_mut().push(format!("copy {}", original_id));
+                Err(SyncError::Api {
+                    code: 409,
+                    message: "nameAlreadyExists".to_string(),
+                })
+            },
+            || upload("/sync/d.txt"),
+        );
+        assert_eq!(uploaded.unwrap(), "id/sync/d.txt");
+        assert_eq!(
+            calls.take(),
+            vec!["copy id/sync/c.txt", "upload /sync/d.txt"]
+        );
+    }
+
+    #[test]
+    fn test_delete_removed_files() {
+        let entry = |cloud_id: &str| CloudStateEntry {
+            cloud_id: cloud_id.to_string(),
+            last_modified: 0,
+            pending: None,
+            ctag: None,
+            xattrs: None,
+            blocks: None,
+            owner: None,
+        };
+
+        let mut cloudstate = CloudState::default();
+        cloudstate
+            .entries
+            .insert("/kept.txt".to_string(), entry("id-kept"));
+        cloudstate
+            .entries
+            .insert("/gone.txt".to_string(), entry("id-gone"));
+        cloudstate
+            .entries
+            .insert("/docs/gone.txt".to_string(), entry("id-docs"));
+        cloudstate
+            .entries
+            .insert("/fails.txt".to_string(), entry("id-fails"));
+        cloudstate
+            .entries
+            .insert("/ignored.log".to_string(), entry("id-ignored"));
+        cloudstate.entries.insert(
+            "/pending.txt".to_string(),
+            CloudStateEntry {
+                pending: Some(PendingTransfer::Download),
+                ..entry("id-pending")
+            },
+        );
+        cloudstate.entries.get_mut("/gone.txt").unwrap().xattrs = Some("id-sidecar".to_string());
+
+        let local_files = BTreeMap::from([("/sync/kept.txt".to_string(), 0)]);
+        let filter = PathFilter::new(Vec::new(), vec!["*.log".to_string()]);
+        let mut report = SyncReport::default();
+
+        // Stands in for the backend, recording every delete
+        let deleted = std::cell::RefCell::new(Vec::new());
+        delete_removed_files(
+            &mut cloudstate,
+            &local_files,
+            "/sync",
+            &filter,
+            &mut report,
+            |cloud_ids| {
+                deleted.borrow_mut().extend(cloud_ids.iter().cloned());
+                cloud_ids
+                    .iter()
+                    .map(|cloud_id| match cloud_id.as_str() {
+                        "id-fails" => Err(SyncError::Network("offline".to_string())),
+                        _ => Ok(()),
+                    })
+                    .collect()
+            },
+        );
+
+        let mut deleted = deleted.into_inner();
+        deleted.sort();
+        assert_eq!(
+            deleted,
+            vec!["id-docs", "id-fails", "id-gone", "id-sidecar"]
+        );
+
+        let mut remaining: Vec<&String> = cloudstate.entries.keys().collect();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            vec!["/fails.txt", "/ignored.log", "/kept.txt", "/pending.txt"]
+        );
+        assert_eq!(report.deleted_remote, 2);
+        assert_eq!(report.errors, 1);
+    }
+
+    #[test]
+    fn test_normalize_account_name() {
+        assert_eq!(normalize_account_name(" personal ").unwrap(), "personal");
+        assert_eq!(normalize_account_name("work-2").unwrap(), "work-2");
+
+        for account_name in ["", "  ", "..", "work/personal", "a\\b", "tab\tname"] {
+            assert!(matches!(
+                normalize_account_name(account_name),
+                Err(SyncError::Usage(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_cloudstate_changes() {
+        let entry = |cloud_id: &str, last_modified: u64| CloudStateEntry {
+            cloud_id: cloud_id.to_string(),
+            last_modified,
+            pending: None,
+            ctag: None,
+            xattrs: None,
+            blocks: None,
+            owner: None,
+        };
+
+        let mut old = CloudState::default();
+        old.entries
+            .insert("/kept.txt".to_string(), entry("id-1", 10));
+        old.entries
+            .insert("/edited.txt".to_string(), entry("id-2", 10));
+        old.entries
+            .insert("/gone.txt".to_string(), entry("id-3", 10));
+        old.entries
+            .insert("/moved.txt".to_string(), entry("id-4", 10));
+
+        let mut new = CloudState::default();
+        new.entries
+            .insert("/kept.txt".to_string(), entry("id-1", 10));
+        new.entries
+            .insert("/edited.txt".to_string(), entry("id-2", 20));
+        new.entries
+            .insert("/moved.txt".to_string(), entry("id-5", 10));
+        new.entries
+            .insert("/new.txt".to_string(), entry("id-6", 20));
+
+        assert_eq!(
+            cloudstate_changes(&old, &new),
+            vec![
+                "/edited.txt\tmodified\t10\t20",
+                "/gone.txt\tremoved\t10\t-",
+                "/moved.txt\tmodified\t10\t10",
+                "/new.txt\tadded\t-\t20",
+            ]
+        );
+        assert!(cloudstate_changes(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_stale_cloud_id() {
+        let mut cloudstate = CloudState::default();
+        cloudstate.entries.insert(
+            "/stale.txt".to_string(),
+            CloudStateEntry {
+                cloud_id: "id-stale".to_string(),
+                last_modified: 0,
+                pending: None,
+                ctag: None,
+                xattrs: Some("id-sidecar".to_string()),
+                blocks: None,
+                owner: None,
+            },
+        );
+
+        let mut report = SyncReport::default();
+        let deleted = delete_removed_files(
+            &mut cloudstate,
+            &BTreeMap::new(),
+            "/sync",
+            &PathFilter::new(Vec::new(), Vec::new()),
+            &mut report,
+            |cloud_ids| {
+                cloud_ids
+                    .iter()
+                    .map(|_| {
+                        Err(SyncError::Api {
+                            code: 404,
+                            message: "itemNotFound: Item not found".to_string(),
+                        })
+                    })
+                    .collect()
+            },
+        );
+
+        // Dropped instead of retried by every later sync
+        assert_eq!(deleted, vec!["/stale.txt"]);
+        assert!(cloudstate.entries.is_empty());
+        assert_eq!(report.deleted_remote, 0);
+        assert_eq!(report.errors, 0);
+    }
+
+    #[test]
+    fn test_prune_empty_folders() {
+        let dir = test_dir("prune-empty");
+        std::fs::create_dir_all(format!("{}/kept", dir)).unwrap();
+
+        let deleted = [
+            "/a/b/c.txt".to_string(),
+            "/a/d.txt".to_string(),
+            "/kept/e.txt".to_string(),
+            "/full/f.txt".to_string(),
+            "/root.txt".to_string(),
+        ];
+        let mut report = SyncReport::default();
+
+        // Stands in for the backend, /full still has files
+        let checked = std::cell::RefCell::new(Vec::new());
+        prune_empty_folders(&deleted, &dir, &mut report, |folder_path| {
+            checked.borrow_mut().push(folder_path.to_string());
+            Ok(folder_path != "/full")
+        });
+
+        // Children before parents, folders still there
+        // locally and the root are left alone
+        assert_eq!(checked.into_inner(), vec!["/a/b", "/a", "/full"]);
+        assert_eq!(report.errors, 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_download_not_uploaded() {
+        let entry = CloudStateEntry {
+            cloud_id: "id".to_string(),
+            last_modified: 0,
+            pending: Some(PendingTransfer::Download),
+            ctag: None,
+            xattrs: None,
+            blocks: None,
+            owner: None,
+        };
+
+        // Stale local copy must not overwrite the cloud file
+        assert!(!needs_upload(Some(&entry), 1691328180, 0, 1));
+    }
+
+    #[test]
+    fn test_max_files() {
+        let folder = test_dir("max_files");
+        let args = |max_files: &str| vec!["--max-files".to_string(), max_files.to_string()];
+        let sync_flags = parse_sync_flags(&args("2"), std::path::Path::new(&folder)).unwrap();
+        assert_eq!(sync_flags.max_files, Some(2));
+        assert!(parse_sync_flags(&args("0"), std::path::Path::new(&folder)).is_err());
+        assert!(parse_sync_flags(&args("all"), std::path::Path::new(&folder)).is_err());
+
+        // Deferred downloads of changed files keep their old
+        // entry and are neither uploaded nor deleted meanwhile
+        let mut cloudstate = CloudState::default();
+        cloudstate.entries.insert(
+            "/old.txt".to_string(),
+            CloudStateEntry {
+                cloud_id: "old".to_string(),
+                last_modified: 1691328180,
+                pending: None,
+                ctag: Some("ctag".to_string()),
+                xattrs: None,
+                blocks: None,
+                owner: None,
+            },
+        );
+        mark_download_pending(&mut cloudstate, "/old.txt".to_string(), "new".to_string());
+        mark_download_pending(&mut cloudstate, "/new.txt".to_string(), "id".to_string());
+
+        let entry = &cloudstate.entries["/old.txt"];
+        assert_eq!(entry.cloud_id, "new");
+        assert_eq!(entry.pending, Some(PendingTransfer::Download));
+        assert!(!needs_upload(Some(entry), 1691328190, 1691328180, 1));
+        let entry = &cloudstate.entries["/new.txt"];
+        assert_eq!(entry.cloud_id, "id");
+        assert_eq!(entry.pending, Some(PendingTransfer::Download));
+    }
+
+    #[test]
+    fn test_check_free_space() {
+        let folder = test_dir("check_free_space");
+        assert!(check_free_space(&folder, None, 0).is_ok());
+        assert!(check_free_space(&folder, None, u64::MAX).is_err());
+
+        // The tmp dir is checked as well
+        let missing = format!("{}/missing", folder);
+        assert!(check_free_space(&folder, Some(&missing), 0).is_err());
+
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 << 30), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_folder_and_account() {
+        let folder = test_dir("folder_and_account");
+        let args = |line: &str| -> Vec<String> { line.split(' ').map(String::from).collect() };
+
+        let sync_args = args(&format!("cloudsync sync {} personal --fresh", folder));
+        let (folder_path, account_name, flags) = folder_and_account(&sync_args).unwrap();
+        assert_eq!(folder_path, std::fs::canonicalize(&folder).unwrap());
+        assert_eq!(account_name, "personal");
+        assert_eq!(flags, args("--fresh"));
+        assert!(folder_and_account(&args("cloudsync sync")).is_err());
+
+        // Default folders are stored absolute and must be folders
+        let file_path = format!("{}/a.txt", folder);
+        std::fs::write(&file_path, "a").unwrap();
+        assert!(canonical_folder(&file_path).is_err());
+        assert!(canonical_folder(&format!("{}/missing", folder)).is_err());
+        assert!(std::path::Path::new(&canonical_folder(&folder).unwrap()).is_absolute());
+    }
+
+    #[test]
+    fn test_drive_relative_path() {
+        assert_eq!(
+            drive_relative_path("/data", "/data/docs/a.txt"),
+            "/docs/a.txt"
+        );
+
+        // A folder named like the root, or the root's path again
+        assert_eq!(
+            drive_relative_path("/data", "/data/data/a.txt"),
+            "/data/a.txt"
+        );
+        assert_eq!(
+            drive_relative_path("/data", "/data/x/data/y.txt"),
+            "/x/data/y.txt"
+        );
+        assert_eq!(
+            drive_relative_path("/home/me/sync", "/home/me/sync/home/me/sync/a.txt"),
+            "/home/me/sync/a.txt"
+        );
+
+        // Only whole components match
+        assert_eq!(drive_relative_path("/data", "/database/a.txt"), "");
+    }
+
+    #[test]
+    fn test_pull_destination() {
+        let folder = test_dir("pull_destination");
+        assert_eq!(
+            pull_destination("/docs/a.txt", &format!("{}/", folder)).unwrap(),
+            format!("{}/a.txt", folder)
+        );
+        assert_eq!(
+            pull_destination("a.txt", &folder).unwrap(),
+            format!("{}/a.txt", folder)
+        );
+
+        // Anything that isn't a folder is the file itself
+        let file_path = format!("{}/b.txt", folder);
+        assert_eq!(
+            pull_destination("/docs/a.txt", &file_path).unwrap(),
+            file_path
+        );
+        assert!(pull_destination("/", &folder).is_err());
+    }
+
+    #[test]
+    fn test_push_destination() {
+        assert_eq!(
+            push_destination("notes/a.txt", "docs/b.txt").unwrap(),
+            "/docs/b.txt"
+        );
+        assert_eq!(
+            push_destination("notes/a.txt", "/docs/").unwrap(),
+            "/docs/a.txt"
+        );
+        assert_eq!(push_destination("a.txt", "/").unwrap(), "/a.txt");
+        assert!(push_destination("..", "/docs/").is_err());
+    }
+
+    #[test]
+    fn test_prefer() {
+        let (last_synced, tolerance) = (1000, 1);
+
+        // Edited locally at 1500 and in the cloud at 2000
+        assert!(Prefer::Newer.cloud_wins(2000, 1500, last_synced, tolerance));
+        assert!(Prefer::Remote.cloud_wins(2000, 1500, last_synced, tolerance));
+        assert!(!Prefer::Local.cloud_wins(2000, 1500, last_synced, tolerance));
+
+        // Edited in the cloud at 1500 and locally at 2000
+        assert!(!Prefer::Newer.cloud_wins(1500, 2000, last_synced, tolerance));
+        assert!(Prefer::Remote.cloud_wins(1500, 2000, last_synced, tolerance));
+        assert!(!Prefer::Local.cloud_wins(1500, 2000, last_synced, tolerance));
+
+        // Without a local change there is no conflict
+        assert!(Prefer::Local.cloud_wins(1500, 900, last_synced, tolerance));
+        assert!(Prefer::Local.cloud_wins(1500, 0, last_synced, tolerance));
+
+        assert_eq!(Prefer::effective(None, false), Prefer::Newer);
+        assert_eq!(Prefer::effective(None, true), Prefer::Remote);
+        assert_eq!(Prefer::effective(Some(Prefer::Local), true), Prefer::Local);
+
+        let flags = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            parse_sync_flags(&args, std::path::Path::new("/sync")).map(|flags| flags.prefer)
+        };
+        assert_eq!(flags(&["--prefer", "local"]).unwrap(), Some(Prefer::Local));
+        assert_eq!(flags(&["--prefer=remote"]).unwrap(), Some(Prefer::Remote));
+        assert_eq!(flags(&[]).unwrap(), None);
+        assert!(flags(&["--prefer=newer"]).is_err());
+        assert!(flags(&["--prefer"]).is_err());
+    }
+
+    #[test]
+    fn test_applies_cloud_change() {
+        let delta_paths = [
+            "/notes/a.md",
+            "/notes/b.txt",
+            "/photos/c.jpg",
+            "/photos/d.JPG",
+            "/e.md",
+            "/notes/.cloudstate",
+        ];
+        let applied = |filter: &PathFilter, download_filter: &PathFilter| {
+            delta_paths
+                .iter()
+                .copied()
+                .filter(|file_path| applies_cloud_change(filter, download_filter, file_path))
+                .collect::<Vec<_>>()
+        };
+
+        let everything = PathFilter::default();
+        assert_eq!(
+            applied(&everything, &everything),
+            vec![
+                "/notes/a.md",
+                "/notes/b.txt",
+                "/photos/c.jpg",
+                "/photos/d.JPG",
+                "/e.md"
+            ]
+        );
+
+        let include = PathFilter::new(vec!["*.md".to_string()], Vec::new());
+        assert_eq!(applied(&include, &everything), vec!["/notes/a.md", "/e.md"]);
+        assert_eq!(applied(&everything, &include), vec!["/notes/a.md", "/e.md"]);
+
+        // Both have to match, and ignores win
+        let ignore_notes = PathFilter::new(
+            vec!["*.md".to_string(), "*.txt".to_string()],
+            vec!["notes/**".to_string()],
+        );
+        assert_eq!(applied(&ignore_notes, &include), vec!["/e.md"]);
+    }
+
+    #[test]
+    fn test_same_contents() {
+        let mut entry = CloudStateEntry {
+            cloud_id: "id".to_string(),
+            last_modified: 0,
+            pending: None,
+            ctag: Some("{ABC},2".to_string()),
+            xattrs: None,
+            blocks: None,
+            owner: None,
+        };
+
+        assert!(same_contents(Some(&entry), Some("{ABC},2")));
+        assert!(!same_contents(Some(&entry), Some("{ABC},3")));
+        assert!(!same_contents(Some(&entry), None));
+        assert!(!same_contents(None, Some("{ABC},2")));
+
+        // Failed downloads are always retried
+        entry.pending = Some(PendingTransfer::Download);
+        assert!(!same_contents(Some(&entry), Some("{ABC},2")));
+
+        // Uploads don't know their tag
+        entry.pending = None;
+        entry.ctag = None;
+        assert!(!same_contents(Some(&entry), None));
+    }
+
+    #[test]
+    fn test_backup_pending_downloads() {
+        let pending = CloudStateEntry {
+            cloud_id: "id".to_string(),
+            last_modified: 0,
+            pending: Some(PendingTransfer::Download),
+            ctag: None,
+            xattrs: None,
+            blocks: None,
+            owner: None,
+        };
+        let mut cloudstate = CloudState::default();
+        cloudstate
+            .entries
+            .insert("/local.txt".to_string(), pending.clone());
+        cloudstate
+            .entries
+            .insert("/missing.txt".to_string(), pending);
+
+        let local_files = BTreeMap::from([("/sync/local.txt".to_string(), 1691328180)]);
+        backup_pending_downloads(&mut cloudstate, &local_files, "/sync");
+
+        assert_eq!(
+            cloudstate.entries["/local.txt"].pending,
+            Some(PendingTransfer::Upload)
+        );
+        assert_eq!(cloudstate.entries["/missing.txt"].pending, None);
+    }
+
+    #[test]
+    fn test_merge_config() {
+        let account = |last_synced| {
+            let token = Token {
+                access_token: String::new(),
+                refresh_token: String::new(),
+                valid_till: 0,
+            };
+            Account {
+                last_synced,
+                ..Account::new(SyncService::Onedrive, token)
+            }
+        };
+
+        let mut config = Config::default();
+        config.accounts.insert("work".to_string(), account(1));
+
+        let mut imported = Config::default();
+        imported.accounts.insert("work".to_string(), account(2));
+        imported.accounts.insert("personal".to_string(), account(3));
+
+        let imported_again = Config {
+            accounts: imported.accounts.clone(),
+            ..Default::default()
+        };
+
+        // Nothing is merged on a collision
+        assert!(merge_config(&mut config, imported, false).is_err());
+        assert_eq!(config.accounts.len(), 1);
+
+        assert_eq!(merge_config(&mut config, imported_again, true), Ok(2));
+        assert_eq!(config.accounts["work"].last_synced, 2);
+        assert_eq!(config.accounts["personal"].last_synced, 3);
+    }
+
+    #[test]
+    fn test_dangerous_folders() {
+        use std::path::Path;
+
+        let home = Some(Path::new("/home/me"));
+        assert!(is_dangerous_folder(Path::new("/"), home));
+        assert!(is_dangerous_folder(Path::new("/usr"), home));
+        assert!(is_dangerous_folder(Path::new("/home"), home));
+        assert!(is_dangerous_folder(Path::new("/home/me"), home));
+
+        assert!(!is_dangerous_folder(Path::new("/home/me/docs"), home));
+        assert!(!is_dangerous_folder(Path::new("/home/other"), home));
+        assert!(!is_dangerous_folder(Path::new("/mnt/backup"), None));
+    }
+
+    #[test]
+    fn test_corrupt_config_backed_up() {
+        let dir = test_dir("corrupt-config");
+        let config_path = format!("{}/cloudsync.json", dir);
+        std::fs::write(&config_path, b"{\"accounts\":{\"\xff\xfe\": 1}}").unwrap();
+
+        let config = load_config_for_update(&config_path).unwrap();
+        assert!(config.accounts.is_empty());
+        assert!(!std::path::Path::new(&config_path).exists());
+        assert_eq!(
+            std::fs::read(format!("{}.bak", config_path)).unwrap(),
+            b"{\"accounts\":{\"\xff\xfe\": 1}}"
+        );
+
+        // Missing config is simply empty
+        assert!(load_config_for_update(&config_path).is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_doctor_checks() {
+        assert!(check_home("/home/me", Some("/home/me")).is_ok());
+        assert!(check_home("/home/me", Some("/root")).is_err());
+        assert!(check_home("/home/me", None).is_err());
+
+        let expired = SyncError::Auth("invalid_grant".to_string());
+        assert!(account_fix("personal", &SyncService::Onedrive, &expired)
+            .contains("cloudsync save onedrive personal"));
+        assert!(account_fix(
+            "nas",
+            &SyncService::WebDav,
+            &SyncError::Api {
+                code: 401,
+                message: String::new()
+            }
+        )
+        .contains("cloudsync save webdav nas"));
+        assert!(account_fix(
+            "nas",
+            &SyncService::WebDav,
+            &SyncError::Network("timeout".to_string())
+        )
+        .contains("network"));
+
+        let drive_ids = [
+            ("personal", "b!1".to_string()),
+            ("work", "b!2".to_string()),
+            ("personal2", "b!1".to_string()),
+        ];
+        assert_eq!(
+            shared_drives(&drive_ids),
+            vec![("b!1".to_string(), vec!["personal", "personal2"])]
+        );
+        assert!(shared_drives(&drive_ids[..2]).is_empty());
+    }
+
+    #[test]
+    fn test_keyring_secrets() {
+        let dir = test_dir("keyring-secrets");
+        let config_path = format!("{}/cloudsync.json", dir);
+
+        let token = Token {
+            access_token: "access".to_string(),
+            refresh_token: "refresh".to_string(),
+            valid_till: 10,
+        };
+        let account = Account::new(SyncService::WebDav, token).with_attributes(HashMap::from([
+            ("password".to_string(), "secret".to_string()),
+            ("url".to_string(), "https://dav.example.com".to_string()),
+            (KEYRING_KEY.to_string(), "true".to_string()),
+        ]));
+
+        let mut blanked = account.clone();
+        let secrets = blanked.take_secrets();
+        assert!(!blanked.has_secrets());
+        assert_eq!(blanked.token.valid_till, 10);
+        assert_eq!(blanked.attributes["url"], "https://dav.example.com");
+
+        let mut restored = blanked.clone();
+        restored.restore_secrets(&secrets).unwrap();
+        assert_eq!(restored.token.refresh_token, "refresh");
+        assert_eq!(restored.attributes["password"], "secret");
+
+        // Secrets already in the keyring aren't stored again,
+        // the config never has them either way
+        KEYRING_CACHE
+            .lock()
+            .unwrap()
+            .insert("keyring-test".to_string(), secrets);
+        let mut config = Config::default();
+        config.accounts.insert("keyring-test".to_string(), account);
+        config.accounts.insert("keyring-blank".to_string(), blanked);
+        write_config(&config_path, &config).unwrap();
+
+        let written = std::fs::read_to_string(&config_path).unwrap();
+        assert!(!written.contains("\"refresh\""));
+        assert!(!written.contains("\"secret\""));
+        assert!(written.contains("dav.example.com"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_saves() {
+        let dir = test_dir("concurrent-saves");
+        let config_path = format!("{}/cloudsync.json", dir);
+        let token = Token {
+            access_token: String::new(),
+            refresh_token: String::new(),
+            valid_till: 0,
+        };
+        update_config(&config_path, |config| {
+            for index in 0..20 {
+                let account = Account::new(SyncService::WebDav, token.clone());
+                config.accounts.insert(format!("old-{}", index), account);
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        let renames = {
+            let config_path = config_path.clone();
+            std::thread::spawn(move || {
+                for index in 0..20 {
+                    update_config(&config_path, |config| {
+                        rename_account(
+                            config,
+                            &format!("old-{}", index),
+                            &format!("renamed-{}", index),
+                        )
+                    })
+                    .unwrap();
+                }
+            })
+        };
+        let saves: Vec<_> = ["personal", "work"]
+            .into_iter()
+            .map(|name| {
+                let config_path = config_path.clone();
+                std::thread::spawn(move || {
+                    for index in 0..20 {
+                        let token = Token {
+                            access_token: String::new(),
+                            refresh_token: String::new(),
+                            valid_till: 0,
+                        };
+                        let account = Account::new(SyncService::WebDav, token);
+                        update_config(&config_path, |config| {
+                            config
+                                .accounts
+                                .insert(format!("{}-{}", name, index), account);
+                            Ok(())
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for save in saves {
+            save.join().unwrap();
+        }
+        renames.join().unwrap();
+
+        // Renames running next to saves neither lose nor bring back accounts
+        let config = load_config_for_update(&config_path).unwrap();
+        assert_eq!(config.accounts.len(), 60);
+        assert!(config.accounts.contains_key("personal-19"));
+        assert!(config.accounts.contains_key("work-19"));
+        assert!(config.accounts.contains_key("renamed-19"));
+        assert!(!config.accounts.keys().any(|name| name.starts_with("old-")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let dir = test_dir("max-depth");
+        std::fs::create_dir_all(format!("{}/one/two", dir)).unwrap();
+        std::fs::write(format!("{}/root.txt", dir), "0").unwrap();
+        std::fs::write(format!("{}/one/a.txt", dir), "1").unwrap();
+        std::fs::write(format!("{}/one/two/b.txt", dir), "2").unwrap();
+
+        let mut local_files = BTreeMap::new();
+        read_dir_rec(&dir, &mut local_files, Some(1), &mut Vec::new()).unwrap();
+
+        let mut file_paths: Vec<&str> = local_files
+            .keys()
+            .map(|path| path.strip_prefix(dir.as_str()).unwrap())
+            .collect();
+        file_paths.sort();
+        assert_eq!(file_paths, vec!["/one/a.txt", "/root.txt"]);
+
+        let mut local_files = BTreeMap::new();
+        read_dir_rec(&dir, &mut local_files, Some(0), &mut Vec::new()).unwrap();
+        assert_eq!(local_files.len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_names() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let dir = test_dir("non-utf8");
+        std::fs::write(format!("{}/a.txt", dir), "a").unwrap();
+
+        // Some filesystems, like the one of macos, refuse such names
+        let bad_name = std::path::Path::new(&dir).join(OsStr::from_bytes(b"bad\xff.txt"));
+        if std::fs::write(&bad_name, "b").is_err() {
+            std::fs::remove_dir_all(&dir).unwrap();
+            return;
+        }
+        let bad_folder = std::path::Path::new(&dir).join(OsStr::from_bytes(b"sub\xfe"));
+        std::fs::create_dir_all(&bad_folder).unwrap();
+        std::fs::write(bad_folder.join("b.txt"), "b").unwrap();
+
+        let mut local_files = BTreeMap::new();
+        read_dir_rec(&dir, &mut local_files, None, &mut Vec::new()).unwrap();
+        assert_eq!(local_files.len(), 1);
+        assert!(local_files.contains_key(&format!("{}/a.txt", dir)));
+
+        // Old files with bad names don't count as changes
+        assert!(!has_local_changes(&dir, timestamp() + 60, 0, None));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unreadable_folder() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir("unreadable");
+        std::fs::create_dir_all(format!("{}/locked", dir)).unwrap();
+        std::fs::write(format!("{}/a.txt", dir), "a").unwrap();
+        std::fs::write(format!("{}/locked/b.txt", dir), "b").unwrap();
+
+        let locked = format!("{}/locked", dir);
+        let set_mode = |mode| {
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(mode)).unwrap()
+        };
+        set_mode(0o000);
+
+        // Root reads it anyway
+        if std::fs::read_dir(&locked).is_ok() {
+            set_mode(0o755);
+            std::fs::remove_dir_all(&dir).unwrap();
+            return;
+        }
+
+        let mut local_files = BTreeMap::new();
+        let mut skipped = Vec::new();
+        let walked = read_dir_rec(&dir, &mut local_files, None, &mut skipped);
+        set_mode(0o755);
+
+        assert!(walked.is_ok());
+        assert_eq!(local_files.len(), 1);
+        assert!(local_files.contains_key(&format!("{}/a.txt", dir)));
+        assert_eq!(skipped, vec![locked]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sync_or_rewind() {
+        let token = |access_token: &str| Token {
+            access_token: access_token.to_string(),
+            refresh_token: "refresh".to_string(),
+            valid_till: 0,
+        };
+        let mut account = Account::new(SyncService::Onedrive, token("old")).with_attributes(
+            HashMap::from([("delta_link".to_string(), "link1".to_string())]),
+        );
+
+        // Failing after the delta moved the link on
+        let synced = sync_or_rewind(&mut account, |account| {
+            account.token = token("new");
+            account
+                .attributes
+                .insert("delta_link".to_string(), "link2".to_string());
+            account.last_synced = 10;
+            Err(SyncError::Io("Cannot write file".to_string()))
+        });
+        assert!(synced.is_err());
+        assert_eq!(account.attributes["delta_link"], "link1");
+        assert_eq!(account.last_synced, 0);
+        assert_eq!(account.token.access_token, "new");
+
+        let synced = sync_or_rewind(&mut account, |account| {
+            account
+                .attributes
+                .insert("delta_link".to_string(), "link2".to_string());
+            Ok(SyncReport::default())
+        });
+        assert!(synced.is_ok());
+        assert_eq!(account.attributes["delta_link"], "link2");
+    }
+
+    #[test]
+    fn test_daemon_backoff() {
+        assert_eq!(backoff(60, 1), 120);
+        assert_eq!(backoff(60, 3), 480);
+        assert_eq!(backoff(60, 30), 3600);
+
+        // Intervals above the cap are never shortened
+        assert_eq!(backoff(7200, 2), 7200);
+    }
+
+    #[test]
+    fn test_format_iso_date() {
+        assert_eq!(format_iso_date(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_iso_date(1709210096), "2024-02-29T12:34:56Z");
+
+        for timestamp in [951782400, 1691328180, 4102444799] {
+            assert_eq!(parse_iso_date(&format_iso_date(timestamp)), Ok(timestamp));
+        }
     }
 }
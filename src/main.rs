@@ -2,18 +2,100 @@
 fn print_usage(program_name: &String) {
     println!("\n{} [OPTIONS]", program_name);
     println!("Cloud syncing utility\n");
-    println!("\t sync  <folder> <account_name> [--fresh|-f]
-                 syncs the folder to cloud provider, --fresh flag does a fetch from begining\n");
-    println!("\t login <gdrive|onedrive>
+    println!("\t --color=<auto|always|never>
+                 colors output on terminals unless NO_COLOR is set with auto (default), can go anywhere\n");
+    println!("\t sync  [folder] <account_name> [SYNC OPTIONS]
+                 syncs the folder to cloud provider, the account's default_folder if left out
+                 --fresh, -f               does a fetch from begining
+                 --mtime-tolerance <secs>  treats files modified within secs of the last sync as unchanged (default 0)
+                 --since <date>            only applies cloud changes made on or after date (2023-08-06 or 2023-08-06T13:23:00Z)
+                 --jobs, -j <n>            number of concurrent downloads sharing one HTTP/2 connection (default 4)
+                 --delete-jobs <n>         number of concurrent cloud deletes, batched where the provider allows (default 4)
+                 --include <glob>          only syncs paths matching the glob, can be repeated
+                 --download-include <glob> only applies cloud changes of paths matching the glob, uploads everything, can be repeated
+                 --remote-ignore <prefix>  leaves the cloud folder or file at prefix out of the sync, can be repeated
+                 --max-depth <n>           only syncs files at most n folders below the root, 0 is the root only
+                 --strict-clock            refuses to sync when the local clock is off from the server or can't be checked
+                 --state-dir <path>        keeps the cloudstate in path instead of the synced folder
+                 --tmp-dir <path>          writes downloads to path before moving them over the file (default the file's folder)
+                 --stateless               uploads files newer than the last sync without reading or writing cloudstate
+                 --state-backups <n>       keeps the cloudstate of the last n syncs next to it as .cloudstate.1 to .n (default 3)
+                 --backup                  only uploads, local deletes are mirrored to the cloud but nothing is downloaded or deleted locally
+                 --follow-shared           also syncs folders shared with you and added to your onedrive
+                 --crlf-normalize          stores text files with LF line endings and writes them with the platform's on download
+                 --text-ext <ext>          extension treated as text by --crlf-normalize and --strip-bom, can be repeated, replaces the defaults
+                 --force                   allows syncing /, folders right below it and the home folder
+                 --trash                   moves files deleted locally by the sync to .cloudsync-trash instead of removing them
+                 --json                    prints the summary as JSON, listing the path and cloud id of every uploaded file
+                 --sync-xattrs             keeps extended attributes in <name>.cloudsync-meta.json sidecars in the cloud
+                 --delta-upload            only uploads the changed blocks of large files where the server supports partial updates
+                 --export-pdf <ext>        downloads onedrive office documents with the extension as <name>.pdf, can be repeated
+                 --retry-budget <n>        total retries of failed requests during the sync (default 200)
+                 --max-retries <n>         retries of each failed request, 0 fails on the first error (default 4)
+                 --prune-empty-dirs        deletes cloud folders left empty by deleting their files
+                 --max-files <n>           stops after n uploads and downloads, the next syncs do the rest
+                 --check-space             stops before downloading more than the free disk space, on with --fresh
+                 --no-check-space          downloads without checking the free disk space
+                 --progress                prints files done out of all, throughput and ETA while transferring
+                 --profile                 prints the time spent per phase of the sync at the end
+                 --no-advance              keeps last_synced and the delta link so the next sync sees the same cloud changes
+                 --compress-state          writes the cloudstate gzip compressed, either kind is read
+                 --since-delta <link>      reads cloud changes from a onedrive delta link or token instead of the stored one
+                 --record-owner            notes the local uid, gid and user name of each upload in the cloudstate
+                 --prefer <local|remote>   side kept when a file changed locally and in the cloud, default the newer one or remote with --fresh
+                 --strip-bom               leaves a leading UTF-8 BOM out of downloaded text files\n");
+    println!("\t login <gdrive|onedrive|webdav>
                  prints the login url\n");
-    println!("\t save  <gdrive|onedrive> <account_name> <auth_code>
-                 Requests access token and saves it to config file\n");
+    println!("\t login onedrive --device <account_name> [--keyring] [--folder <path>]
+                 prints a code to sign in with on any device, waits for the sign in and saves the account\n");
+    println!("\t save  <gdrive|onedrive> <account_name> <auth_code|redirect_url> [--keyring] [--folder <path>]
+                 Requests access token and saves it to config file, takes the code or the whole url the browser ended up on
+                 --keyring keeps the token in the OS keyring instead
+                 --folder sets the folder synced when sync is only given the account\n");
+    println!("\t save  webdav <account_name> <url> <username> <password> [--keyring] [--folder <path>]
+                 checks the credentials and saves them to config file, --keyring keeps the password in the OS keyring\n");
+    println!("\t rename <old_name> <new_name>
+                 renames a saved account\n");
+    println!("\t account <set|get|unset> <account_name> [key] [value]
+                 changes the attributes of a saved account, get without a key lists them\n");
+    println!("\t export <file> [--encrypt]
+                 writes all accounts to file, --encrypt protects it with a passphrase\n");
+    println!("\t import <file> [--overwrite]
+                 adds the accounts of an export, --overwrite replaces accounts with the same name\n");
+    println!("\t restore <account_name> <remote_path> [--version <id>] [--output|-o <file>]
+                 lists the versions of a onedrive file, --version restores one in the cloud and with --output only downloads it\n");
+    println!("\t cat <account_name> <remote_path>
+                 writes the contents of a cloud file to stdout without syncing\n");
+    println!("\t ls <account_name> [remote_path]
+                 lists name, size, type and last modified of the files and folders in a cloud folder (default the root)\n");
+    println!("\t pull <account_name> <remote_path> <local_dest>
+                 downloads a cloud file to local_dest or into it if it's a folder, leaving the cloudstate as it is\n");
+    println!("\t push <account_name> <local_path> <remote_path>
+                 uploads a local file to remote_path or into it if it ends with /, without a synced folder\n");
+    println!("\t reconcile <folder> <account_name> [--dry-run|-n] [--state-dir <path>]
+                 rewrites cloudstate to match the remote, --dry-run only reports stale entries\n");
+    println!("\t daemon [folder] <account_name> [--interval <secs>] [SYNC OPTIONS]
+                 syncs the folder every interval (default 300) until SIGTERM, waits longer after failures\n");
+    println!("\t watch [folder] <account_name> [--debounce <secs>] [--interval <secs>] [SYNC OPTIONS]
+                 syncs the folder once local changes settle for debounce secs (default 2) and every interval (default 300)\n");
+    println!("\t serve <addr> [--interval <secs>]
+                 syncs the folders listed in config every interval (default 300) and serves their status on http://<addr>/status\n");
+    println!("\t doctor
+                 checks the config, signs in to every account and prints how to fix the problems found\n");
     println!("\t help
                  prints this menu \n");
 }
 
 fn main() {
-    let args: Vec<_> = std::env::args().collect();
+    let mut args: Vec<_> = std::env::args().collect();
+    match cloudsync::take_color_option(&mut args) {
+        Ok(mode) => cloudsync::set_color(mode),
+        Err(err) => {
+            eprintln!("ERROR: {err}");
+            std::process::exit(-1);
+        }
+    }
+
     let program_name = &args[0];
 
     if args.len() < 2 {
@@ -25,12 +107,28 @@ fn main() {
     let command = &args[1];
 
     let res = match command.as_str() {
-        "sync" => cloudsync::sync(&args),
+        "sync" => cloudsync::sync(&args).map(|_| ()),
         "login" => cloudsync::login(&args),
         "save" => cloudsync::save(&args),
+        "reconcile" => cloudsync::reconcile(&args),
+        "restore" => cloudsync::restore(&args),
+        "rename" => cloudsync::rename(&args),
+        "account" => cloudsync::account(&args),
+        "export" => cloudsync::export(&args),
+        "import" => cloudsync::import(&args),
+        "daemon" => cloudsync::daemon(&args),
+        "watch" => cloudsync::watch(&args),
+        "serve" => cloudsync::serve(&args),
+        "delta" => cloudsync::delta(&args),
+        "state-diff" => cloudsync::state_diff(&args),
+        "cat" => cloudsync::cat(&args),
+        "ls" => cloudsync::ls(&args),
+        "pull" => cloudsync::pull(&args),
+        "push" => cloudsync::push(&args),
+        "doctor" => cloudsync::doctor(&args),
         _ => {
             print_usage(program_name);
-            Err("Invalid arguments".to_string())
+            Err(cloudsync::SyncError::Usage("Invalid arguments".to_string()))
         }
     };
 
@@ -1,26 +1,149 @@
 use std::{
-    io::Read,
-    time::{SystemTime, UNIX_EPOCH},
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use curl::easy::{Easy, Form, List};
-use serde::{Deserialize, Serialize};
+use curl::easy::{Form, List};
+use serde::{
+    de::{DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor},
+    Deserialize, Serialize,
+};
 
-use crate::{parse_iso_date, urlencode, Account, DriveDelta, DriveDeltaType, Token};
+use crate::{
+    format_iso_date, http, parse_http_date, parse_iso_date, transfer::Download, urldecode,
+    urlencode, Account, DriveDelta, DriveDeltaType, FolderEntry, SyncError, Token,
+};
 
 const CLIENT_ID: &str = "3dceca68-abd4-46a1-9e72-9dda8a80d9c1";
 const REDIRECT_URL: &str = "https://login.microsoftonline.com/common/oauth2/nativeclient";
-const SCOPES: &str = "User.Read%20Files.ReadWrite.All%20offline_access";
+const SCOPES: &str = "User.Read Files.ReadWrite.All offline_access";
+const TOKEN_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/token";
+const DEVICE_CODE_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/devicecode";
+const DEVICE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+const COPY_POLL_ATTEMPTS: u32 = 60;
+
+// Items are addressed below the root of the drive, or below
+// the app folder for accounts with app_folder set
+const DRIVE_ROOT_URL: &str = "https://graph.microsoft.com/v1.0/me/drive/root";
+const APP_ROOT_URL: &str = "https://graph.microsoft.com/v1.0/me/drive/special/approot";
+
+// Simple uploads are limited to 4MB, larger files go through
+// an upload session in chunks of a multiple of 320KB
+pub const SIMPLE_UPLOAD_LIMIT: usize = 4 * 1024 * 1024;
+const UPLOAD_CHUNK_SIZE: usize = 32 * 320 * 1024;
+
+// Lets users register their own app, anything
+// not set falls back to the built-in client
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ClientConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<String>,
+}
+
+impl ClientConfig {
+    // Account attributes take priority over
+    // the values from the config section
+    pub fn with_attributes(&self, attributes: &HashMap<String, String>) -> ClientConfig {
+        let pick = |key: &str, fallback: &Option<String>| {
+            attributes.get(key).cloned().or_else(|| fallback.clone())
+        };
+
+        ClientConfig {
+            client_id: pick("client_id", &self.client_id),
+            client_secret: pick("client_secret", &self.client_secret),
+            redirect_url: pick("redirect_url", &self.redirect_url),
+            scopes: pick("scopes", &self.scopes),
+        }
+    }
+
+    // Refresh tokens are bound to the client which issued them
+    // so overrides are stored along side the account
+    pub fn to_attributes(&self) -> HashMap<String, String> {
+        let values = [
+            ("client_id", &self.client_id),
+            ("client_secret", &self.client_secret),
+            ("redirect_url", &self.redirect_url),
+            ("scopes", &self.scopes),
+        ];
+
+        values
+            .into_iter()
+            .filter_map(|(key, value)| value.clone().map(|value| (key.to_string(), value)))
+            .collect()
+    }
+
+    fn client_id(&self) -> &str {
+        self.client_id.as_deref().unwrap_or(CLIENT_ID)
+    }
 
-pub fn get_oauth_url() -> String {
+    fn redirect_url(&self) -> &str {
+        self.redirect_url.as_deref().unwrap_or(REDIRECT_URL)
+    }
+
+    fn scopes(&self) -> &str {
+        self.scopes.as_deref().unwrap_or(SCOPES)
+    }
+}
+
+// Rejected tokens are refreshed with the client
+// which issued them, same as refresh_token
+fn bearer(account: &Account) -> http::Bearer {
+    let client = ClientConfig::default().with_attributes(&account.attributes);
+    http::Bearer {
+        token: account.token.clone(),
+        refresh: Box::new(move |token| get_token(&client, &token.refresh_token, "refresh_token")),
+    }
+}
+
+pub fn get_oauth_url(client: &ClientConfig) -> String {
     let auth_url = "https://login.microsoftonline.com/common/oauth2/v2.0/authorize";
 
     format!(
         "{}?client_id={}&response_type=code&redirect_uri={}&scope={}",
-        auth_url, CLIENT_ID, REDIRECT_URL, SCOPES,
+        auth_url,
+        client.client_id(),
+        client.redirect_url(),
+        urlencode(client.scopes()),
     )
 }
 
+// Accepts either the bare code or the whole url the browser was
+// redirected to, which carries an error instead when consent failed
+// @Returns the auth code
+pub fn parse_redirect(input: &str) -> Result<String, SyncError> {
+    let input = input.trim();
+    let Some((_, query)) = input.split_once(['?', '#']) else {
+        return Ok(input.to_string());
+    };
+
+    let params: HashMap<&str, String> = query
+        .split(['&', '#'])
+        .filter_map(|param| param.split_once('='))
+        .map(|(key, value)| (key, urldecode(value)))
+        .collect();
+
+    if let Some(error) = params.get("error") {
+        return Err(SyncError::Auth(format!(
+            "Login failed: {}: {}",
+            error,
+            params
+                .get("error_description")
+                .map_or("no description", |description| description.as_str())
+        )));
+    }
+
+    params
+        .get("code")
+        .cloned()
+        .ok_or(SyncError::Usage("Redirect url has no code".to_string()))
+}
+
 #[allow(non_snake_case)]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct FileProperties {
@@ -33,9 +156,29 @@ struct FolderProperties {
     childCount: Option<u32>,
 }
 
+#[allow(non_snake_case)]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct ParentReference {
     path: Option<String>,
+    driveId: Option<String>,
+}
+
+// Item of another drive mounted into this one,
+// e.g. a folder someone shared
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RemoteItem {
+    id: String,
+    parentReference: Option<ParentReference>,
+    folder: Option<FolderProperties>,
+}
+
+// Items like OneNote notebooks which look like
+// files but have no content to download
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Package {
+    #[serde(rename = "type")]
+    package_type: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -43,6 +186,23 @@ struct Deleted {
     state: String,
 }
 
+// Timestamps of the file on the client it came
+// from, as opposed to when onedrive got it
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct FileSystemInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    createdDateTime: Option<String>,
+}
+
+impl FileSystemInfo {
+    fn created(created: u64) -> Self {
+        FileSystemInfo {
+            createdDateTime: Some(format_iso_date(created)),
+        }
+    }
+}
+
 #[allow(non_snake_case)]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct OneDriveItem {
@@ -54,286 +214,2214 @@ struct OneDriveItem {
     parentReference: ParentReference,
 
     lastModifiedDateTime: Option<String>,
+    cTag: Option<String>,
+    size: Option<u64>,
+    fileSystemInfo: Option<FileSystemInfo>,
     file: Option<FileProperties>,
     folder: Option<FolderProperties>,
     deleted: Option<Deleted>,
+
+    // Only present on the drive root
+    root: Option<serde_json::Value>,
+    remoteItem: Option<RemoteItem>,
+    package: Option<Package>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct OneDriveListItems {
-    #[serde(rename = "@odata.nextLink")]
-    next_link: Option<String>,
+impl OneDriveItem {
+    // Delta includes the root itself, without a root facet it is
+    // told apart by its name and the missing parent path. Business
+    // drives leave out the path on every item so it alone isn't enough
+    fn is_root(&self) -> bool {
+        self.root.is_some()
+            || (self.parentReference.path.is_none()
+                && self.deleted.is_none()
+                && self.name.as_deref() == Some("root"))
+    }
+}
 
-    #[serde(rename = "@odata.deltaLink")]
+// Links at the end of a delta page
+#[derive(Default)]
+struct DeltaLinks {
+    next_link: Option<String>,
     delta_link: Option<String>,
-    value: Vec<OneDriveItem>,
+
+    // Pages of an empty drive still have an empty list
+    has_items: bool,
 }
-fn get_delta(account: &mut Account, api_url: &str, items: &mut Vec<OneDriveItem>) {
-    let mut headers = List::new();
-    headers
-        .append(format!("Authorization:Bearer {}", account.token.access_token).as_str())
-        .unwrap();
 
-    let mut handle = Easy::new();
-    let mut response_body = Vec::new();
+// Where the delta continues after a page
+#[derive(Debug, PartialEq)]
+enum PageEnd {
+    Next(String),
+    Last(String),
+}
 
-    handle.url(api_url).unwrap();
-    handle.http_headers(headers).unwrap();
-    {
-        let mut transfer = handle.transfer();
-        transfer
-            .write_function(|data| {
-                response_body.extend_from_slice(data);
-                Ok(data.len())
-            })
-            .unwrap();
-        transfer.perform().unwrap();
+impl DeltaLinks {
+    // Anything else came from somewhere other than graph, taking
+    // it for an empty drive would upload every local file
+    fn end(self) -> Result<PageEnd, SyncError> {
+        match (self.has_items, self.next_link, self.delta_link) {
+            (true, Some(next_link), _) => Ok(PageEnd::Next(next_link)),
+            (true, None, Some(delta_link)) => Ok(PageEnd::Last(delta_link)),
+            (false, _, _) => Err(SyncError::Parse(
+                "Delta page has no list of items".to_string(),
+            )),
+            (true, None, None) => Err(SyncError::Parse(
+                "Delta page has neither a next nor a delta link".to_string(),
+            )),
+        }
+    }
+}
+
+// Delta pages are parsed as they are read, every item is
+// handed to on_item so only one is in memory at a time
+struct DeltaPage<'a> {
+    on_item: &'a mut dyn FnMut(OneDriveItem),
+}
+
+impl<'de> DeserializeSeed<'de> for DeltaPage<'_> {
+    type Value = DeltaLinks;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<DeltaLinks, D::Error> {
+        deserializer.deserialize_map(self)
     }
+}
 
-    let drive_items = serde_json::from_slice::<OneDriveListItems>(&response_body).unwrap();
-    items.extend(drive_items.value);
+impl<'de> Visitor<'de> for DeltaPage<'_> {
+    type Value = DeltaLinks;
 
-    // Last page conatins deltaLink for next time
-    // sync
-    if let Some(delta_link) = drive_items.delta_link {
-        let delta_link_key = "delta_link".to_string();
-        account.attributes.insert(delta_link_key, delta_link);
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a delta page")
     }
 
-    if let Some(next_link) = drive_items.next_link {
-        get_delta(account, next_link.as_str(), items);
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<DeltaLinks, A::Error> {
+        let mut links = DeltaLinks::default();
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "@odata.nextLink" => links.next_link = map.next_value()?,
+                "@odata.deltaLink" => links.delta_link = map.next_value()?,
+                "value" => {
+                    map.next_value_seed(DeltaItems {
+                        on_item: &mut *self.on_item,
+                    })?;
+                    links.has_items = true;
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(links)
     }
 }
 
-pub fn download_file(account: &Account, item_path: &str) -> Result<Vec<u8>, String> {
-    let mut headers = List::new();
-    headers
-        .append(format!("Authorization:Bearer {}", account.token.access_token).as_str())
-        .unwrap();
+struct DeltaItems<'a> {
+    on_item: &'a mut dyn FnMut(OneDriveItem),
+}
 
-    let item_path_escaped = urlencode(item_path);
-    let api_url = format!(
-        "https://graph.microsoft.com/v1.0/me/drive/root:/{}:/content",
-        item_path_escaped
-    );
-    let mut handle = Easy::new();
-    let mut response_body = Vec::new();
+impl<'de> DeserializeSeed<'de> for DeltaItems<'_> {
+    type Value = ();
 
-    handle.url(&api_url).unwrap();
-    handle.follow_location(true).unwrap();
-    handle.http_headers(headers).unwrap();
-    handle.fail_on_error(true).unwrap();
-    {
-        let mut transfer = handle.transfer();
-        transfer
-            .write_function(|data| {
-                response_body.extend_from_slice(data);
-                Ok(data.len())
-            })
-            .unwrap();
-        transfer
-            .perform()
-            .map_err(|err| format!("Cannot perform request: {}", err))?;
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for DeltaItems<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a list of drive items")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(item) = seq.next_element::<OneDriveItem>()? {
+            (self.on_item)(item);
+        }
+        Ok(())
     }
+}
 
-    Ok(response_body)
+fn parse_delta_page(
+    reader: impl std::io::Read,
+    on_item: &mut dyn FnMut(OneDriveItem),
+) -> Result<DeltaLinks, serde_json::Error> {
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let links = DeltaPage { on_item }.deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(links)
 }
 
-pub fn upload_new_file(
+// Follows next links until the last page, pages are spooled
+// to disk so a huge page doesn't have to fit in memory
+// @Returns delta link for the next sync
+fn get_delta(
     account: &Account,
-    item_path: &str,
-    mut contents: &[u8],
-) -> Result<String, String> {
-    let mut headers = List::new();
-    headers
-        .append(format!("Authorization:Bearer {}", account.token.access_token).as_str())
-        .unwrap();
-    headers.append("Content-Type: text/plain").unwrap();
+    api_url: &str,
+    on_item: &mut dyn FnMut(OneDriveItem),
+) -> Result<String, SyncError> {
+    let mut spool = http::Spool::new()?;
+    let mut page_url = api_url.to_string();
 
-    let item_path_escaped = urlencode(item_path);
-    let api_url = format!(
-        "https://graph.microsoft.com/v1.0/me/drive/root:{}:/content",
-        item_path_escaped
-    );
-    let mut handle = Easy::new();
-    let mut response_body = Vec::new();
+    loop {
+        let mut handle = http::handle();
 
-    handle.url(&api_url).unwrap();
-    handle.http_headers(headers).unwrap();
-    handle.put(true).unwrap();
-    handle.fail_on_error(true).unwrap();
-    {
-        let mut transfer = handle.transfer();
-        transfer
-            .read_function(|into| Ok(contents.read(into).unwrap()))
-            .unwrap();
+        handle.url(&page_url).unwrap();
+        handle.authorize(bearer(account), &[]);
 
-        transfer
-            .write_function(|data| {
-                response_body.extend_from_slice(data);
-                Ok(data.len())
-            })
-            .unwrap();
+        // Delta pages of large drives compress well
+        handle.accept_encoding("").unwrap();
+
+        http::send_spooled(&mut handle, &mut spool)?.success()?;
+        let links = parse_delta_page(spool.reader()?, on_item).map_err(http::parse_error)?;
 
-        transfer
-            .perform()
-            .map_err(|err| format!("Cannot perform request: {}", err))?;
+        // Last page conatins deltaLink for next time
+        // sync
+        match links.end()? {
+            PageEnd::Next(next_link) => page_url = next_link,
+            PageEnd::Last(delta_link) => return Ok(delta_link),
+        }
     }
+}
 
-    let drive_item: OneDriveItem = serde_json::from_slice(&response_body)
-        .map_err(|err| format!("Cannot parse response: {}", err))?;
+// Replaying changes from a known point, a bare token continues the
+// delta of the synced root. Links carry the bearer token with them
+// so only graph links are taken
+pub fn replay_delta_link(account: &Account, link_or_token: &str) -> Result<String, SyncError> {
+    if link_or_token.contains("://") {
+        if !link_or_token.starts_with("https://graph.microsoft.com/") {
+            return Err(SyncError::Usage(format!(
+                "{} is not a graph delta link",
+                link_or_token
+            )));
+        }
+        return Ok(link_or_token.to_string());
+    }
 
-    Ok(drive_item.id)
+    let token = link_or_token
+        .strip_prefix("token=")
+        .unwrap_or(link_or_token);
+    if token.is_empty() || token.contains(['&', '?', '#', ' ']) {
+        return Err(SyncError::Usage(format!(
+            "{} is not a delta token",
+            link_or_token
+        )));
+    }
+    Ok(format!("{}/delta?token={}", root_url(account), token))
 }
 
-pub fn delete_file(account: &Account, cloud_id: &str) -> Result<(), String> {
-    let mut headers = List::new();
-    headers
-        .append(format!("Authorization:Bearer {}", account.token.access_token).as_str())
-        .unwrap();
-    headers.append("Content-Type: text/plain").unwrap();
+// Delta links expire after a while, graph then answers 410 Gone with
+// one of the resync codes. Listing from the root delta again gives the
+// current state of the drive without its history, so changes are
+// compared against the cloudstate like on any other sync but deletes
+// made while the link was expired aren't reported
+fn needs_resync(err: &SyncError) -> bool {
+    match err {
+        SyncError::Api { code: 410, .. } => true,
+        SyncError::Api { message, .. } => message.starts_with("resync"),
+        _ => false,
+    }
+}
 
-    let api_url = format!(
-        "https://graph.microsoft.com/v1.0/me/drive/items/{}",
-        cloud_id
-    );
-    let mut handle = Easy::new();
+// Parent references look like /drive/root: for the root
+// and /drive/root:/sub for folders below it
+const ROOT_REFERENCE: &str = "/drive/root:";
+
+// Item paths always start with a '/', e.g. /a.txt or /sub/a.txt
+// @Returns url addressing the item by path, root:/sub/a.txt:/<action>
+fn item_url(root_url: &str, item_path: &str, action: &str) -> String {
+    format!(
+        "{}:/{}:/{}",
+        root_url,
+        urlencode(item_path.trim_start_matches('/')),
+        action
+    )
+}
+
+// Attribute syncing the app folder instead of the whole drive
+pub const APP_FOLDER_KEY: &str = "app_folder";
+
+// Path of the app folder in the drive, e.g. /Apps/cloudsync
+const APP_FOLDER_PATH_KEY: &str = "app_folder_path";
+
+fn uses_app_folder(account: &Account) -> bool {
+    account
+        .attributes
+        .get(APP_FOLDER_KEY)
+        .is_some_and(|value| value == "true")
+}
+
+fn root_url(account: &Account) -> &'static str {
+    if uses_app_folder(account) {
+        APP_ROOT_URL
+    } else {
+        DRIVE_ROOT_URL
+    }
+}
+
+// Switching between the drive and the app folder changes
+// every path, what was stored belongs to the old root
+pub fn reset_root(account: &mut Account) {
+    for key in ["delta_link", APP_FOLDER_PATH_KEY, SHARED_MOUNTS_KEY] {
+        account.attributes.remove(key);
+    }
+}
+
+// @Returns path of the app folder in the drive,
+// none when the whole drive is synced
+fn app_folder_path(account: &Account) -> Result<Option<String>, SyncError> {
+    if !uses_app_folder(account) {
+        return Ok(None);
+    }
+    if let Some(app_path) = account.attributes.get(APP_FOLDER_PATH_KEY) {
+        return Ok(Some(app_path.clone()));
+    }
+
+    let api_url = format!("{}?$select=id,name,parentReference", APP_ROOT_URL);
+    let mut handle = http::handle();
 
     handle.url(&api_url).unwrap();
-    handle.http_headers(headers).unwrap();
-    handle.custom_request("DELETE").unwrap();
-    handle.fail_on_error(true).unwrap();
+    handle.authorize(bearer(account), &[]);
 
-    handle
-        .perform()
-        .map_err(|err| format!("Cannot perform request: {}", err))
+    let response = http::send(&mut handle, None)?.success()?;
+    let item: OneDriveItem = serde_json::from_slice(&response.body).map_err(http::parse_error)?;
+    let name = item
+        .name
+        .ok_or(SyncError::Parse("App folder has no name".to_string()))?;
+
+    Ok(Some(item_path(item.parentReference.path.as_deref(), &name)))
 }
 
-pub fn get_drive_delta(account: &mut Account) -> Result<Vec<DriveDelta>, String> {
-    let mut files = Vec::new();
-    let root_delta_link = "https://graph.microsoft.com/v1.0/me/drive/root/delta".to_string();
+// Items of the app folder may be reported with their path in
+// the drive, they are synced relative to the app folder
+fn app_relative(file_path: String, app_path: Option<&str>) -> String {
+    let rest = app_path
+        .and_then(|app_path| file_path.strip_prefix(app_path))
+        .filter(|rest| rest.starts_with('/'));
 
-    let delta_link_key = "delta_link".to_string();
-    let delta_link = match account.attributes.get(&delta_link_key) {
-        Some(val) => val.clone(),
-        None => root_delta_link.clone(),
-    };
+    match rest {
+        Some(rest) => rest.to_string(),
+        None => file_path,
+    }
+}
 
-    get_delta(account, &delta_link, &mut files);
+// Items of shared folders are kept as <drive id>/<item id>,
+// items of the drive itself by their id alone
+fn item_id_path(cloud_id: &str) -> String {
+    match cloud_id.split_once('/') {
+        Some((drive_id, item_id)) => format!("/drives/{}/items/{}", drive_id, item_id),
+        None => format!("/me/drive/items/{}", cloud_id),
+    }
+}
 
-    let mut cloud_files = Vec::new();
-    for file in files {
-        // Skipping folders
-        if file.folder.is_some() {
-            continue;
-        }
+// Attribute holding the shared folders found in the drive
+pub const SHARED_MOUNTS_KEY: &str = "shared_mounts";
 
-        if file.name.is_none() {
-            continue;
-        }
+// Account attributes users may set, see ClientConfig
+pub const ACCOUNT_ATTRIBUTES: &[&str] = &[
+    "client_id",
+    "client_secret",
+    "redirect_url",
+    "scopes",
+    APP_FOLDER_KEY,
+    crate::KEYRING_KEY,
+    crate::DEFAULT_FOLDER_KEY,
+];
 
-        let file_name = file.name.unwrap();
-        if file_name.is_empty() {
-            continue;
-        }
+// Account attributes written by sync itself
+pub const MANAGED_ATTRIBUTES: &[&str] = &[
+    "delta_link",
+    SHARED_MOUNTS_KEY,
+    APP_FOLDER_PATH_KEY,
+    crate::SYNC_ERRORS_KEY,
+];
 
-        let file_path = if let Some(mut parent) = file.parentReference.path {
-            let folder = parent.split_off(12);
-            format!("{}/{}", folder, file_name)
-        } else {
-            format!("/{}", file_name)
-        };
+// Shared folder mounted into the drive
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SharedMount {
+    // Id and path of the mount point in this drive
+    id: String,
+    path: String,
 
-        let last_modified = parse_iso_date(&file.lastModifiedDateTime.unwrap());
-
-        cloud_files.push(DriveDelta {
-            cloud_id: file.id,
-            file_path,
-            last_modified,
-            delta_type: if file.deleted.is_some() {
-                DriveDeltaType::Deleted
-            } else {
-                DriveDeltaType::CreatedOrModifiled
-            },
-        });
+    drive_id: String,
+    item_id: String,
+
+    // Path of the folder in the owner's drive,
+    // items below it are reported relative to it
+    remote_path: Option<String>,
+    delta_link: Option<String>,
+}
+
+fn shared_mounts(account: &Account) -> Vec<SharedMount> {
+    account
+        .attributes
+        .get(SHARED_MOUNTS_KEY)
+        .and_then(|mounts| serde_json::from_str(mounts).ok())
+        .unwrap_or_default()
+}
+
+// @Returns mount the path is below and the rest of the path
+fn find_mount<'m, 'p>(
+    mounts: &'m [SharedMount],
+    item_path: &'p str,
+) -> Option<(&'m SharedMount, &'p str)> {
+    let item_path = item_path.trim_start_matches('/');
+    mounts.iter().find_map(|mount| {
+        let rest = item_path
+            .strip_prefix(mount.path.trim_start_matches('/'))?
+            .strip_prefix('/')?;
+        Some((mount, rest))
+    })
+}
+
+// Same as item_url, paths below a shared folder are
+// addressed relative to the folder in its own drive
+fn drive_item_url(account: &Account, item_path: &str, action: &str) -> String {
+    match find_mount(&shared_mounts(account), item_path) {
+        Some((mount, rest)) => format!(
+            "https://graph.microsoft.com/v1.0/drives/{}/items/{}:/{}:/{}",
+            mount.drive_id,
+            mount.item_id,
+            urlencode(rest),
+            action
+        ),
+        None => item_url(root_url(account), item_path, action),
     }
+}
 
-    Ok(cloud_files)
+// Deleted items have no path in their parent reference, shared
+// items are referenced as /drives/<drive id>/root: instead
+fn item_path(parent_path: Option<&str>, name: &str) -> String {
+    let folder = parent_path
+        .and_then(|parent| parent.split_once("root:"))
+        .map_or("", |(_, folder)| folder)
+        .trim_end_matches('/');
+
+    format!("{}/{}", folder, name)
 }
 
-#[derive(Serialize, Deserialize)]
-struct MicrosoftGraphToken {
-    access_token: String,
-    refresh_token: String,
-    expires_in: u64,
+// Inverse of item_path, paths of the app folder
+// are referenced by their path in the drive
+// @Returns parent reference path and name
+fn parent_reference(app_path: Option<&str>, item_path: &str) -> (String, String) {
+    let item_path = format!("/{}", item_path.trim_start_matches('/'));
+    let (folder, name) = item_path.rsplit_once('/').unwrap();
+
+    (
+        format!("{}{}{}", ROOT_REFERENCE, app_path.unwrap_or(""), folder),
+        name.to_string(),
+    )
 }
 
-pub fn get_token(code: &str, grant_type: &str) -> Result<Token, String> {
-    let mut form = Form::new();
-    form.part("client_id")
-        .contents(CLIENT_ID.as_bytes())
-        .add()
-        .unwrap();
-    form.part("redirect_uri")
-        .contents(REDIRECT_URL.as_bytes())
-        .add()
-        .unwrap();
-    form.part("grant_type")
-        .contents(grant_type.as_bytes())
-        .add()
-        .unwrap();
+// Format converts the contents on the server, e.g. pdf
+fn content_url(account: &Account, item_path: &str, format: Option<&str>) -> String {
+    let api_url = drive_item_url(account, item_path, "content");
+    match format {
+        Some(format) => format!("{}?format={}", api_url, format),
+        None => api_url,
+    }
+}
 
-    match grant_type {
-        "authorization_code" => {
-            form.part("code").contents(code.as_bytes()).add().unwrap();
-        }
-        "refresh_token" => {
-            form.part("refresh_token")
-                .contents(code.as_bytes())
-                .add()
-                .unwrap();
-        }
-        _ => return Err("Invalid grant_type".to_string()),
+fn download_handle(account: &Account, item_path: &str, format: Option<&str>) -> http::Handle {
+    let api_url = content_url(account, item_path, format);
+    let mut handle = http::handle();
+
+    handle.url(&api_url).unwrap();
+    handle.follow_location(true).unwrap();
+    handle.authorize(bearer(account), &[]);
+    handle.accept_encoding("").unwrap();
+    handle
+}
+
+pub fn download_file(
+    account: &Account,
+    item_path: &str,
+    format: Option<&str>,
+) -> Result<Vec<u8>, SyncError> {
+    let mut handle = download_handle(account, item_path, format);
+    let response = http::send(&mut handle, None)?.success()?;
+    Ok(response.body)
+}
+
+// Same as download_file with the contents left in the spool
+pub fn download_file_spooled(
+    account: &Account,
+    item_path: &str,
+    spool: &mut http::Spool,
+) -> Result<(), SyncError> {
+    let mut handle = download_handle(account, item_path, None);
+    http::send_spooled(&mut handle, spool)?.success()?;
+    Ok(())
+}
+
+// Same request as download_file, to be run on the transfer pool,
+// with a ctag it is answered with 304 if the contents still match
+pub(crate) fn download_request(
+    account: &Account,
+    item_path: &str,
+    ctag: Option<&str>,
+    format: Option<&str>,
+) -> Download {
+    Download {
+        url: content_url(account, item_path, format),
+        headers: ctag
+            .map(|ctag| format!("If-None-Match: {}", ctag))
+            .into_iter()
+            .collect(),
+        bearer: Some(bearer(account)),
+    }
+}
+
+// Simple uploads only take the contents, the
+// creation time is set with a second request
+pub fn upload_new_file(
+    account: &Account,
+    item_path: &str,
+    contents: &[u8],
+    created: Option<u64>,
+) -> Result<String, SyncError> {
+    let api_url = drive_item_url(account, item_path, "content");
+    let mut handle = http::handle();
+
+    handle.url(&api_url).unwrap();
+    handle.authorize(
+        bearer(account),
+        &["Content-Type: text/plain", http::NO_EXPECT],
+    );
+    handle.put(true).unwrap();
+    handle.in_filesize(contents.len() as u64).unwrap();
+
+    let response = http::send(&mut handle, Some(contents))?.success()?;
+    let drive_item: OneDriveItem = response.json()?;
+
+    if let Some(created) = created {
+        set_created(account, &drive_item.id, created)?;
+    }
+
+    Ok(drive_item.id)
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Debug)]
+struct UpdateItemRequest {
+    fileSystemInfo: FileSystemInfo,
+}
+
+// Sets the creation time shown for the item
+pub fn set_created(account: &Account, cloud_id: &str, created: u64) -> Result<(), SyncError> {
+    let request = UpdateItemRequest {
+        fileSystemInfo: FileSystemInfo::created(created),
     };
+    let request_body = serde_json::to_vec(&request).unwrap();
 
-    let api_url = "https://login.microsoftonline.com/common/oauth2/v2.0/token";
-    let mut handle = Easy::new();
-    let mut response_body = Vec::new();
+    let api_url = format!("https://graph.microsoft.com/v1.0{}", item_id_path(cloud_id));
+    let mut handle = http::handle();
 
-    handle.url(api_url).unwrap();
-    handle.httppost(form).unwrap();
-    handle.fail_on_error(true).unwrap();
-    {
-        let mut transfer = handle.transfer();
-        transfer
-            .write_function(|data| {
-                response_body.extend_from_slice(data);
-                Ok(data.len())
-            })
-            .unwrap();
+    handle.url(&api_url).unwrap();
+    handle.authorize(bearer(account), &["Content-Type: application/json"]);
+    handle.custom_request("PATCH").unwrap();
+    handle.post_fields_copy(&request_body).unwrap();
+
+    http::send(&mut handle, None)?.success()?;
+    Ok(())
+}
+
+// Upload session of an interrupted large upload, kept
+// in the cloudstate so the next sync can pick it up
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UploadSession {
+    upload_url: String,
+    next_offset: u64,
+    expires_at: u64,
+
+    // Session is only resumed for the same contents
+    content_hash: u64,
+}
 
-        transfer
-            .perform()
-            .map_err(|err| format!("Cannot perform request: {}", err))?;
+impl UploadSession {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= crate::timestamp()
     }
+}
 
-    let microsoft_token: MicrosoftGraphToken =
-        serde_json::from_slice(&response_body).map_err(|err| {
-            format!(
-                "Cannot parse response please relogin : {} :\n{}",
-                grant_type, err
-            )
-        })?;
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug)]
+struct UploadSessionStatus {
+    uploadUrl: Option<String>,
+    expirationDateTime: Option<String>,
+    nextExpectedRanges: Option<Vec<String>>,
+}
 
-    let start = SystemTime::now();
-    let since_the_epoch = start
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_secs();
+// Ranges look like "12345-" or "12345-67890",
+// the first one is where the upload continues from
+fn next_offset(ranges: &[String]) -> Option<u64> {
+    let range = ranges.first()?;
+    let (start, _) = range.split_once('-').unwrap_or((range, ""));
+    start.parse().ok()
+}
 
-    Ok(Token {
-        access_token: microsoft_token.access_token,
-        refresh_token: microsoft_token.refresh_token,
-        valid_till: since_the_epoch + microsoft_token.expires_in,
-    })
+#[allow(non_snake_case)]
+#[derive(Serialize, Debug)]
+struct UploadSessionItem {
+    #[serde(rename = "@microsoft.graph.conflictBehavior")]
+    conflict_behavior: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fileSystemInfo: Option<FileSystemInfo>,
+}
+
+#[derive(Serialize, Debug)]
+struct UploadSessionRequest {
+    item: UploadSessionItem,
+}
+
+fn create_upload_session(
+    account: &Account,
+    item_path: &str,
+    content_hash: u64,
+    created: Option<u64>,
+) -> Result<UploadSession, SyncError> {
+    let request = UploadSessionRequest {
+        item: UploadSessionItem {
+            conflict_behavior: "replace".to_string(),
+            fileSystemInfo: created.map(FileSystemInfo::created),
+        },
+    };
+    let request_body = serde_json::to_vec(&request).unwrap();
+
+    let api_url = drive_item_url(account, item_path, "createUploadSession");
+    let mut handle = http::handle();
+
+    handle.url(&api_url).unwrap();
+    handle.authorize(bearer(account), &["Content-Type: application/json"]);
+    handle.post(true).unwrap();
+    handle.post_fields_copy(&request_body).unwrap();
+
+    let response = http::send(&mut handle, None)?.success()?;
+    let status: UploadSessionStatus = response.json()?;
+
+    let upload_url = status.uploadUrl.ok_or(SyncError::Parse(
+        "Upload session has no upload url".to_string(),
+    ))?;
+    let expires_at = match status.expirationDateTime {
+        Some(expiration) => parse_iso_date(&expiration)?,
+        None => crate::timestamp(),
+    };
+
+    Ok(UploadSession {
+        upload_url,
+        next_offset: 0,
+        expires_at,
+        content_hash,
+    })
+}
+
+// Asks the server how much of the session it already has
+// @Returns none when the session is gone on the server
+fn query_upload_session(session: &UploadSession) -> Result<Option<UploadSession>, SyncError> {
+    // Upload url is pre-authenticated
+    let mut handle = http::handle();
+    handle.url(&session.upload_url).unwrap();
+
+    let response = http::send(&mut handle, None)?;
+    if response.code == 404 {
+        return Ok(None);
+    }
+
+    let response = response.success()?;
+    let status: UploadSessionStatus = response.json()?;
+
+    let Some(next_offset) = status.nextExpectedRanges.as_deref().and_then(next_offset) else {
+        return Ok(None);
+    };
+    let expires_at = match status.expirationDateTime {
+        Some(expiration) => parse_iso_date(&expiration)?,
+        None => session.expires_at,
+    };
+
+    Ok(Some(UploadSession {
+        next_offset,
+        expires_at,
+        ..session.clone()
+    }))
+}
+
+// Uploads in chunks through an upload session, resuming the
+// stored session when it is still valid for these contents
+// on_progress is called after every accepted chunk
+// @Returns cloud id of the uploaded item
+pub fn upload_large_file(
+    account: &Account,
+    item_path: &str,
+    contents: &[u8],
+    created: Option<u64>,
+    stored_session: Option<UploadSession>,
+    mut on_progress: impl FnMut(&UploadSession),
+) -> Result<String, SyncError> {
+    let content_hash = crate::fnv1a(contents);
+    let total_size = contents.len() as u64;
+
+    let resumed = match stored_session {
+        Some(session) if !session.is_expired() && session.content_hash == content_hash => {
+            query_upload_session(&session).unwrap_or_else(|err| {
+                println!("ERROR: Querying upload session: {}", err);
+                None
+            })
+        }
+        _ => None,
+    };
+
+    let mut session = match resumed {
+        Some(session) => {
+            println!(
+                "INFO: Resuming upload of {} from byte {}",
+                item_path, session.next_offset
+            );
+            session
+        }
+        None => create_upload_session(account, item_path, content_hash, created)?,
+    };
+    on_progress(&session);
+
+    while session.next_offset < total_size {
+        let start = session.next_offset as usize;
+        let end = (start + UPLOAD_CHUNK_SIZE).min(contents.len());
+        let chunk = &contents[start..end];
+
+        let mut headers = List::new();
+        headers
+            .append(format!("Content-Range: bytes {}-{}/{}", start, end - 1, total_size).as_str())
+            .unwrap();
+        headers.append(http::NO_EXPECT).unwrap();
+
+        let mut handle = http::handle();
+        handle.url(&session.upload_url).unwrap();
+        handle.http_headers(headers).unwrap();
+        handle.put(true).unwrap();
+        handle.in_filesize(chunk.len() as u64).unwrap();
+
+        let response = http::send(&mut handle, Some(chunk))?.success()?;
+
+        // Last chunk returns the created item
+        if response.code == 200 || response.code == 201 {
+            let drive_item: OneDriveItem = response.json()?;
+            return Ok(drive_item.id);
+        }
+
+        let status: UploadSessionStatus = response.json()?;
+        session.next_offset = status
+            .nextExpectedRanges
+            .as_deref()
+            .and_then(next_offset)
+            .unwrap_or(end as u64);
+        if let Some(expiration) = status.expirationDateTime {
+            session.expires_at = parse_iso_date(&expiration)?;
+        }
+        on_progress(&session);
+    }
+
+    Err(SyncError::Parse(
+        "Upload session ended without creating the item".to_string(),
+    ))
+}
+
+pub fn delete_file(account: &Account, cloud_id: &str) -> Result<(), SyncError> {
+    let api_url = format!("https://graph.microsoft.com/v1.0{}", item_id_path(cloud_id));
+    let mut handle = http::handle();
+
+    handle.url(&api_url).unwrap();
+    handle.authorize(bearer(account), &["Content-Type: text/plain"]);
+    handle.custom_request("DELETE").unwrap();
+
+    http::send(&mut handle, None)?.success()?;
+    Ok(())
+}
+
+// @Returns cloud id of the item at the path
+pub fn get_item_id(account: &Account, item_path: &str) -> Result<String, SyncError> {
+    // Addressing the item itself, not one of its actions
+    let api_url = format!(
+        "{}?$select=id",
+        drive_item_url(account, item_path, "").trim_end_matches(":/")
+    );
+    let mut handle = http::handle();
+
+    handle.url(&api_url).unwrap();
+    handle.authorize(bearer(account), &[]);
+
+    let response = http::send(&mut handle, None)?.success()?;
+    let item: ItemId = serde_json::from_slice(&response.body).map_err(http::parse_error)?;
+
+    match find_mount(&shared_mounts(account), item_path) {
+        Some((mount, _)) => Ok(format!("{}/{}", mount.drive_id, item.id)),
+        None => Ok(item.id),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ItemId {
+    id: String,
+}
+
+#[allow(non_snake_case)]
+#[derive(Deserialize, Debug)]
+struct FolderItem {
+    id: String,
+    eTag: Option<String>,
+    folder: Option<FolderProperties>,
+}
+
+// The delete only goes through if nothing was added to
+// the folder since it was found empty, its eTag would differ
+// @Returns whether the folder was empty and deleted
+pub fn delete_empty_folder(account: &Account, folder_path: &str) -> Result<bool, SyncError> {
+    // Removing a mount point would remove the share from the drive
+    let folder = folder_path.trim_start_matches('/');
+    let mounts = shared_mounts(account);
+    if folder.is_empty()
+        || mounts
+            .iter()
+            .any(|mount| mount.path.trim_matches('/') == folder)
+    {
+        return Ok(false);
+    }
+
+    let api_url = format!(
+        "{}?$select=id,eTag,folder",
+        drive_item_url(account, folder_path, "").trim_end_matches(":/")
+    );
+    let mut handle = http::handle();
+
+    handle.url(&api_url).unwrap();
+    handle.authorize(bearer(account), &[]);
+
+    let response = match http::send(&mut handle, None)?.success() {
+        Ok(response) => response,
+        Err(SyncError::Api { code: 404, .. }) => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    let item: FolderItem = serde_json::from_slice(&response.body).map_err(http::parse_error)?;
+
+    let child_count = item.folder.and_then(|folder| folder.childCount);
+    if child_count != Some(0) {
+        return Ok(false);
+    }
+
+    let cloud_id = match find_mount(&mounts, folder_path) {
+        Some((mount, _)) => format!("{}/{}", mount.drive_id, item.id),
+        None => item.id,
+    };
+    let api_url = format!(
+        "https://graph.microsoft.com/v1.0{}",
+        item_id_path(&cloud_id)
+    );
+    let if_match = item.eTag.map(|etag| format!("If-Match: {}", etag));
+    let mut handle = http::handle();
+
+    handle.url(&api_url).unwrap();
+    handle.authorize(
+        bearer(account),
+        &if_match.iter().map(String::as_str).collect::<Vec<_>>(),
+    );
+    handle.custom_request("DELETE").unwrap();
+
+    match http::send(&mut handle, None)?.success() {
+        Ok(_) => Ok(true),
+        Err(SyncError::Api {
+            code: 404 | 412, ..
+        }) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+#[allow(non_snake_case)]
+#[derive(Deserialize, Debug)]
+struct DriveItemVersion {
+    id: String,
+    lastModifiedDateTime: String,
+    size: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DriveItemVersions {
+    value: Vec<DriveItemVersion>,
+}
+
+// Earlier contents of a file kept by onedrive
+pub struct FileVersion {
+    pub id: String,
+    pub last_modified: u64,
+    pub size: u64,
+}
+
+fn parse_versions(body: &[u8]) -> Result<Vec<FileVersion>, SyncError> {
+    let versions: DriveItemVersions = serde_json::from_slice(body).map_err(http::parse_error)?;
+
+    versions
+        .value
+        .into_iter()
+        .map(|version| {
+            Ok(FileVersion {
+                last_modified: parse_iso_date(&version.lastModifiedDateTime)?,
+                size: version.size.unwrap_or(0),
+                id: version.id,
+            })
+        })
+        .collect()
+}
+
+// @Returns versions of the file, newest
+// first which is the current contents
+pub fn list_versions(account: &Account, cloud_id: &str) -> Result<Vec<FileVersion>, SyncError> {
+    let api_url = format!(
+        "https://graph.microsoft.com/v1.0{}/versions",
+        item_id_path(cloud_id)
+    );
+    let mut handle = http::handle();
+
+    handle.url(&api_url).unwrap();
+    handle.authorize(bearer(account), &[]);
+
+    let response = http::send(&mut handle, None)?.success()?;
+    parse_versions(&response.body)
+}
+
+pub fn download_version(
+    account: &Account,
+    cloud_id: &str,
+    version_id: &str,
+) -> Result<Vec<u8>, SyncError> {
+    let api_url = format!(
+        "https://graph.microsoft.com/v1.0{}/versions/{}/content",
+        item_id_path(cloud_id),
+        urlencode(version_id)
+    );
+    let mut handle = http::handle();
+
+    handle.url(&api_url).unwrap();
+    handle.follow_location(true).unwrap();
+    handle.authorize(bearer(account), &[]);
+    handle.accept_encoding("").unwrap();
+
+    let response = http::send(&mut handle, None)?.success()?;
+    Ok(response.body)
+}
+
+// Makes the version the current one, which
+// itself is kept as a new version
+pub fn restore_version(
+    account: &Account,
+    cloud_id: &str,
+    version_id: &str,
+) -> Result<(), SyncError> {
+    let api_url = format!(
+        "https://graph.microsoft.com/v1.0{}/versions/{}/restoreVersion",
+        item_id_path(cloud_id),
+        urlencode(version_id)
+    );
+    let mut handle = http::handle();
+
+    handle.url(&api_url).unwrap();
+    handle.authorize(bearer(account), &[]);
+    handle.post_fields_copy(&[]).unwrap();
+
+    http::send(&mut handle, None)?.success()?;
+    Ok(())
+}
+
+// Graph accepts up to 20 requests in one batch
+pub const BATCH_LIMIT: usize = 20;
+
+#[derive(Serialize, Debug)]
+struct BatchRequestItem {
+    id: String,
+    method: String,
+    url: String,
+}
+
+#[derive(Serialize, Debug)]
+struct BatchRequest {
+    requests: Vec<BatchRequestItem>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BatchResponseItem {
+    id: String,
+    status: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct BatchResponse {
+    responses: Vec<BatchResponseItem>,
+}
+
+// Deletes up to BATCH_LIMIT items in one request, throttled
+// or missing responses fall back to single deletes which retry
+// @Returns results in the same order as cloud_ids
+pub fn delete_files(account: &Account, cloud_ids: &[String]) -> Vec<Result<(), SyncError>> {
+    let request = BatchRequest {
+        requests: cloud_ids
+            .iter()
+            .enumerate()
+            .map(|(index, cloud_id)| BatchRequestItem {
+                id: index.to_string(),
+                method: "DELETE".to_string(),
+                url: item_id_path(cloud_id),
+            })
+            .collect(),
+    };
+    let request_body = serde_json::to_vec(&request).unwrap();
+
+    let api_url = "https://graph.microsoft.com/v1.0/$batch";
+    let mut handle = http::handle();
+
+    handle.url(api_url).unwrap();
+    handle.authorize(bearer(account), &["Content-Type: application/json"]);
+    handle.post(true).unwrap();
+    handle.post_fields_copy(&request_body).unwrap();
+
+    let batch_response = http::send(&mut handle, None)
+        .and_then(|response| response.success())
+        .and_then(|response| {
+            serde_json::from_slice::<BatchResponse>(&response.body).map_err(http::parse_error)
+        });
+
+    let batch_response = match batch_response {
+        Ok(batch_response) => batch_response,
+        Err(err) => return cloud_ids.iter().map(|_| Err(err.clone())).collect(),
+    };
+
+    let mut statuses: Vec<Option<u32>> = cloud_ids.iter().map(|_| None).collect();
+    for response in batch_response.responses {
+        if let Some(status) = response
+            .id
+            .parse::<usize>()
+            .ok()
+            .and_then(|index| statuses.get_mut(index))
+        {
+            *status = Some(response.status);
+        }
+    }
+
+    cloud_ids
+        .iter()
+        .zip(statuses)
+        .map(|(cloud_id, status)| match status {
+            Some(200..=299) => Ok(()),
+            Some(429) | Some(503) | None => delete_file(account, cloud_id),
+            Some(status) => Err(SyncError::Api {
+                code: status,
+                message: String::new(),
+            }),
+        })
+        .collect()
+}
+
+// Cheap authenticated request, only the
+// Date header of the response is used
+// @Returns server time as unix timestamp
+pub fn get_server_time(account: &Account) -> Result<u64, SyncError> {
+    let api_url = "https://graph.microsoft.com/v1.0/me/drive?$select=id";
+    let mut handle = http::handle();
+
+    handle.url(api_url).unwrap();
+    handle.authorize(bearer(account), &[]);
+
+    let response = http::send(&mut handle, None)?.success()?;
+    let server_date = response
+        .header("date")
+        .ok_or(SyncError::Parse("Response has no Date header".to_string()))?;
+    parse_http_date(server_date)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Drive {
+    id: String,
+}
+
+// Accounts saved twice for the same drive share this id
+// @Returns id of the drive of the signed in user
+pub fn get_drive_id(account: &Account) -> Result<String, SyncError> {
+    let api_url = "https://graph.microsoft.com/v1.0/me/drive?$select=id";
+    let mut handle = http::handle();
+
+    handle.url(api_url).unwrap();
+    handle.authorize(bearer(account), &[]);
+
+    let response = http::send(&mut handle, None)?.success()?;
+    let drive: Drive = serde_json::from_slice(&response.body).map_err(http::parse_error)?;
+    Ok(drive.id)
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug)]
+struct User {
+    userPrincipalName: Option<String>,
+    displayName: Option<String>,
+}
+
+// @Returns name of the signed in user
+pub fn get_user(account: &Account) -> Result<String, SyncError> {
+    let api_url = "https://graph.microsoft.com/v1.0/me?$select=userPrincipalName,displayName";
+    let mut handle = http::handle();
+
+    handle.url(api_url).unwrap();
+    handle.authorize(bearer(account), &[]);
+
+    let response = http::send(&mut handle, None)?.success()?;
+    let user: User = serde_json::from_slice(&response.body).map_err(http::parse_error)?;
+    Ok(user
+        .userPrincipalName
+        .or(user.displayName)
+        .unwrap_or_default())
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CopyParentReference {
+    path: String,
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug)]
+struct CopyRequest {
+    parentReference: CopyParentReference,
+    name: String,
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Debug)]
+struct CopyStatus {
+    status: String,
+    resourceId: Option<String>,
+}
+
+// Copying is async on graph, the response only has
+// a monitor url which we poll until the copy is done
+// @Returns cloud id of the new item
+pub fn copy_item(account: &Account, cloud_id: &str, new_path: &str) -> Result<String, SyncError> {
+    // Copy destinations are parent paths of this drive
+    if cloud_id.contains('/') || find_mount(&shared_mounts(account), new_path).is_some() {
+        return Err(SyncError::Usage(
+            "Shared folders are not copied into, uploading instead".to_string(),
+        ));
+    }
+
+    let app_path = app_folder_path(account)?;
+    let (parent_path, name) = parent_reference(app_path.as_deref(), new_path);
+    let request = CopyRequest {
+        parentReference: CopyParentReference { path: parent_path },
+        name,
+    };
+    let request_body = serde_json::to_vec(&request).unwrap();
+
+    let api_url = format!(
+        "https://graph.microsoft.com/v1.0/me/drive/items/{}/copy?@microsoft.graph.conflictBehavior=replace",
+        cloud_id
+    );
+    let mut handle = http::handle();
+
+    handle.url(&api_url).unwrap();
+    handle.authorize(bearer(account), &["Content-Type: application/json"]);
+    handle.post(true).unwrap();
+    handle.post_fields_copy(&request_body).unwrap();
+
+    let response = http::send(&mut handle, None)?.success()?;
+    let monitor_url = response.header("location").ok_or(SyncError::Parse(
+        "Copy response has no monitor url".to_string(),
+    ))?;
+
+    // Monitor url is pre-authenticated
+    for _ in 0..COPY_POLL_ATTEMPTS {
+        let mut handle = http::handle();
+        handle.url(monitor_url).unwrap();
+
+        let response = http::send(&mut handle, None)?.success()?;
+        let copy_status: CopyStatus =
+            serde_json::from_slice(&response.body).map_err(http::parse_error)?;
+
+        match copy_status.status.as_str() {
+            "completed" => {
+                return copy_status.resourceId.ok_or(SyncError::Parse(
+                    "Copy completed without an item id".to_string(),
+                ))
+            }
+            "failed" => {
+                return Err(SyncError::Api {
+                    code: response.code,
+                    message: "Copy failed on server".to_string(),
+                })
+            }
+            _ => std::thread::sleep(std::time::Duration::from_secs(1)),
+        }
+    }
+
+    Err(SyncError::Network(
+        "Timed out waiting for copy to complete".to_string(),
+    ))
+}
+
+// Shared folders are only followed with follow_shared,
+// their contents come from the delta of the owner's drive
+pub fn get_drive_delta(
+    account: &mut Account,
+    follow_shared: bool,
+) -> Result<Vec<DriveDelta>, SyncError> {
+    let stored_mounts = shared_mounts(account);
+
+    let app_path = app_folder_path(account)?;
+    if let Some(app_path) = &app_path {
+        account
+            .attributes
+            .insert(APP_FOLDER_PATH_KEY.to_string(), app_path.clone());
+    }
+
+    // Shared folders are mounted in the drive
+    // root, never inside of the app folder
+    let collect = |api_url: &str| {
+        let mut deltas = Vec::new();
+        let mut mounts = stored_mounts.clone();
+        let delta_link = get_delta(account, api_url, &mut |item| {
+            if app_path.is_none() {
+                update_mounts(&mut mounts, &item);
+            }
+            deltas.extend(item_to_delta(item).map(|mut delta| {
+                delta.file_path = app_relative(delta.file_path, app_path.as_deref());
+                delta
+            }));
+        })?;
+        Ok::<_, SyncError>((delta_link, deltas, mounts))
+    };
+
+    let delta_link_key = "delta_link".to_string();
+    let root_delta = format!("{}/delta", root_url(account));
+    let (delta_link, mut deltas, mut mounts) = collect_delta(
+        account.attributes.get(&delta_link_key).map(String::as_str),
+        &root_delta,
+        "Stored delta link expired, listing the whole drive again",
+        collect,
+    )?;
+    account.attributes.insert(delta_link_key, delta_link);
+
+    if follow_shared && app_path.is_none() {
+        for mount in &mut mounts {
+            deltas.extend(get_shared_delta(account, mount)?);
+        }
+    }
+
+    account.attributes.insert(
+        SHARED_MOUNTS_KEY.to_string(),
+        serde_json::to_string(&mounts).unwrap(),
+    );
+    Ok(deltas)
+}
+
+// Starts from the stored delta link, without one or once
+// it expired the changes are collected from root_delta
+fn collect_delta<T>(
+    stored: Option<&str>,
+    root_delta: &str,
+    expired: &str,
+    mut collect: impl FnMut(&str) -> Result<T, SyncError>,
+) -> Result<T, SyncError> {
+    match stored {
+        Some(delta_link) => match collect(delta_link) {
+            Err(err) if needs_resync(&err) => {
+                println!("INFO: {}", expired);
+                collect(root_delta)
+            }
+            result => result,
+        },
+        None => collect(root_delta),
+    }
+}
+
+// Mount points show up in the delta as items with
+// a remote item, deleted ones only by their id
+fn update_mounts(mounts: &mut Vec<SharedMount>, item: &OneDriveItem) {
+    let previous = mounts
+        .iter()
+        .position(|mount| mount.id == item.id)
+        .map(|index| mounts.remove(index));
+
+    let remote = item
+        .remoteItem
+        .as_ref()
+        .filter(|remote| remote.folder.is_some());
+    let (Some(remote), Some(name), None) = (remote, &item.name, &item.deleted) else {
+        return;
+    };
+
+    let Some(drive_id) = remote
+        .parentReference
+        .as_ref()
+        .and_then(|parent| parent.driveId.clone())
+    else {
+        println!("ERROR: Skipping shared folder {} without drive id", name);
+        return;
+    };
+
+    // Moving the mount point keeps the remote delta
+    let (remote_path, delta_link) = previous
+        .filter(|mount| mount.item_id == remote.id)
+        .map_or((None, None), |mount| (mount.remote_path, mount.delta_link));
+
+    mounts.push(SharedMount {
+        id: item.id.clone(),
+        path: item_path(item.parentReference.path.as_deref(), name),
+        drive_id,
+        item_id: remote.id.clone(),
+        remote_path,
+        delta_link,
+    });
+}
+
+// @Returns changes below the shared folder, with paths
+// below the mount point and ids including the drive
+fn get_shared_delta(
+    account: &Account,
+    mount: &mut SharedMount,
+) -> Result<Vec<DriveDelta>, SyncError> {
+    let remote_path = match &mount.remote_path {
+        Some(remote_path) => remote_path.clone(),
+        None => {
+            let remote_path = get_remote_path(account, mount)?;
+            mount.remote_path = Some(remote_path.clone());
+            remote_path
+        }
+    };
+
+    let collect = |api_url: &str| {
+        let mut deltas = Vec::new();
+        let delta_link = get_delta(account, api_url, &mut |item| {
+            let Some(mut delta) = item_to_delta(item) else {
+                return;
+            };
+
+            let Some(rest) = delta.file_path.strip_prefix(remote_path.as_str()) else {
+                return;
+            };
+            if !rest.starts_with('/') {
+                return;
+            }
+
+            delta.file_path = format!("{}{}", mount.path, rest);
+            delta.cloud_id = format!("{}/{}", mount.drive_id, delta.cloud_id);
+            deltas.push(delta);
+        })?;
+        Ok::<_, SyncError>((delta_link, deltas))
+    };
+
+    let root_delta = format!(
+        "https://graph.microsoft.com/v1.0/drives/{}/items/{}/delta",
+        mount.drive_id, mount.item_id
+    );
+    let (delta_link, deltas) = collect_delta(
+        mount.delta_link.as_deref(),
+        &root_delta,
+        &format!(
+            "Stored delta link of {} expired, listing it again",
+            mount.path
+        ),
+        collect,
+    )?;
+
+    mount.delta_link = Some(delta_link);
+    Ok(deltas)
+}
+
+fn get_remote_path(account: &Account, mount: &SharedMount) -> Result<String, SyncError> {
+    let api_url = format!(
+        "https://graph.microsoft.com/v1.0/drives/{}/items/{}?$select=id,name,parentReference",
+        mount.drive_id, mount.item_id
+    );
+    let mut handle = http::handle();
+
+    handle.url(&api_url).unwrap();
+    handle.authorize(bearer(account), &[]);
+
+    let response = http::send(&mut handle, None)?.success()?;
+    let item: OneDriveItem = serde_json::from_slice(&response.body).map_err(http::parse_error)?;
+    let name = item
+        .name
+        .ok_or(SyncError::Parse("Shared folder has no name".to_string()))?;
+
+    Ok(item_path(item.parentReference.path.as_deref(), &name))
+}
+
+#[derive(Deserialize)]
+struct ChildrenPage {
+    value: Vec<OneDriveItem>,
+
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>,
+}
+
+// The root and mount points have no path
+// below the drive, they are addressed directly
+fn children_url(account: &Account, folder_path: &str) -> String {
+    let folder_path = folder_path.trim_matches('/');
+    let mounts = shared_mounts(account);
+    if let Some(mount) = mounts
+        .iter()
+        .find(|mount| mount.path.trim_matches('/') == folder_path)
+    {
+        return format!(
+            "https://graph.microsoft.com/v1.0/drives/{}/items/{}/children",
+            mount.drive_id, mount.item_id
+        );
+    }
+
+    match folder_path {
+        "" => format!("{}/children", root_url(account)),
+        _ => drive_item_url(account, folder_path, "children"),
+    }
+}
+
+fn item_to_entry(item: OneDriveItem) -> Option<FolderEntry> {
+    let is_folder = item.folder.is_some()
+        || item
+            .remoteItem
+            .as_ref()
+            .is_some_and(|remote| remote.folder.is_some());
+
+    Some(FolderEntry {
+        name: item.name?,
+        is_folder,
+        size: item.size,
+        last_modified: item
+            .lastModifiedDateTime
+            .as_deref()
+            .and_then(|date| parse_iso_date(date).ok()),
+    })
+}
+
+// @Returns files and folders right inside the folder
+pub fn list_children(account: &Account, folder_path: &str) -> Result<Vec<FolderEntry>, SyncError> {
+    let mut entries = Vec::new();
+    let mut page_url = children_url(account, folder_path);
+
+    loop {
+        let mut handle = http::handle();
+
+        handle.url(&page_url).unwrap();
+        handle.authorize(bearer(account), &[]);
+
+        let response = http::send(&mut handle, None)?.success()?;
+        let page: ChildrenPage = response.json()?;
+        entries.extend(page.value.into_iter().filter_map(item_to_entry));
+
+        match page.next_link {
+            Some(next_link) => page_url = next_link,
+            None => return Ok(entries),
+        }
+    }
+}
+
+// A delta from the root without a stored link
+// enumerates every item currently in the drive
+pub fn get_drive_listing(account: &Account) -> Result<Vec<DriveDelta>, SyncError> {
+    let mut listing = Vec::new();
+    let app_path = app_folder_path(account)?;
+
+    // Delta link is dropped so the stored
+    // one isn't advanced by the listing
+    let mut mounts = shared_mounts(account);
+    let delta_link = format!("{}/delta", root_url(account));
+    get_delta(account, &delta_link, &mut |item| {
+        if app_path.is_none() {
+            update_mounts(&mut mounts, &item);
+        }
+        if let Some(mut delta) = item_to_delta(item) {
+            if matches!(delta.delta_type, DriveDeltaType::CreatedOrModifiled) {
+                delta.file_path = app_relative(delta.file_path, app_path.as_deref());
+                listing.push(delta);
+            }
+        }
+    })?;
+
+    // Files below shared folders synced with --follow-shared
+    // aren't in the drive's own delta, each is listed in full
+    for mut mount in mounts {
+        mount.delta_link = None;
+        listing.extend(
+            get_shared_delta(account, &mut mount)?
+                .into_iter()
+                .filter(|delta| matches!(delta.delta_type, DriveDeltaType::CreatedOrModifiled)),
+        );
+    }
+
+    Ok(listing)
+}
+
+// @Returns none for items which aren't synced as files
+fn item_to_delta(file: OneDriveItem) -> Option<DriveDelta> {
+    // Skipping folders
+    if file.folder.is_some() || file.is_root() {
+        return None;
+    }
+
+    // Shortcuts to items of other drives and packages can't be
+    // downloaded as files, shared folders are followed separately
+    if file.remoteItem.is_some() || file.package.is_some() {
+        return None;
+    }
+
+    let file_name = file.name?;
+    if file_name.is_empty() {
+        return None;
+    }
+
+    let file_path = item_path(file.parentReference.path.as_deref(), &file_name);
+
+    let last_modified = match file.lastModifiedDateTime.as_deref().map(parse_iso_date) {
+        Some(Ok(last_modified)) => last_modified,
+        Some(Err(err)) => {
+            println!("ERROR: Skipping {}: {}", file_path, err);
+            return None;
+        }
+        None => {
+            println!("ERROR: Skipping {}: no last modified date", file_path);
+            return None;
+        }
+    };
+
+    let created = file
+        .fileSystemInfo
+        .and_then(|info| info.createdDateTime)
+        .and_then(|created| parse_iso_date(&created).ok());
+
+    Some(DriveDelta {
+        cloud_id: file.id,
+        file_path,
+        last_modified,
+        created,
+        ctag: file.cTag,
+        size: file.size,
+        delta_type: if file.deleted.is_some() {
+            DriveDeltaType::Deleted
+        } else {
+            DriveDeltaType::CreatedOrModifiled
+        },
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+struct MicrosoftGraphToken {
+    access_token: String,
+    refresh_token: String,
+    expires_in: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MicrosoftGraphTokenError {
+    error: String,
+    error_description: Option<String>,
+}
+
+fn request_token(
+    client: &ClientConfig,
+    code: &str,
+    grant_type: &str,
+) -> Result<http::Response, SyncError> {
+    let mut form = form_with_client(client);
+    form.part("redirect_uri")
+        .contents(client.redirect_url().as_bytes())
+        .add()
+        .unwrap();
+
+    form.part("grant_type")
+        .contents(grant_type.as_bytes())
+        .add()
+        .unwrap();
+
+    let code_field = match grant_type {
+        "authorization_code" => "code",
+        _ => "refresh_token",
+    };
+    form.part(code_field)
+        .contents(code.as_bytes())
+        .add()
+        .unwrap();
+
+    let mut handle = http::handle();
+
+    handle.url(TOKEN_URL).unwrap();
+    handle.httppost(form).unwrap();
+
+    http::send(&mut handle, None)
+}
+
+fn token_error(grant_type: &str, response_body: &[u8]) -> SyncError {
+    let token_error = match serde_json::from_slice::<MicrosoftGraphTokenError>(response_body) {
+        Ok(token_error) => token_error,
+        Err(_) => {
+            return SyncError::Auth("Token request failed with an unexpected response".to_string())
+        }
+    };
+
+    // Revoked or expired grants never succeed on retry
+    if token_error.error == "invalid_grant" {
+        let message = match grant_type {
+            "authorization_code" => "Auth code is invalid or expired, please run login again",
+            DEVICE_GRANT_TYPE => "Device code is invalid, please run login --device again",
+            _ => "Refresh token was revoked or expired, please run login and save again",
+        };
+        return SyncError::Auth(message.to_string());
+    }
+
+    SyncError::Auth(format!(
+        "Token request failed: {}: {}",
+        token_error.error,
+        token_error.error_description.unwrap_or_default()
+    ))
+}
+
+pub fn get_token(client: &ClientConfig, code: &str, grant_type: &str) -> Result<Token, SyncError> {
+    if !matches!(grant_type, "authorization_code" | "refresh_token") {
+        return Err(SyncError::Usage("Invalid grant_type".to_string()));
+    }
+
+    // Network errors and server side failures are
+    // already retried, anything left is final
+    let response = request_token(client, code, grant_type)?;
+    if !response.is_success() {
+        return Err(token_error(grant_type, &response.body));
+    }
+
+    parse_token(&response.body, grant_type)
+}
+
+fn parse_token(response_body: &[u8], grant_type: &str) -> Result<Token, SyncError> {
+    let microsoft_token: MicrosoftGraphToken =
+        serde_json::from_slice(response_body).map_err(|err| {
+            SyncError::Parse(format!(
+                "Cannot parse response please relogin : {} :\n{}",
+                grant_type, err
+            ))
+        })?;
+
+    let start = SystemTime::now();
+    let since_the_epoch = start
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+
+    Ok(Token {
+        access_token: microsoft_token.access_token,
+        refresh_token: microsoft_token.refresh_token,
+        valid_till: since_the_epoch + microsoft_token.expires_in,
+    })
+}
+
+// Code the user enters on another device to sign in there
+#[derive(Deserialize, Debug)]
+pub struct DeviceCode {
+    device_code: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    expires_in: u64,
+
+    // Seconds to wait between polls
+    #[serde(default = "default_poll_interval")]
+    interval: u64,
+}
+
+fn default_poll_interval() -> u64 {
+    5
+}
+
+fn form_with_client(client: &ClientConfig) -> Form {
+    let mut form = Form::new();
+    form.part("client_id")
+        .contents(client.client_id().as_bytes())
+        .add()
+        .unwrap();
+
+    // Only confidential clients have a secret
+    if let Some(client_secret) = &client.client_secret {
+        form.part("client_secret")
+            .contents(client_secret.as_bytes())
+            .add()
+            .unwrap();
+    }
+    form
+}
+
+// Device authorization grant, for machines without a browser.
+// The app registration has to allow public client flows
+pub fn get_device_code(client: &ClientConfig) -> Result<DeviceCode, SyncError> {
+    let mut form = form_with_client(client);
+    form.part("scope")
+        .contents(client.scopes().as_bytes())
+        .add()
+        .unwrap();
+
+    let mut handle = http::handle();
+    handle.url(DEVICE_CODE_URL).unwrap();
+    handle.httppost(form).unwrap();
+
+    let response = http::send(&mut handle, None)?;
+    if !response.is_success() {
+        return Err(token_error(DEVICE_GRANT_TYPE, &response.body));
+    }
+    serde_json::from_slice(&response.body).map_err(http::parse_error)
+}
+
+enum DevicePoll {
+    Pending,
+    SlowDown,
+    Failed(SyncError),
+}
+
+// Errors of the token endpoint while the user hasn't finished
+fn device_poll(response_body: &[u8]) -> DevicePoll {
+    match serde_json::from_slice::<MicrosoftGraphTokenError>(response_body) {
+        Ok(token_error) if token_error.error == "authorization_pending" => DevicePoll::Pending,
+        Ok(token_error) if token_error.error == "slow_down" => DevicePoll::SlowDown,
+        Ok(token_error) if token_error.error == "expired_token" => DevicePoll::Failed(
+            SyncError::Auth("Device code expired, please run login --device again".to_string()),
+        ),
+        Ok(token_error) if token_error.error == "authorization_declined" => {
+            DevicePoll::Failed(SyncError::Auth("Sign in was declined".to_string()))
+        }
+        _ => DevicePoll::Failed(token_error(DEVICE_GRANT_TYPE, response_body)),
+    }
+}
+
+// Polls until the user signed in with the code, declined or the code expired
+pub fn poll_device_token(
+    client: &ClientConfig,
+    device_code: &DeviceCode,
+) -> Result<Token, SyncError> {
+    let expires_at = SystemTime::now() + Duration::from_secs(device_code.expires_in);
+    let mut interval = device_code.interval;
+
+    loop {
+        std::thread::sleep(Duration::from_secs(interval));
+        if SystemTime::now() > expires_at {
+            return Err(SyncError::Auth(
+                "Device code expired, please run login --device again".to_string(),
+            ));
+        }
+
+        let mut form = form_with_client(client);
+        form.part("grant_type")
+            .contents(DEVICE_GRANT_TYPE.as_bytes())
+            .add()
+            .unwrap();
+        form.part("device_code")
+            .contents(device_code.device_code.as_bytes())
+            .add()
+            .unwrap();
+
+        let mut handle = http::handle();
+        handle.url(TOKEN_URL).unwrap();
+        handle.httppost(form).unwrap();
+
+        let response = http::send(&mut handle, None)?;
+        if response.is_success() {
+            return parse_token(&response.body, DEVICE_GRANT_TYPE);
+        }
+
+        match device_poll(&response.body) {
+            DevicePoll::Pending => {}
+            DevicePoll::SlowDown => interval += 5,
+            DevicePoll::Failed(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::onedrive::{
+        app_relative, children_url, collect_delta, device_poll, drive_item_url, item_id_path,
+        item_path, item_to_delta, item_to_entry, item_url, needs_resync, next_offset,
+        parent_reference, parse_delta_page, parse_redirect, parse_versions, replay_delta_link,
+        reset_root, update_mounts, ChildrenPage, DeviceCode, DevicePoll, OneDriveItem, PageEnd,
+        APP_FOLDER_KEY, DRIVE_ROOT_URL, SHARED_MOUNTS_KEY,
+    };
+    use crate::{Account, DriveDelta, DriveDeltaType, FolderEntry, SyncError, SyncService, Token};
+
+    fn parse_items(payload: &str) -> Vec<OneDriveItem> {
+        let mut items = Vec::new();
+        parse_delta_page(payload.as_bytes(), &mut |item| items.push(item)).unwrap();
+        items
+    }
+
+    fn items_to_deltas(items: Vec<OneDriveItem>) -> Vec<DriveDelta> {
+        items.into_iter().filter_map(item_to_delta).collect()
+    }
+
+    #[test]
+    fn test_device_code() {
+        let device_code: DeviceCode = serde_json::from_str(
+            r#"{"device_code":"DAQAB","user_code":"F2XQ7L9KD","verification_uri":"https://microsoft.com/devicelogin","expires_in":900,"message":"To sign in, ..."}"#,
+        )
+        .unwrap();
+        assert_eq!(device_code.user_code, "F2XQ7L9KD");
+        assert_eq!(device_code.interval, 5);
+
+        let poll = |error: &str| device_poll(format!(r#"{{"error":"{}"}}"#, error).as_bytes());
+        assert!(matches!(poll("authorization_pending"), DevicePoll::Pending));
+        assert!(matches!(poll("slow_down"), DevicePoll::SlowDown));
+        assert!(matches!(
+            poll("expired_token"),
+            DevicePoll::Failed(SyncError::Auth(message)) if message.contains("expired")
+        ));
+        assert!(matches!(
+            poll("invalid_grant"),
+            DevicePoll::Failed(SyncError::Auth(message)) if message.contains("login --device")
+        ));
+        assert!(matches!(device_poll(b"<html>"), DevicePoll::Failed(_)));
+    }
+
+    #[test]
+    fn test_parse_redirect() {
+        let redirect = "https://login.microsoftonline.com/common/oauth2/nativeclient";
+
+        assert_eq!(parse_redirect("M.C507_abc"), Ok("M.C507_abc".to_string()));
+        assert_eq!(
+            parse_redirect(&format!("{}?code=M.C507_abc%2Bdef", redirect)),
+            Ok("M.C507_abc+def".to_string())
+        );
+        assert_eq!(
+            parse_redirect(&format!(
+                "{}?error=access_denied&error_description=The+user+has+denied+access",
+                redirect
+            )),
+            Err(SyncError::Auth(
+                "Login failed: access_denied: The user has denied access".to_string()
+            ))
+        );
+        assert!(parse_redirect(&format!("{}?state=1", redirect)).is_err());
+    }
+
+    #[test]
+    fn test_item_path_round_trip() {
+        let root_file = item_path(Some("/drive/root:"), "a.txt");
+        assert_eq!(root_file, "/a.txt");
+        assert_eq!(
+            item_url(DRIVE_ROOT_URL, &root_file, "content"),
+            "https://graph.microsoft.com/v1.0/me/drive/root:/a.txt:/content"
+        );
+        assert_eq!(
+            parent_reference(None, &root_file),
+            ("/drive/root:".to_string(), "a.txt".to_string())
+        );
+
+        let nested_file = item_path(Some("/drive/root:/sub dir/b"), "a.txt");
+        assert_eq!(nested_file, "/sub dir/b/a.txt");
+        assert_eq!(
+            item_url(DRIVE_ROOT_URL, &nested_file, "createUploadSession"),
+            "https://graph.microsoft.com/v1.0/me/drive/root:/sub%20dir/b/a.txt:/createUploadSession"
+        );
+        let (parent_path, name) = parent_reference(None, &nested_file);
+        assert_eq!(item_path(Some(&parent_path), &name), nested_file);
+
+        // Both shapes of the same path build the same url
+        assert_eq!(
+            item_url(DRIVE_ROOT_URL, "a.txt", "content"),
+            item_url(DRIVE_ROOT_URL, "/a.txt", "content")
+        );
+        assert_eq!(item_path(None, "a.txt"), "/a.txt");
+    }
+
+    #[test]
+    fn test_list_children() {
+        let token = Token {
+            access_token: String::new(),
+            refresh_token: String::new(),
+            valid_till: 0,
+        };
+        let account = Account::new(SyncService::Onedrive, token);
+        assert_eq!(
+            children_url(&account, "/"),
+            "https://graph.microsoft.com/v1.0/me/drive/root/children"
+        );
+        assert_eq!(
+            children_url(&account, "/docs"),
+            "https://graph.microsoft.com/v1.0/me/drive/root:/docs:/children"
+        );
+
+        let payload = r#"{
+            "value": [
+                {
+                    "id": "A",
+                    "name": "a.txt",
+                    "parentReference": {"path": "/drive/root:/docs"},
+                    "lastModifiedDateTime": "2023-08-06T13:23:00Z",
+                    "size": 12,
+                    "file": {}
+                },
+                {
+                    "id": "B",
+                    "name": "sub",
+                    "parentReference": {"path": "/drive/root:/docs"},
+                    "size": 0,
+                    "folder": {"childCount": 0}
+                },
+                {
+                    "id": "C",
+                    "name": "Team",
+                    "parentReference": {"path": "/drive/root:/docs"},
+                    "remoteItem": {"id": "REMOTE", "folder": {}}
+                }
+            ]
+        }"#;
+        let page: ChildrenPage = serde_json::from_str(payload).unwrap();
+        assert_eq!(page.next_link, None);
+
+        let entries: Vec<FolderEntry> = page.value.into_iter().filter_map(item_to_entry).collect();
+        assert_eq!(
+            entries[0],
+            FolderEntry {
+                name: "a.txt".to_string(),
+                is_folder: false,
+                size: Some(12),
+                last_modified: Some(1691328180),
+            }
+        );
+        assert!(entries[1].is_folder);
+        assert!(entries[2].is_folder);
+        assert_eq!(entries[2].size, None);
+    }
+
+    #[test]
+    fn test_app_folder() {
+        let token = Token {
+            access_token: String::new(),
+            refresh_token: String::new(),
+            valid_till: 0,
+        };
+        let mut account = Account::new(SyncService::Onedrive, token);
+        assert_eq!(
+            drive_item_url(&account, "/a.txt", "content"),
+            "https://graph.microsoft.com/v1.0/me/drive/root:/a.txt:/content"
+        );
+
+        account
+            .attributes
+            .insert(APP_FOLDER_KEY.to_string(), "true".to_string());
+        assert_eq!(
+            drive_item_url(&account, "/sub/a.txt", "content"),
+            "https://graph.microsoft.com/v1.0/me/drive/special/approot:/sub/a.txt:/content"
+        );
+        assert_eq!(
+            replay_delta_link(&account, "token=aTE09NjM4").unwrap(),
+            "https://graph.microsoft.com/v1.0/me/drive/special/approot/delta?token=aTE09NjM4"
+        );
+        let link = "https://graph.microsoft.com/v1.0/me/drive/root/delta?token=aTE09NjM4";
+        assert_eq!(replay_delta_link(&account, link).unwrap(), link);
+        assert!(replay_delta_link(&account, "https://example.com/delta?token=1").is_err());
+        assert!(replay_delta_link(&account, "a&b").is_err());
+
+        account
+            .attributes
+            .insert("delta_link".to_string(), "https://example.com".to_string());
+        reset_root(&mut account);
+        assert!(!account.attributes.contains_key("delta_link"));
+
+        // Items come with their path in the drive or below approot:
+        let app_path = Some("/Apps/cloudsync");
+        let file_path = item_path(Some("/drive/root:/Apps/cloudsync/sub"), "a.txt");
+        assert_eq!(app_relative(file_path, app_path), "/sub/a.txt");
+        let file_path = item_path(Some("/drive/special/approot:/sub"), "a.txt");
+        assert_eq!(app_relative(file_path, app_path), "/sub/a.txt");
+        assert_eq!(
+            app_relative("/Apps/cloudsync2/a.txt".to_string(), app_path),
+            "/Apps/cloudsync2/a.txt"
+        );
+
+        assert_eq!(
+            parent_reference(app_path, "/sub/a.txt"),
+            (
+                "/drive/root:/Apps/cloudsync/sub".to_string(),
+                "a.txt".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_versions() {
+        let payload = br#"{
+            "value": [
+                {"id": "2.0", "lastModifiedDateTime": "2023-08-06T13:23:00Z", "size": 12},
+                {"id": "1.0", "lastModifiedDateTime": "2023-08-05T13:23:00Z"}
+            ]
+        }"#;
+
+        let versions = parse_versions(payload).unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].id, "2.0");
+        assert_eq!(versions[0].last_modified, 1691328180);
+        assert_eq!(versions[0].size, 12);
+        assert_eq!(versions[1].size, 0);
+    }
+
+    #[test]
+    fn test_next_offset() {
+        assert_eq!(next_offset(&["327680-".to_string()]), Some(327680));
+        assert_eq!(
+            next_offset(&["100-199".to_string(), "400-".to_string()]),
+            Some(100)
+        );
+        assert_eq!(next_offset(&[]), None);
+    }
+
+    #[test]
+    fn test_parse_delta_page() {
+        let payload = r#"{
+            "@odata.context": "https://graph.microsoft.com/v1.0/$metadata",
+            "value": [
+                {"id": "A", "parentReference": {}, "extra": [1, {"nested": true}]},
+                {"id": "B", "parentReference": {}}
+            ],
+            "@odata.nextLink": "https://graph.microsoft.com/v1.0/me/drive/root/delta?token=2"
+        }"#;
+
+        let mut ids = Vec::new();
+        let links = parse_delta_page(payload.as_bytes(), &mut |item| ids.push(item.id)).unwrap();
+        assert_eq!(ids, ["A", "B"]);
+        assert_eq!(
+            links.next_link.as_deref(),
+            Some("https://graph.microsoft.com/v1.0/me/drive/root/delta?token=2")
+        );
+        assert_eq!(links.delta_link, None);
+
+        // Trailing garbage fails the whole page
+        assert!(parse_delta_page(r#"{"value": []} x"#.as_bytes(), &mut |_| {}).is_err());
+    }
+
+    #[test]
+    fn test_empty_drive_delta() {
+        // A new drive only lists its root
+        let payload = r#"{
+            "value": [
+                {"id": "ROOT", "name": "root", "parentReference": {}, "root": {}}
+            ],
+            "@odata.deltaLink": "https://graph.microsoft.com/v1.0/me/drive/root/delta?token=1"
+        }"#;
+        let mut items = Vec::new();
+        let links = parse_delta_page(payload.as_bytes(), &mut |item| items.push(item)).unwrap();
+        assert!(items_to_deltas(items).is_empty());
+        assert_eq!(
+            links.end(),
+            Ok(PageEnd::Last(
+                "https://graph.microsoft.com/v1.0/me/drive/root/delta?token=1".to_string()
+            ))
+        );
+
+        // Bodies which only parse as JSON are no empty drive
+        for payload in [
+            r#"{}"#,
+            r#"{"error": {"code": "generalException"}}"#,
+            r#"{"value": []}"#,
+        ] {
+            let links = parse_delta_page(payload.as_bytes(), &mut |_| {}).unwrap();
+            assert!(links.end().is_err(), "{}", payload);
+        }
+    }
+
+    #[test]
+    fn test_needs_resync() {
+        // As graph answers an expired delta link
+        let response = crate::http::Response {
+            code: 410,
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: br#"{"error":{"code":"resyncRequired","message":"Resync required. Replace any local items with the server's version (including deletes) if you're sure that the service was up to date with your local changes when you last sync'd. Upload any local changes that the server doesn't know about."}}"#.to_vec(),
+        };
+        let Err(err) = response.success() else {
+            panic!("410 taken for a success");
+        };
+        assert!(needs_resync(&err));
+
+        assert!(needs_resync(&SyncError::Api {
+            code: 410,
+            message: "resyncRequired: Resync required".to_string(),
+        }));
+        assert!(needs_resync(&SyncError::Api {
+            code: 400,
+            message: "resyncChangesApplyDifferences: Resync required".to_string(),
+        }));
+        assert!(!needs_resync(&SyncError::Api {
+            code: 404,
+            message: "itemNotFound: Item not found".to_string(),
+        }));
+        assert!(!needs_resync(&SyncError::Network("offline".to_string())));
+    }
+
+    #[test]
+    fn test_collect_delta() {
+        let root_delta = "https://graph.microsoft.com/v1.0/me/drive/root/delta";
+        let run = |stored: Option<&str>| {
+            let mut requested = Vec::new();
+            let delta_link = collect_delta(stored, root_delta, "expired", |api_url| {
+                requested.push(api_url.to_string());
+                match api_url {
+                    "expired" => Err(SyncError::Api {
+                        code: 410,
+                        message: "resyncRequired: Resync required".to_string(),
+                    }),
+                    "offline" => Err(SyncError::Network("offline".to_string())),
+                    _ => Ok(format!("{}?token=new", api_url)),
+                }
+            });
+            (delta_link, requested)
+        };
+
+        // An expired link falls back to listing the root
+        let (delta_link, requested) = run(Some("expired"));
+        assert_eq!(delta_link.unwrap(), format!("{}?token=new", root_delta));
+        assert_eq!(requested, vec!["expired", root_delta]);
+
+        // Other failures don't start over
+        let (delta_link, requested) = run(Some("offline"));
+        assert!(matches!(delta_link, Err(SyncError::Network(_))));
+        assert_eq!(requested, vec!["offline"]);
+
+        let (delta_link, requested) = run(None);
+        assert_eq!(delta_link.unwrap(), format!("{}?token=new", root_delta));
+        assert_eq!(requested, vec![root_delta]);
+    }
+
+    #[test]
+    fn test_delta_skips_root() {
+        let payload = r#"{
+            "value": [
+                {
+                    "id": "ROOT",
+                    "name": "root",
+                    "parentReference": {"driveId": "abc", "driveType": "personal"},
+                    "lastModifiedDateTime": "2023-08-06T13:23:00Z",
+                    "root": {}
+                },
+                {
+                    "id": "NAMED_ROOT",
+                    "name": "root",
+                    "parentReference": {},
+                    "lastModifiedDateTime": "2023-08-06T13:23:00Z"
+                },
+                {
+                    "id": "FILE",
+                    "name": "a.txt",
+                    "parentReference": {"path": "/drive/root:/docs"},
+                    "lastModifiedDateTime": "2023-08-06T13:23:00Z",
+                    "size": 1024,
+                    "file": {"mimeType": "text/plain"}
+                },
+                {
+                    "id": "GONE",
+                    "name": "b.txt",
+                    "parentReference": {},
+                    "lastModifiedDateTime": "2023-08-06T13:23:00Z",
+                    "deleted": {"state": "deleted"}
+                }
+            ]
+        }"#;
+        let deltas = items_to_deltas(parse_items(payload));
+
+        assert_eq!(deltas.len(), 2);
+        assert_eq!(deltas[0].file_path, "/docs/a.txt");
+        assert_eq!(deltas[0].size, Some(1024));
+        assert_eq!(deltas[1].cloud_id, "GONE");
+        assert!(matches!(deltas[1].delta_type, DriveDeltaType::Deleted));
+    }
+
+    #[test]
+    fn test_shared_mounts() {
+        let payload = r#"{
+            "value": [
+                {
+                    "id": "MOUNT",
+                    "name": "Team",
+                    "parentReference": {"path": "/drive/root:/Shared"},
+                    "remoteItem": {
+                        "id": "REMOTE",
+                        "parentReference": {"driveId": "d1"},
+                        "folder": {"childCount": 1}
+                    }
+                }
+            ]
+        }"#;
+        let items = parse_items(payload);
+        assert!(items_to_deltas(items.clone()).is_empty());
+
+        let mut mounts = Vec::new();
+        update_mounts(&mut mounts, &items[0]);
+        assert_eq!(mounts.len(), 1);
+        assert_eq!(mounts[0].path, "/Shared/Team");
+
+        let token = Token {
+            access_token: String::new(),
+            refresh_token: String::new(),
+            valid_till: 0,
+        };
+        let mut account = Account::new(SyncService::Onedrive, token);
+        account.attributes.insert(
+            SHARED_MOUNTS_KEY.to_string(),
+            serde_json::to_string(&mounts).unwrap(),
+        );
+        assert_eq!(
+            drive_item_url(&account, "/Shared/Team/sub/a.txt", "content"),
+            "https://graph.microsoft.com/v1.0/drives/d1/items/REMOTE:/sub/a.txt:/content"
+        );
+        assert_eq!(
+            drive_item_url(&account, "/Shared/Teams.txt", "content"),
+            item_url(DRIVE_ROOT_URL, "/Shared/Teams.txt", "content")
+        );
+        assert_eq!(
+            children_url(&account, "/Shared/Team/"),
+            "https://graph.microsoft.com/v1.0/drives/d1/items/REMOTE/children"
+        );
+
+        assert_eq!(item_id_path("d1/ABC"), "/drives/d1/items/ABC");
+        assert_eq!(item_id_path("ABC!12"), "/me/drive/items/ABC!12");
+
+        // Removing the mount point only reports its id
+        let payload = r#"{
+            "value": [
+                {"id": "MOUNT", "parentReference": {}, "deleted": {"state": "deleted"}}
+            ]
+        }"#;
+        update_mounts(&mut mounts, &parse_items(payload)[0]);
+        assert!(mounts.is_empty());
+    }
+
+    #[test]
+    fn test_delta_skips_shortcuts() {
+        let payload = r#"{
+            "value": [
+                {
+                    "id": "SHORTCUT",
+                    "name": "report.docx",
+                    "parentReference": {"path": "/drive/root:"},
+                    "lastModifiedDateTime": "2023-08-06T13:23:00Z",
+                    "remoteItem": {
+                        "id": "REMOTE",
+                        "parentReference": {"driveId": "d1"},
+                        "file": {"mimeType": "application/msword"}
+                    }
+                },
+                {
+                    "id": "NOTEBOOK",
+                    "name": "Notes",
+                    "parentReference": {"path": "/drive/root:"},
+                    "lastModifiedDateTime": "2023-08-06T13:23:00Z",
+                    "package": {"type": "oneNote"}
+                },
+                {
+                    "id": "LINK",
+                    "name": "site.url",
+                    "parentReference": {"path": "/drive/root:"},
+                    "lastModifiedDateTime": "2023-08-06T13:23:00Z",
+                    "file": {"mimeType": "application/internet-shortcut"}
+                }
+            ]
+        }"#;
+        let deltas = items_to_deltas(parse_items(payload));
+
+        // Windows shortcut files are plain files
+        assert_eq!(deltas.len(), 1);
+        assert_eq!(deltas[0].file_path, "/site.url");
+    }
 }
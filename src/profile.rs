@@ -0,0 +1,89 @@
+// Wall clock time per phase of a sync for --profile
+//
+// Phases are laps, each one ends where the next begins so together
+// they add up to the whole sync. A phase reached more than once,
+// like the local walk of a sync without changes, adds up
+
+use std::time::{Duration, Instant};
+
+pub struct Profile {
+    enabled: bool,
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Profile {
+    pub fn new(enabled: bool) -> Self {
+        Profile {
+            enabled,
+            last: Instant::now(),
+            phases: Vec::new(),
+        }
+    }
+
+    // Time since the previous lap goes to the phase
+    pub fn lap(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        self.add(phase, now.duration_since(self.last));
+        self.last = now;
+    }
+
+    fn add(&mut self, phase: &'static str, duration: Duration) {
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += duration,
+            None => self.phases.push((phase, duration)),
+        }
+    }
+
+    pub fn print(&self) {
+        if !self.enabled {
+            return;
+        }
+
+        println!("INFO: Time per phase");
+        for line in self.lines() {
+            println!("INFO:   {}", line);
+        }
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let total: Duration = self.phases.iter().map(|(_, duration)| *duration).sum();
+        self.phases
+            .iter()
+            .map(|(phase, duration)| {
+                let share = if total.is_zero() {
+                    0.0
+                } else {
+                    duration.as_secs_f64() * 100.0 / total.as_secs_f64()
+                };
+                format!(
+                    "{:<12} {:>8.2}s {:>3.0}%",
+                    phase,
+                    duration.as_secs_f64(),
+                    share
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::profile::Profile;
+
+    #[test]
+    fn test_profile_lines() {
+        let mut profile = Profile::new(false);
+        profile.add("delta", Duration::from_millis(1500));
+        profile.add("downloads", Duration::from_millis(2000));
+        profile.add("delta", Duration::from_millis(500));
+
+        assert_eq!(
+            profile.lines(),
+            vec!["delta            2.00s  50%", "downloads        2.00s  50%"]
+        );
+        assert_eq!(Profile::new(false).lines(), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,116 @@
+// Overall progress of a sync for --progress
+//
+// Totals come from the downloads and uploads the sync decided on
+// before transferring anything. Every finished transfer counts,
+// failed ones included, so the count always reaches the total. The
+// ETA is the bytes left at the throughput so far, sizes the cloud
+// didn't list count as empty
+
+use std::time::{Duration, Instant};
+
+use crate::format_size;
+
+// Lines are printed at most this often, besides the last one
+const SHOW_EVERY: Duration = Duration::from_secs(1);
+
+pub struct Progress {
+    enabled: bool,
+    total_files: usize,
+    total_bytes: u64,
+    files: usize,
+    bytes: u64,
+    started: Instant,
+    last_shown: Option<Instant>,
+}
+
+impl Progress {
+    pub fn new(enabled: bool, total_files: usize, total_bytes: u64) -> Self {
+        Progress {
+            enabled,
+            total_files,
+            total_bytes,
+            files: 0,
+            bytes: 0,
+            started: Instant::now(),
+            last_shown: None,
+        }
+    }
+
+    pub fn file_done(&mut self, bytes: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+        if !self.enabled {
+            return;
+        }
+
+        let now = Instant::now();
+        let due = self
+            .last_shown
+            .is_none_or(|last_shown| now.duration_since(last_shown) >= SHOW_EVERY);
+        if due || self.files >= self.total_files {
+            self.last_shown = Some(now);
+            println!("INFO: {}", self.line(now.duration_since(self.started)));
+        }
+    }
+
+    fn line(&self, elapsed: Duration) -> String {
+        let seconds = elapsed.as_secs_f64();
+        let rate = if seconds > 0.0 {
+            self.bytes as f64 / seconds
+        } else {
+            0.0
+        };
+
+        let left = self.total_bytes.saturating_sub(self.bytes);
+        let eta = if left == 0 {
+            format_eta(0)
+        } else if rate > 0.0 {
+            format_eta((left as f64 / rate).ceil() as u64)
+        } else {
+            "--:--".to_string()
+        };
+
+        format!(
+            "{}/{} files, {}/s, ETA {}",
+            self.files,
+            self.total_files,
+            format_size(rate as u64),
+            eta
+        )
+    }
+}
+
+// mm:ss, hours only show up when needed
+fn format_eta(seconds: u64) -> String {
+    match seconds / 3600 {
+        0 => format!("{:02}:{:02}", seconds / 60, seconds % 60),
+        hours => format!("{}:{:02}:{:02}", hours, seconds / 60 % 60, seconds % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::progress::{format_eta, Progress};
+
+    #[test]
+    fn test_progress_line() {
+        let mut progress = Progress::new(false, 4, 4 << 20);
+        assert_eq!(progress.line(Duration::ZERO), "0/4 files, 0 B/s, ETA --:--");
+
+        progress.file_done(1 << 20);
+        progress.file_done(0);
+        assert_eq!(
+            progress.line(Duration::from_secs(2)),
+            "2/4 files, 512.0 KiB/s, ETA 00:06"
+        );
+
+        // Sizes the cloud didn't list can take it past the total
+        progress.file_done(4 << 20);
+        assert!(progress.line(Duration::from_secs(3)).ends_with("ETA 00:00"));
+
+        assert_eq!(format_eta(59), "00:59");
+        assert_eq!(format_eta(3725), "1:02:05");
+    }
+}
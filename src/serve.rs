@@ -0,0 +1,151 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+
+use crate::{SyncError, SyncReport};
+
+// Outcome of the syncs of one configured folder
+#[derive(Serialize, Default, Clone)]
+pub struct FolderStatus {
+    pub account: String,
+    pub last_attempt: Option<u64>,
+    pub last_synced: Option<u64>,
+    pub last_report: Option<SyncReport>,
+    pub last_error: Option<String>,
+    pub syncs: u32,
+    pub errors: u32,
+}
+
+impl FolderStatus {
+    pub fn record(&mut self, result: &Result<SyncReport, SyncError>, now: u64) {
+        self.last_attempt = Some(now);
+        self.syncs += 1;
+
+        match result {
+            Ok(report) => {
+                self.last_synced = Some(now);
+                self.errors += report.errors;
+
+                // Ids of every uploaded file would only bloat the status
+                let mut report = report.clone();
+                report.uploads.clear();
+                self.last_report = Some(report);
+                self.last_error = None;
+            }
+            Err(err) => {
+                self.errors += 1;
+                self.last_error = Some(err.to_string());
+            }
+        }
+    }
+}
+
+// Status of every configured folder keyed by folder path
+pub type StatusBoard = Arc<Mutex<BTreeMap<String, FolderStatus>>>;
+
+// Serves the status board on a background thread
+pub fn spawn_server(addr: &str, board: StatusBoard) -> Result<(), SyncError> {
+    let listener = TcpListener::bind(addr)
+        .map_err(|err| SyncError::Io(format!("Cannot listen on {}: {}", addr, err)))?;
+
+    println!("INFO: Serving status on http://{}/status", addr);
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(err) = handle_connection(stream, &board) {
+                println!("ERROR: Status request failed: {}", err);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn handle_connection(mut stream: TcpStream, board: &StatusBoard) -> std::io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Headers are read and ignored so the client
+    // doesn't see a reset before the response
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/status")) => {
+            let board = board.lock().unwrap();
+            ("200 OK", serde_json::to_string(&*board)?)
+        }
+        _ => ("404 Not Found", r#"{"error":"Not found"}"#.to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use crate::serve::{spawn_server, FolderStatus, StatusBoard};
+    use crate::{SyncError, SyncReport};
+
+    fn request(addr: &str, path: &str) -> String {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_status_endpoint() {
+        let board = StatusBoard::default();
+        let mut status = FolderStatus {
+            account: "personal".to_string(),
+            ..Default::default()
+        };
+        status.record(&Ok(SyncReport::default()), 10);
+        status.record(&Err(SyncError::Network("offline".to_string())), 20);
+        board
+            .lock()
+            .unwrap()
+            .insert("/tmp/docs".to_string(), status);
+
+        // Picking a free port first
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        spawn_server(&addr, board).unwrap();
+
+        let response = request(&addr, "/status");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let status: serde_json::Value = serde_json::from_str(body).unwrap();
+        let folder = &status["/tmp/docs"];
+        assert_eq!(folder["account"], "personal");
+        assert_eq!(folder["last_synced"], 10);
+        assert_eq!(folder["last_attempt"], 20);
+        assert_eq!(folder["syncs"], 2);
+        assert_eq!(folder["errors"], 1);
+        assert_eq!(folder["last_error"], "offline");
+
+        assert!(request(&addr, "/other").starts_with("HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,354 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use curl::{
+    easy::{Easy2, Handler, HttpVersion, List, WriteError},
+    multi::{Easy2Handle, Multi},
+};
+
+use crate::{http, SyncError};
+
+// A GET request to run on the pool
+pub struct Download {
+    pub url: String,
+    pub headers: Vec<String>,
+
+    // Authorization comes from the bearer when set,
+    // it's refreshed once if the server rejects it
+    pub bearer: Option<http::Bearer>,
+}
+
+#[derive(Default)]
+struct Collector {
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Handler for Collector {
+    fn write(&mut self, data: &[u8]) -> Result<usize, WriteError> {
+        self.body.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn header(&mut self, header: &[u8]) -> bool {
+        let header = String::from_utf8_lossy(header);
+
+        // Only keeping headers of the last
+        // response when redirects are followed
+        if header.starts_with("HTTP/") {
+            self.headers.clear();
+        } else if let Some((name, value)) = header.split_once(':') {
+            self.headers
+                .push((name.trim().to_string(), value.trim().to_string()));
+        }
+        true
+    }
+}
+
+// Outcome of a single attempt
+enum Attempt {
+    Done(Result<Option<Vec<u8>>, SyncError>),
+    RetryAfter(Duration),
+    Rejected,
+}
+
+// Queued download waiting for a free slot
+struct Queued {
+    index: usize,
+    attempt: u32,
+    ready_at: Instant,
+    reauthorized: bool,
+}
+
+fn network_error(err: impl std::fmt::Display) -> SyncError {
+    SyncError::Network(format!("Cannot perform request: {}", err))
+}
+
+// @Returns the handle and the access token it was sent with
+fn build_handle(download: &Download) -> Result<(Easy2<Collector>, Option<String>), curl::Error> {
+    let mut headers = List::new();
+    for header in &download.headers {
+        headers.append(header)?;
+    }
+
+    let access_token = download
+        .bearer
+        .as_ref()
+        .map(|bearer| http::current_token(&bearer.token).access_token);
+    if let Some(access_token) = &access_token {
+        headers.append(&format!("Authorization:Bearer {}", access_token))?;
+    }
+
+    let mut handle = Easy2::new(Collector::default());
+    handle.url(&download.url)?;
+    handle.http_headers(headers)?;
+    handle.follow_location(true)?;
+
+    // Empty list offers every encoding libcurl was built
+    // with, gzip included, and decompresses the response
+    handle.accept_encoding("")?;
+
+    // Falls back to HTTP/1.1 if the server
+    // or libcurl doesn't support HTTP/2
+    handle.http_version(HttpVersion::V2TLS)?;
+
+    // Wait for an existing connection to multiplex
+    // on rather than opening a new socket
+    handle.pipewait(true)?;
+
+    Ok((handle, access_token))
+}
+
+fn finish(handle: &mut Easy2<Collector>, result: Result<(), curl::Error>, attempt: u32) -> Attempt {
+    // Dropped connections and timeouts are retried like in http::send
+    let response_code = match result.and_then(|_| handle.response_code()) {
+        Ok(response_code) => response_code,
+        Err(err) if http::is_transient(&err) && http::take_retry(attempt) => {
+            return Attempt::RetryAfter(http::backoff(attempt));
+        }
+        Err(err) => {
+            http::record_outcome(true);
+            return Attempt::Done(Err(network_error(err)));
+        }
+    };
+
+    let collector = handle.get_mut();
+    if response_code == 401 {
+        return Attempt::Rejected;
+    }
+
+    // Only sent for If-None-Match requests
+    if response_code == 304 {
+        return Attempt::Done(Ok(None));
+    }
+
+    let delay = http::retry_delay(response_code, &collector.headers, &collector.body, attempt);
+    if let Some(delay) = delay {
+        if http::take_retry(attempt) {
+            return Attempt::RetryAfter(delay);
+        }
+    }
+    http::record_outcome(delay.is_some());
+
+    if response_code >= 400 {
+        return Attempt::Done(Err(SyncError::Api {
+            code: response_code,
+            message: String::new(),
+        }));
+    }
+
+    Attempt::Done(Ok(Some(std::mem::take(&mut collector.body))))
+}
+
+// Runs the downloads with at most jobs in flight, requests
+// to the same host share one HTTP/2 connection. on_done gets
+// the index and result of every download as soon as it's
+// finished, none for conditional requests answered with not
+// modified. Bodies are handed over rather than kept, so only
+// the ones in flight are held in memory
+pub fn download_all(
+    downloads: &[Download],
+    jobs: usize,
+    mut on_done: impl FnMut(usize, Result<Option<Vec<u8>>, SyncError>),
+) {
+    let mut finished = vec![false; downloads.len()];
+    let mut set_result = |index: usize, result: Result<Option<Vec<u8>>, SyncError>| {
+        finished[index] = true;
+        on_done(index, result);
+    };
+
+    let mut multi = Multi::new();
+    multi.pipelining(false, true).unwrap();
+
+    let now = Instant::now();
+    let mut queue: VecDeque<Queued> = (0..downloads.len())
+        .map(|index| Queued {
+            index,
+            attempt: 1,
+            ready_at: now,
+            reauthorized: false,
+        })
+        .collect();
+    let mut active: Vec<(Queued, Option<String>, Easy2Handle<Collector>)> = Vec::new();
+
+    while !queue.is_empty() || !active.is_empty() {
+        // Downloads waiting for a retry go to the back of the
+        // queue so others can start in the meantime
+        let mut waiting = VecDeque::new();
+        while active.len() < jobs.max(1) {
+            let Some(queued) = queue.pop_front() else {
+                break;
+            };
+
+            if queued.ready_at > Instant::now() {
+                waiting.push_back(queued);
+                continue;
+            }
+
+            // Nothing more is sent to a service that looks down
+            if http::breaker_tripped() {
+                set_result(queued.index, Err(http::breaker_error()));
+                continue;
+            }
+
+            let added = build_handle(&downloads[queued.index])
+                .map_err(|err| err.to_string())
+                .and_then(|(handle, access_token)| {
+                    let handle = multi.add2(handle).map_err(|err| err.to_string())?;
+                    Ok((handle, access_token))
+                });
+
+            match added {
+                Ok((handle, access_token)) => active.push((queued, access_token, handle)),
+                Err(err) => set_result(
+                    queued.index,
+                    Err(SyncError::Network(format!(
+                        "Cannot create request: {}",
+                        err
+                    ))),
+                ),
+            }
+        }
+        queue.extend(waiting);
+
+        if active.is_empty() {
+            if let Some(ready_at) = queue.iter().map(|queued| queued.ready_at).min() {
+                std::thread::sleep(ready_at.saturating_duration_since(Instant::now()));
+            }
+            continue;
+        }
+
+        if let Err(err) = multi.perform() {
+            println!("ERROR: Transfer pool: {}", err);
+            break;
+        }
+
+        let mut done = Vec::new();
+        multi.messages(|message| {
+            for (position, (_, _, handle)) in active.iter().enumerate() {
+                if let Some(result) = message.result_for2(handle) {
+                    done.push((position, result));
+                }
+            }
+        });
+
+        // Removing from the back so positions stay valid
+        done.sort_by_key(|(position, _)| std::cmp::Reverse(*position));
+        for (position, result) in done {
+            let (queued, access_token, handle) = active.swap_remove(position);
+            let attempt = match multi.remove2(handle) {
+                Ok(mut handle) => finish(&mut handle, result, queued.attempt),
+                Err(err) => Attempt::Done(Err(network_error(err))),
+            };
+
+            match attempt {
+                Attempt::Done(result) => set_result(queued.index, result),
+                Attempt::RetryAfter(delay) => {
+                    println!(
+                        "INFO: Download failed, retrying in {:.1}s",
+                        delay.as_secs_f64()
+                    );
+                    queue.push_back(Queued {
+                        index: queued.index,
+                        attempt: queued.attempt + 1,
+                        ready_at: Instant::now() + delay,
+                        reauthorized: queued.reauthorized,
+                    });
+                }
+                Attempt::Rejected => {
+                    let bearer = downloads[queued.index].bearer.as_ref();
+                    let refreshed = match (bearer, access_token, queued.reauthorized) {
+                        (Some(bearer), Some(access_token), false) => {
+                            http::refresh_rejected(bearer, &access_token)
+                        }
+                        _ => Err(SyncError::Api {
+                            code: 401,
+                            message: String::new(),
+                        }),
+                    };
+
+                    // Other downloads rejected with the same token
+                    // are sent again with the one refreshed here
+                    match refreshed {
+                        Ok(_) => queue.push_back(Queued {
+                            index: queued.index,
+                            attempt: queued.attempt,
+                            ready_at: Instant::now(),
+                            reauthorized: true,
+                        }),
+                        Err(err) => set_result(queued.index, Err(err)),
+                    }
+                }
+            }
+        }
+
+        if !active.is_empty() {
+            let _ = multi.wait(&mut [], Duration::from_secs(1));
+        }
+    }
+
+    // Anything left over was cut short by a pool failure
+    for (index, finished) in finished.into_iter().enumerate() {
+        if !finished {
+            on_done(
+                index,
+                Err(SyncError::Network("Transfer was not performed".to_string())),
+            );
+        }
+    }
+}
+
+// Runs f over items on at most jobs threads, for
+// requests which don't fit the download pool
+// @Returns results in the same order as items
+pub fn run_all<T: Sync, R: Send>(items: &[T], jobs: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let next_item = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.max(1).min(items.len()) {
+            scope.spawn(|| loop {
+                let index = next_item.fetch_add(1, Ordering::SeqCst);
+                let Some(item) = items.get(index) else {
+                    break;
+                };
+
+                let result = f(item);
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transfer::run_all;
+
+    #[test]
+    fn test_run_all_keeps_order() {
+        let items: Vec<u64> = (0..50).collect();
+        let results = run_all(&items, 8, |item| {
+            std::thread::sleep(std::time::Duration::from_millis(50 - item));
+            item * 2
+        });
+
+        assert_eq!(
+            results,
+            items.iter().map(|item| item * 2).collect::<Vec<_>>()
+        );
+        assert!(run_all(&[] as &[u64], 4, |item| *item).is_empty());
+    }
+}
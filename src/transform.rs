@@ -0,0 +1,267 @@
+// Rewrites of file contents between the disk and the cloud
+//
+// Line endings of text files are stored as LF in the cloud and
+// written with the platform's own line endings on download, only
+// files with one of the text extensions are touched. Downloaded text
+// files can have a leading UTF-8 BOM left out as well. Office documents
+// can be downloaded as PDFs converted by OneDrive instead
+
+use crate::SyncError;
+
+const DEFAULT_TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "md", "csv", "tsv", "log", "json", "xml", "yaml", "yml", "ini", "html", "css", "js",
+];
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+struct TextFiles {
+    extensions: Vec<String>,
+}
+
+impl TextFiles {
+    // No extensions means the defaults
+    fn new(extensions: Vec<String>) -> Self {
+        let extensions = if extensions.is_empty() {
+            DEFAULT_TEXT_EXTENSIONS
+                .iter()
+                .map(|extension| extension.to_string())
+                .collect()
+        } else {
+            extensions
+                .into_iter()
+                .map(|extension| extension.trim_start_matches('.').to_lowercase())
+                .collect()
+        };
+
+        TextFiles { extensions }
+    }
+
+    // Contents with a NUL byte are treated as binary
+    // whatever the extension says
+    fn contains(&self, file_path: &str, contents: &[u8]) -> bool {
+        let file_name = file_path.rsplit('/').next().unwrap_or(file_path);
+        let is_text = file_name
+            .rsplit_once('.')
+            .is_some_and(|(_, extension)| self.extensions.contains(&extension.to_lowercase()));
+
+        is_text && !contents.contains(&0)
+    }
+}
+
+pub struct LineEndings {
+    text_files: TextFiles,
+}
+
+impl LineEndings {
+    pub fn new(extensions: Vec<String>) -> Self {
+        LineEndings {
+            text_files: TextFiles::new(extensions),
+        }
+    }
+
+    fn applies(&self, file_path: &str, contents: &[u8]) -> bool {
+        self.text_files.contains(file_path, contents)
+    }
+
+    pub fn to_cloud(&self, file_path: &str, contents: Vec<u8>) -> Vec<u8> {
+        if !self.applies(file_path, &contents) {
+            return contents;
+        }
+
+        crlf_to_lf(&contents)
+    }
+
+    pub fn to_local(&self, file_path: &str, contents: Vec<u8>) -> Vec<u8> {
+        if !self.applies(file_path, &contents) || !cfg!(windows) {
+            return contents;
+        }
+
+        // Normalizing first so existing CRLFs aren't doubled
+        let mut local = Vec::with_capacity(contents.len());
+        for byte in crlf_to_lf(&contents) {
+            if byte == b'\n' {
+                local.push(b'\r');
+            }
+            local.push(byte);
+        }
+        local
+    }
+}
+
+// Only downloads are stripped, uploads keep
+// whatever the local file starts with
+pub struct BomStrip {
+    text_files: TextFiles,
+}
+
+impl BomStrip {
+    pub fn new(extensions: Vec<String>) -> Self {
+        BomStrip {
+            text_files: TextFiles::new(extensions),
+        }
+    }
+
+    pub fn to_local(&self, file_path: &str, mut contents: Vec<u8>) -> Vec<u8> {
+        if contents.starts_with(UTF8_BOM) && self.text_files.contains(file_path, &contents) {
+            contents.drain(..UTF8_BOM.len());
+        }
+        contents
+    }
+}
+
+// Formats OneDrive converts to PDF
+const PDF_EXTENSIONS: &[&str] = &[
+    "doc", "docx", "dot", "dotx", "dotm", "odt", "rtf", "ppt", "pptx", "pptm", "pps", "ppsx",
+    "odp", "xls", "xlsx", "xlsm", "ods", "epub", "htm", "html", "md", "eml", "msg",
+];
+
+// Exports are stored as <name>.pdf next to where the original
+// would be, e.g. /a.docx as /a.docx.pdf, and only downloaded
+pub struct PdfExport {
+    extensions: Vec<String>,
+}
+
+impl PdfExport {
+    pub fn new(extensions: Vec<String>) -> Result<Self, SyncError> {
+        let extensions: Vec<String> = extensions
+            .into_iter()
+            .map(|extension| extension.trim_start_matches('.').to_lowercase())
+            .collect();
+
+        if let Some(extension) = extensions
+            .iter()
+            .find(|extension| !PDF_EXTENSIONS.contains(&extension.as_str()))
+        {
+            return Err(SyncError::Usage(format!(
+                "Cannot export .{} files to PDF",
+                extension
+            )));
+        }
+
+        Ok(PdfExport { extensions })
+    }
+
+    fn applies(&self, file_path: &str) -> bool {
+        let file_name = file_path.rsplit('/').next().unwrap_or(file_path);
+        file_name
+            .rsplit_once('.')
+            .is_some_and(|(_, extension)| self.extensions.contains(&extension.to_lowercase()))
+    }
+
+    // @Returns local path of a cloud file
+    pub fn local_path(&self, cloud_path: &str) -> String {
+        if self.applies(cloud_path) {
+            format!("{}.pdf", cloud_path)
+        } else {
+            cloud_path.to_string()
+        }
+    }
+
+    // @Returns path of the original for exports
+    pub fn cloud_path<'p>(&self, local_path: &'p str) -> Option<&'p str> {
+        local_path
+            .strip_suffix(".pdf")
+            .filter(|cloud_path| self.applies(cloud_path))
+    }
+
+    pub fn is_export(&self, local_path: &str) -> bool {
+        self.cloud_path(local_path).is_some()
+    }
+}
+
+fn crlf_to_lf(contents: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(contents.len());
+    for (index, byte) in contents.iter().enumerate() {
+        if *byte == b'\r' && contents.get(index + 1) == Some(&b'\n') {
+            continue;
+        }
+        normalized.push(*byte);
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transform::{BomStrip, LineEndings, PdfExport};
+
+    #[test]
+    fn test_line_endings() {
+        let line_endings = LineEndings::new(Vec::new());
+        assert_eq!(
+            line_endings.to_cloud("/notes/a.TXT", b"one\r\ntwo\rthree\n".to_vec()),
+            b"one\ntwo\rthree\n"
+        );
+
+        // Other extensions and binaries are left alone
+        assert_eq!(
+            line_endings.to_cloud("/a.bin", b"one\r\n".to_vec()),
+            b"one\r\n"
+        );
+        assert_eq!(
+            line_endings.to_cloud("/a.txt", b"\0one\r\n".to_vec()),
+            b"\0one\r\n"
+        );
+
+        let line_endings = LineEndings::new(vec![".conf".to_string()]);
+        assert_eq!(
+            line_endings.to_cloud("/a.conf", b"one\r\n".to_vec()),
+            b"one\n"
+        );
+        assert_eq!(
+            line_endings.to_cloud("/a.txt", b"one\r\n".to_vec()),
+            b"one\r\n"
+        );
+
+        let local = line_endings.to_local("/a.conf", b"one\r\ntwo\n".to_vec());
+        if cfg!(windows) {
+            assert_eq!(local, b"one\r\ntwo\r\n");
+        } else {
+            assert_eq!(local, b"one\r\ntwo\n");
+        }
+    }
+
+    #[test]
+    fn test_bom_strip() {
+        let bom_strip = BomStrip::new(Vec::new());
+        assert_eq!(
+            bom_strip.to_local("/notes/a.csv", b"\xef\xbb\xbfa,b\n".to_vec()),
+            b"a,b\n"
+        );
+
+        // Only a leading one is stripped
+        assert_eq!(
+            bom_strip.to_local("/a.txt", b"a\xef\xbb\xbf".to_vec()),
+            b"a\xef\xbb\xbf"
+        );
+
+        // Other extensions and binaries are left alone
+        assert_eq!(
+            bom_strip.to_local("/a.bin", b"\xef\xbb\xbfa".to_vec()),
+            b"\xef\xbb\xbfa"
+        );
+        assert_eq!(
+            bom_strip.to_local("/a.txt", b"\xef\xbb\xbf\0a".to_vec()),
+            b"\xef\xbb\xbf\0a"
+        );
+
+        let bom_strip = BomStrip::new(vec!["conf".to_string()]);
+        assert_eq!(
+            bom_strip.to_local("/a.txt", b"\xef\xbb\xbfa".to_vec()),
+            b"\xef\xbb\xbfa"
+        );
+    }
+
+    #[test]
+    fn test_pdf_export() {
+        let export = PdfExport::new(vec!["docx".to_string(), ".PPTX".to_string()]).unwrap();
+        assert_eq!(export.local_path("/docs/a.docx"), "/docs/a.docx.pdf");
+        assert_eq!(export.local_path("/docs/b.Pptx"), "/docs/b.Pptx.pdf");
+        assert_eq!(export.local_path("/docs/c.xlsx"), "/docs/c.xlsx");
+
+        assert_eq!(export.cloud_path("/docs/a.docx.pdf"), Some("/docs/a.docx"));
+        assert!(!export.is_export("/docs/a.pdf"));
+        assert!(!export.is_export("/docs/a.docx"));
+
+        assert!(PdfExport::new(vec!["zip".to_string()]).is_err());
+    }
+}
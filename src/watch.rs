@@ -0,0 +1,132 @@
+use std::time::{Duration, Instant};
+
+use notify::{
+    event::{AccessKind, AccessMode},
+    Event, EventKind,
+};
+
+use crate::is_reserved;
+
+// Bursts of events are coalesced into one sync
+// which starts once the folder has been quiet for
+// debounce, or max_delay after the first event
+pub struct Debouncer {
+    debounce: Duration,
+    max_delay: Duration,
+    first: Option<Instant>,
+    last: Option<Instant>,
+}
+
+impl Debouncer {
+    pub fn new(debounce: Duration, max_delay: Duration) -> Self {
+        Debouncer {
+            debounce,
+            max_delay,
+            first: None,
+            last: None,
+        }
+    }
+
+    pub fn event(&mut self, now: Instant) {
+        self.first.get_or_insert(now);
+        self.last = Some(now);
+    }
+
+    pub fn due(&self, now: Instant) -> bool {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => {
+                now.duration_since(last) >= self.debounce
+                    || now.duration_since(first) >= self.max_delay
+            }
+            _ => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.first = None;
+        self.last = None;
+    }
+}
+
+// Reads don't change anything and the files sync
+// itself keeps in the folder, like the cloudstate or
+// the trash, would retrigger it
+pub fn is_relevant(event: &Event) -> bool {
+    let changes = match event.kind {
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+        EventKind::Access(_) => false,
+        _ => true,
+    };
+
+    changes
+        && event.paths.iter().any(|path| {
+            !path
+                .iter()
+                .any(|part| part.to_str().is_some_and(is_reserved))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        time::{Duration, Instant},
+    };
+
+    use notify::{
+        event::{AccessKind, CreateKind},
+        Event, EventKind,
+    };
+
+    use crate::watch::{is_relevant, Debouncer};
+
+    #[test]
+    fn test_debouncer() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        let mut debouncer = Debouncer::new(Duration::from_secs(2), Duration::from_secs(10));
+        assert!(!debouncer.due(at(100)));
+
+        // Events keep pushing the sync back
+        debouncer.event(at(0));
+        debouncer.event(at(1));
+        assert!(!debouncer.due(at(2)));
+        assert!(debouncer.due(at(3)));
+
+        // Up to max_delay after the first one
+        for secs in 3..10 {
+            debouncer.event(at(secs));
+        }
+        assert!(debouncer.due(at(10)));
+
+        debouncer.clear();
+        assert!(!debouncer.due(at(20)));
+    }
+
+    #[test]
+    fn test_relevant_events() {
+        let event = |kind, path: &str| Event::new(kind).add_path(PathBuf::from(path));
+
+        assert!(is_relevant(&event(
+            EventKind::Create(CreateKind::File),
+            "/docs/a.txt"
+        )));
+        assert!(!is_relevant(&event(
+            EventKind::Access(AccessKind::Read),
+            "/docs/a.txt"
+        )));
+        assert!(!is_relevant(&event(
+            EventKind::Create(CreateKind::File),
+            "/docs/.cloudstate"
+        )));
+        assert!(!is_relevant(&event(
+            EventKind::Create(CreateKind::File),
+            "/docs/.cloudstate.2"
+        )));
+        assert!(!is_relevant(&event(
+            EventKind::Create(CreateKind::File),
+            "/docs/.cloudsync-trash/a.txt.2023-08-06T13-23-00Z"
+        )));
+    }
+}
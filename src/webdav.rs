@@ -0,0 +1,672 @@
+use std::collections::{HashMap, HashSet};
+
+use curl::easy::List;
+
+use crate::{
+    http, parse_http_date, parse_iso_date, transfer::Download, Account, DriveDelta, DriveDeltaType,
+    FolderEntry, SyncError, Token,
+};
+
+// Generic WebDAV backend (Nextcloud, ownCloud, ...)
+//
+// Account attributes
+// url       root of the dav tree, e.g. https://cloud.example.com/remote.php/dav/files/<user>
+// username
+// password  an app password where the server supports them
+//
+// Cloud ids are paths relative to the root
+
+// Paths seen by the last delta, files missing
+// from the next listing were deleted remotely
+const LISTING_KEY: &str = "listing";
+
+pub const ACCOUNT_ATTRIBUTES: &[&str] = &[
+    "url",
+    "username",
+    "password",
+    crate::KEYRING_KEY,
+    crate::DEFAULT_FOLDER_KEY,
+];
+pub const MANAGED_ATTRIBUTES: &[&str] = &[LISTING_KEY, crate::SYNC_ERRORS_KEY];
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:getlastmodified/><d:creationdate/><d:getcontentlength/><d:resourcetype/></d:prop></d:propfind>"#;
+
+struct Credentials<'a> {
+    url: &'a str,
+    username: &'a str,
+    password: &'a str,
+}
+
+fn credentials(account: &Account) -> Result<Credentials<'_>, SyncError> {
+    let attribute = |key: &str| {
+        account
+            .attributes
+            .get(key)
+            .map(|value| value.as_str())
+            .ok_or(SyncError::Usage(format!("WebDAV account has no {}", key)))
+    };
+
+    Ok(Credentials {
+        url: attribute("url")?.trim_end_matches('/'),
+        username: attribute("username")?,
+        password: attribute("password")?,
+    })
+}
+
+impl Credentials<'_> {
+    fn authorization(&self) -> String {
+        let user_pass = format!("{}:{}", self.username, self.password);
+        format!("Authorization: Basic {}", base64(user_pass.as_bytes()))
+    }
+
+    fn item_url(&self, item_path: &str) -> String {
+        format!("{}{}", self.url, encode_path(item_path))
+    }
+
+    // Path part of the root url, hrefs in
+    // listings are relative to the server
+    fn root_path(&self) -> &str {
+        let without_scheme = self
+            .url
+            .split_once("://")
+            .map_or(self.url, |(_, rest)| rest);
+
+        without_scheme
+            .find('/')
+            .map_or("", |start| &without_scheme[start..])
+    }
+
+    fn handle(&self, api_url: &str, extra_headers: &[String]) -> http::Handle {
+        let mut headers = List::new();
+        headers.append(&self.authorization()).unwrap();
+        for header in extra_headers {
+            headers.append(header).unwrap();
+        }
+
+        let mut handle = http::handle();
+        handle.url(api_url).unwrap();
+        handle.http_headers(headers).unwrap();
+        handle
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let group = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+
+        for position in 0..4 {
+            if position <= chunk.len() {
+                let index = (group >> (18 - 6 * position)) & 0x3f;
+                encoded.push(ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// Percent encodes everything but unreserved characters and '/'
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::new();
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn decode_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::new();
+
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = path
+            .get(index + 1..index + 3)
+            .filter(|_| bytes[index] == b'%')
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&decoded)
+        .replace("&amp;", "&")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+}
+
+// Minimal multistatus parsing, elements are matched by
+// name without their namespace prefix as servers pick their own
+// @Returns inner text of every matching element, empty for <x/>
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let mut found = Vec::new();
+    let mut rest = xml;
+
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let Some(tag_end) = rest.find('>') else {
+            break;
+        };
+
+        let tag = &rest[..tag_end];
+        let qualified_name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("");
+        let local_name = qualified_name.rsplit(':').next().unwrap_or("");
+
+        if qualified_name.is_empty() || local_name != name {
+            continue;
+        }
+
+        if tag.ends_with('/') {
+            found.push("");
+            continue;
+        }
+
+        let inner = &rest[tag_end + 1..];
+        let closing = format!("</{}>", qualified_name);
+        if let Some(inner_end) = inner.find(&closing) {
+            found.push(&inner[..inner_end]);
+            rest = &inner[inner_end + closing.len()..];
+        }
+    }
+
+    found
+}
+
+struct Entry {
+    path: String,
+    last_modified: Option<u64>,
+    created: Option<u64>,
+    size: Option<u64>,
+    is_folder: bool,
+}
+
+fn propfind(
+    credentials: &Credentials,
+    folder_path: &str,
+    depth: u32,
+) -> Result<Vec<Entry>, SyncError> {
+    let mut handle = credentials.handle(
+        &credentials.item_url(folder_path),
+        &[
+            format!("Depth: {}", depth),
+            "Content-Type: application/xml".to_string(),
+        ],
+    );
+    handle.custom_request("PROPFIND").unwrap();
+    handle.accept_encoding("").unwrap();
+    handle.post_fields_copy(PROPFIND_BODY.as_bytes()).unwrap();
+
+    let response = http::send(&mut handle, None)?.success()?;
+    let body = String::from_utf8_lossy(&response.body);
+
+    let root_path = decode_path(credentials.root_path());
+    let mut entries = Vec::new();
+    for response in elements(&body, "response") {
+        let Some(href) = elements(response, "href")
+            .first()
+            .map(|href| decode_path(href))
+        else {
+            continue;
+        };
+
+        // Some servers return absolute urls
+        let href = match href.split_once("://") {
+            Some((_, rest)) => rest
+                .find('/')
+                .map_or("/".to_string(), |start| rest[start..].to_string()),
+            None => href,
+        };
+
+        let path = href
+            .strip_prefix(root_path.as_str())
+            .unwrap_or(&href)
+            .trim_end_matches('/')
+            .to_string();
+
+        entries.push(Entry {
+            path,
+            last_modified: elements(response, "getlastmodified")
+                .first()
+                .and_then(|date| parse_http_date(date.trim()).ok()),
+
+            // Offsets other than UTC are not handled by the parser
+            created: elements(response, "creationdate")
+                .first()
+                .map(|date| date.trim())
+                .filter(|date| date.ends_with('Z'))
+                .and_then(|date| parse_iso_date(date).ok()),
+            size: elements(response, "getcontentlength")
+                .first()
+                .and_then(|size| size.trim().parse().ok()),
+            is_folder: !elements(response, "collection").is_empty(),
+        });
+    }
+
+    // A listing always has the folder itself, without it the
+    // answer isn't one and the folder would look empty
+    if entries.is_empty() {
+        return Err(SyncError::Parse(format!(
+            "Server listed nothing for /{}, not even the folder itself",
+            folder_path.trim_start_matches('/')
+        )));
+    }
+
+    Ok(entries)
+}
+
+// Walks the tree one level at a time, Depth: infinity
+// is disabled on most servers
+fn list_folder(
+    credentials: &Credentials,
+    folder_path: &str,
+    files: &mut Vec<DriveDelta>,
+) -> Result<(), SyncError> {
+    for entry in propfind(credentials, folder_path, 1)? {
+        // Listing includes the folder itself
+        if entry.path == folder_path.trim_end_matches('/') {
+            continue;
+        }
+
+        if entry.is_folder {
+            list_folder(credentials, &entry.path, files)?;
+            continue;
+        }
+
+        match entry.last_modified {
+            Some(last_modified) => files.push(DriveDelta {
+                cloud_id: entry.path.clone(),
+                file_path: entry.path,
+                last_modified,
+                created: entry.created,
+                ctag: None,
+                size: entry.size,
+                delta_type: DriveDeltaType::CreatedOrModifiled,
+            }),
+            None => println!("ERROR: Skipping {} without last modified date", entry.path),
+        }
+    }
+
+    Ok(())
+}
+
+// Checks the credentials against the server
+// @Returns token and attributes for a new account
+pub fn get_token(
+    url: &str,
+    username: &str,
+    password: &str,
+) -> Result<(Token, HashMap<String, String>), SyncError> {
+    let attributes = HashMap::from([
+        ("url".to_string(), url.to_string()),
+        ("username".to_string(), username.to_string()),
+        ("password".to_string(), password.to_string()),
+    ]);
+
+    let credentials = Credentials {
+        url: url.trim_end_matches('/'),
+        username,
+        password,
+    };
+    propfind(&credentials, "", 0)?;
+
+    // Basic auth never expires
+    let token = Token {
+        access_token: String::new(),
+        refresh_token: String::new(),
+        valid_till: u64::MAX,
+    };
+
+    Ok((token, attributes))
+}
+
+// Same check as get_token with the saved credentials
+pub fn check_account(account: &Account) -> Result<(), SyncError> {
+    let credentials = credentials(account)?;
+    propfind(&credentials, "", 0)?;
+    Ok(())
+}
+
+pub fn get_drive_listing(account: &Account) -> Result<Vec<DriveDelta>, SyncError> {
+    let credentials = credentials(account)?;
+
+    let mut files = Vec::new();
+    list_folder(&credentials, "", &mut files)?;
+    Ok(files)
+}
+
+// Every listed file is reported, sync skips the ones
+// not modified since the last sync
+pub fn get_drive_delta(account: &mut Account) -> Result<Vec<DriveDelta>, SyncError> {
+    let mut deltas = get_drive_listing(account)?;
+
+    let listed: HashSet<String> = deltas.iter().map(|delta| delta.file_path.clone()).collect();
+    let previous: Vec<String> = account
+        .attributes
+        .get(LISTING_KEY)
+        .and_then(|listing| serde_json::from_str(listing).ok())
+        .unwrap_or_default();
+
+    let now = crate::timestamp();
+    for file_path in previous {
+        if !listed.contains(&file_path) {
+            deltas.push(DriveDelta {
+                cloud_id: file_path.clone(),
+                file_path,
+                last_modified: now,
+                created: None,
+                ctag: None,
+                size: None,
+                delta_type: DriveDeltaType::Deleted,
+            });
+        }
+    }
+
+    let listing: Vec<&String> = listed.iter().collect();
+    account.attributes.insert(
+        LISTING_KEY.to_string(),
+        serde_json::to_string(&listing).unwrap(),
+    );
+
+    Ok(deltas)
+}
+
+// @Returns files and folders right inside the folder
+pub fn list_children(account: &Account, folder_path: &str) -> Result<Vec<FolderEntry>, SyncError> {
+    let credentials = credentials(account)?;
+    let folder_path = folder_path.trim_end_matches('/');
+
+    Ok(propfind(&credentials, folder_path, 1)?
+        .into_iter()
+        .filter(|entry| entry.path != folder_path)
+        .map(|entry| FolderEntry {
+            name: entry
+                .path
+                .rsplit_once('/')
+                .map_or(entry.path.as_str(), |(_, name)| name)
+                .to_string(),
+            is_folder: entry.is_folder,
+            size: entry.size,
+            last_modified: entry.last_modified,
+        })
+        .collect())
+}
+
+fn download_handle(account: &Account, item_path: &str) -> Result<http::Handle, SyncError> {
+    let credentials = credentials(account)?;
+
+    let mut handle = credentials.handle(&credentials.item_url(item_path), &[]);
+    handle.follow_location(true).unwrap();
+    handle.accept_encoding("").unwrap();
+    Ok(handle)
+}
+
+pub fn download_file(account: &Account, item_path: &str) -> Result<Vec<u8>, SyncError> {
+    let mut handle = download_handle(account, item_path)?;
+    let response = http::send(&mut handle, None)?.success()?;
+    Ok(response.body)
+}
+
+// Same as download_file with the contents left in the spool
+pub fn download_file_spooled(
+    account: &Account,
+    item_path: &str,
+    spool: &mut http::Spool,
+) -> Result<(), SyncError> {
+    let mut handle = download_handle(account, item_path)?;
+    http::send_spooled(&mut handle, spool)?.success()?;
+    Ok(())
+}
+
+// Same request as download_file, to be run on the transfer pool
+pub(crate) fn download_request(account: &Account, item_path: &str) -> Download {
+    let (url, headers) = match credentials(account) {
+        Ok(credentials) => (
+            credentials.item_url(item_path),
+            vec![credentials.authorization()],
+        ),
+        Err(_) => (String::new(), Vec::new()),
+    };
+
+    Download {
+        url,
+        headers,
+        bearer: None,
+    }
+}
+
+// MKCOL fails on existing folders with 405
+fn create_parent_folders(credentials: &Credentials, item_path: &str) -> Result<(), SyncError> {
+    let Some((parent, _)) = item_path.rsplit_once('/') else {
+        return Ok(());
+    };
+
+    let mut folder_path = String::new();
+    for component in parent.split('/').filter(|component| !component.is_empty()) {
+        folder_path = format!("{}/{}", folder_path, component);
+
+        let mut handle = credentials.handle(&credentials.item_url(&folder_path), &[]);
+        handle.custom_request("MKCOL").unwrap();
+
+        let response = http::send(&mut handle, None)?;
+        if response.code != 405 {
+            response.success()?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn upload_new_file(
+    account: &Account,
+    item_path: &str,
+    contents: &[u8],
+) -> Result<String, SyncError> {
+    upload_file(account, item_path, contents)?;
+    Ok(item_path.to_string())
+}
+
+// @Returns etag of the new contents, if the server sends one
+pub fn upload_file(
+    account: &Account,
+    item_path: &str,
+    contents: &[u8],
+) -> Result<Option<String>, SyncError> {
+    let credentials = credentials(account)?;
+    create_parent_folders(&credentials, item_path)?;
+
+    let mut handle = credentials.handle(
+        &credentials.item_url(item_path),
+        &[
+            "Content-Type: application/octet-stream".to_string(),
+            http::NO_EXPECT.to_string(),
+        ],
+    );
+    handle.put(true).unwrap();
+    handle.in_filesize(contents.len() as u64).unwrap();
+
+    let response = http::send(&mut handle, Some(contents))?.success()?;
+    Ok(response.header("etag").map(|etag| etag.to_string()))
+}
+
+// sabre/dav servers with the partial update plugin
+// list its content type in Accept-Patch
+pub fn supports_partial_update(account: &Account) -> Result<bool, SyncError> {
+    let credentials = credentials(account)?;
+
+    let mut handle = credentials.handle(credentials.url, &[]);
+    handle.custom_request("OPTIONS").unwrap();
+
+    let response = http::send(&mut handle, None)?.success()?;
+    Ok(response
+        .header("accept-patch")
+        .is_some_and(|accepted| accepted.contains(PARTIAL_UPDATE_TYPE)))
+}
+
+const PARTIAL_UPDATE_TYPE: &str = "application/x-sabredav-partialupdate";
+
+// Overwrites the bytes from offset on, extending the file if they
+// go past its end. With an etag the server refuses with 412 when
+// the file isn't that version anymore
+// @Returns etag of the new contents, if the server sends one
+pub fn patch_file(
+    account: &Account,
+    item_path: &str,
+    offset: usize,
+    data: &[u8],
+    etag: Option<&str>,
+) -> Result<Option<String>, SyncError> {
+    let credentials = credentials(account)?;
+
+    let mut headers = vec![
+        format!("Content-Type: {}", PARTIAL_UPDATE_TYPE),
+        http::NO_EXPECT.to_string(),
+        format!(
+            "X-Update-Range: bytes={}-{}",
+            offset,
+            (offset + data.len()).saturating_sub(1)
+        ),
+    ];
+    headers.extend(etag.map(|etag| format!("If-Match: {}", etag)));
+
+    let mut handle = credentials.handle(&credentials.item_url(item_path), &headers);
+    handle.custom_request("PATCH").unwrap();
+    handle.post_fields_copy(data).unwrap();
+
+    let response = http::send(&mut handle, None)?.success()?;
+    Ok(response.header("etag").map(|etag| etag.to_string()))
+}
+
+pub fn delete_file(account: &Account, cloud_id: &str) -> Result<(), SyncError> {
+    let credentials = credentials(account)?;
+
+    let mut handle = credentials.handle(&credentials.item_url(cloud_id), &[]);
+    handle.custom_request("DELETE").unwrap();
+
+    http::send(&mut handle, None)?.success()?;
+    Ok(())
+}
+
+// Emptiness is checked right before the delete, a file
+// uploaded in between by another client would be lost with it
+// @Returns whether the folder was empty and deleted
+pub fn delete_empty_folder(account: &Account, folder_path: &str) -> Result<bool, SyncError> {
+    let folder_path = folder_path.trim_end_matches('/');
+    if folder_path.is_empty() {
+        return Ok(false);
+    }
+
+    let credentials = credentials(account)?;
+    let entries = match propfind(&credentials, folder_path, 1) {
+        Ok(entries) => entries,
+        Err(SyncError::Api { code: 404, .. }) => return Ok(false),
+        Err(err) => return Err(err),
+    };
+
+    // Listing includes the folder itself
+    if entries.iter().any(|entry| entry.path != folder_path) {
+        return Ok(false);
+    }
+
+    let folder_url = format!("{}/", credentials.item_url(folder_path));
+    let mut handle = credentials.handle(&folder_url, &[]);
+    handle.custom_request("DELETE").unwrap();
+
+    http::send(&mut handle, None)?.success()?;
+    Ok(true)
+}
+
+// Server side COPY, unlike graph it completes synchronously
+// @Returns cloud id of the new item
+pub fn copy_item(account: &Account, cloud_id: &str, new_path: &str) -> Result<String, SyncError> {
+    let credentials = credentials(account)?;
+    create_parent_folders(&credentials, new_path)?;
+
+    let mut handle = credentials.handle(
+        &credentials.item_url(cloud_id),
+        &[
+            format!("Destination: {}", credentials.item_url(new_path)),
+            "Overwrite: T".to_string(),
+        ],
+    );
+    handle.custom_request("COPY").unwrap();
+
+    http::send(&mut handle, None)?.success()?;
+    Ok(new_path.to_string())
+}
+
+// @Returns server time as unix timestamp
+pub fn get_server_time(account: &Account) -> Result<u64, SyncError> {
+    let credentials = credentials(account)?;
+
+    let mut handle = credentials.handle(credentials.url, &[]);
+    handle.custom_request("OPTIONS").unwrap();
+
+    let response = http::send(&mut handle, None)?.success()?;
+    let server_date = response
+        .header("date")
+        .ok_or(SyncError::Parse("Response has no Date header".to_string()))?;
+    parse_http_date(server_date)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::webdav::{base64, decode_path, elements, encode_path};
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b"user:pass"), "dXNlcjpwYXNz");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"a"), "YQ==");
+    }
+
+    #[test]
+    fn test_path_encoding() {
+        assert_eq!(encode_path("/My Notes/a&b.txt"), "/My%20Notes/a%26b.txt");
+        assert_eq!(decode_path("/My%20Notes/a&amp;b.txt"), "/My Notes/a&b.txt");
+    }
+
+    #[test]
+    fn test_multistatus_elements() {
+        let xml = r#"<d:multistatus xmlns:d="DAV:">
+            <d:response><d:href>/dav/a.txt</d:href><d:propstat><d:prop>
+                <d:getlastmodified>Sun, 06 Aug 2023 13:23:00 GMT</d:getlastmodified>
+                <d:resourcetype/></d:prop></d:propstat></d:response>
+            <D:response><D:href>/dav/docs/</D:href><D:propstat><D:prop>
+                <D:resourcetype><D:collection/></D:resourcetype></D:prop></D:propstat></D:response>
+        </d:multistatus>"#;
+
+        let responses = elements(xml, "response");
+        assert_eq!(responses.len(), 2);
+        assert_eq!(elements(responses[0], "href"), vec!["/dav/a.txt"]);
+        assert!(elements(responses[0], "collection").is_empty());
+        assert_eq!(elements(responses[1], "collection").len(), 1);
+    }
+}
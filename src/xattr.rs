@@ -0,0 +1,249 @@
+// Extended attributes of local files kept in sidecars
+//
+// With --sync-xattrs the attributes of an uploaded file are stored
+// next to it in the cloud as <name>.cloudsync-meta.json and set again
+// on download. Only Linux and macOS have them, everywhere else files
+// have none and nothing is set. On Linux only the user namespace is
+// read, the others need privileges to be set again
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{crypto, SyncError};
+
+pub const SIDECAR_SUFFIX: &str = ".cloudsync-meta.json";
+
+// Attribute values by name
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+#[derive(Serialize, Deserialize)]
+struct Sidecar {
+    // Values are hex encoded, they are binary
+    xattrs: BTreeMap<String, String>,
+}
+
+pub fn is_sidecar(file_name: &str) -> bool {
+    file_name.len() > SIDECAR_SUFFIX.len() && file_name.ends_with(SIDECAR_SUFFIX)
+}
+
+pub fn sidecar_path(file_path: &str) -> String {
+    format!("{}{}", file_path, SIDECAR_SUFFIX)
+}
+
+// @Returns path of the file a sidecar belongs to
+pub fn sidecar_target(sidecar_path: &str) -> Option<&str> {
+    let (_, file_name) = sidecar_path.rsplit_once('/').unwrap_or(("", sidecar_path));
+    if !is_sidecar(file_name) {
+        return None;
+    }
+
+    sidecar_path.strip_suffix(SIDECAR_SUFFIX)
+}
+
+pub fn to_sidecar(xattrs: &Xattrs) -> Vec<u8> {
+    let sidecar = Sidecar {
+        xattrs: xattrs
+            .iter()
+            .map(|(name, value)| (name.clone(), crypto::to_hex(value)))
+            .collect(),
+    };
+    serde_json::to_vec_pretty(&sidecar).unwrap()
+}
+
+pub fn from_sidecar(data: &[u8]) -> Result<Xattrs, SyncError> {
+    let invalid = |name: &str| SyncError::Parse(format!("Invalid value of attribute {}", name));
+
+    let sidecar: Sidecar = serde_json::from_slice(data)
+        .map_err(|err| SyncError::Parse(format!("Invalid metadata sidecar: {}", err)))?;
+    sidecar
+        .xattrs
+        .into_iter()
+        .map(|(name, value)| match crypto::from_hex(&value) {
+            Some(value) => Ok((name, value)),
+            None => Err(invalid(&name)),
+        })
+        .collect()
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod sys {
+    use std::{ffi::CString, io};
+
+    fn c_string(data: &str) -> io::Result<CString> {
+        CString::new(data).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn list(path: *const libc::c_char, buf: *mut libc::c_char, size: usize) -> isize {
+        libc::listxattr(path, buf, size)
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn list(path: *const libc::c_char, buf: *mut libc::c_char, size: usize) -> isize {
+        libc::listxattr(path, buf, size, 0)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn get(
+        path: *const libc::c_char,
+        name: *const libc::c_char,
+        buf: *mut libc::c_void,
+        size: usize,
+    ) -> isize {
+        libc::getxattr(path, name, buf, size)
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn get(
+        path: *const libc::c_char,
+        name: *const libc::c_char,
+        buf: *mut libc::c_void,
+        size: usize,
+    ) -> isize {
+        libc::getxattr(path, name, buf, size, 0, 0)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn set(
+        path: *const libc::c_char,
+        name: *const libc::c_char,
+        value: *const libc::c_void,
+        size: usize,
+    ) -> i32 {
+        libc::setxattr(path, name, value, size, 0)
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn set(
+        path: *const libc::c_char,
+        name: *const libc::c_char,
+        value: *const libc::c_void,
+        size: usize,
+    ) -> i32 {
+        libc::setxattr(path, name, value, size, 0, 0)
+    }
+
+    // Sizes are asked for first, attributes
+    // changing in between fail with ERANGE
+    fn sized(mut call: impl FnMut(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+        let size = call(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let size = call(buf.as_mut_ptr(), buf.len());
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        buf.truncate(size as usize);
+        Ok(buf)
+    }
+
+    pub fn names(path: &str) -> io::Result<Vec<String>> {
+        let path = c_string(path)?;
+        let names = sized(|buf, size| unsafe { list(path.as_ptr(), buf.cast(), size) })?;
+
+        Ok(names
+            .split(|byte| *byte == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).to_string())
+            .collect())
+    }
+
+    pub fn value(path: &str, name: &str) -> io::Result<Vec<u8>> {
+        let path = c_string(path)?;
+        let name = c_string(name)?;
+        sized(|buf, size| unsafe { get(path.as_ptr(), name.as_ptr(), buf.cast(), size) })
+    }
+
+    pub fn set_value(path: &str, name: &str, value: &[u8]) -> io::Result<()> {
+        let path = c_string(path)?;
+        let name = c_string(name)?;
+        let result = unsafe {
+            set(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn read(path: &str) -> std::io::Result<Xattrs> {
+    let mut xattrs = Xattrs::new();
+    for name in sys::names(path)? {
+        if cfg!(target_os = "linux") && !name.starts_with("user.") {
+            continue;
+        }
+
+        let value = sys::value(path, &name)?;
+        xattrs.insert(name, value);
+    }
+    Ok(xattrs)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn read(_path: &str) -> std::io::Result<Xattrs> {
+    Ok(Xattrs::new())
+}
+
+// Attributes the filesystem refuses are reported and
+// skipped so one of them doesn't lose all the others
+// @Returns attributes which couldn't be set with the error
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn write(path: &str, xattrs: &Xattrs) -> Vec<(String, std::io::Error)> {
+    xattrs
+        .iter()
+        .filter_map(|(name, value)| {
+            sys::set_value(path, name, value)
+                .err()
+                .map(|err| (name.clone(), err))
+        })
+        .collect()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn write(_path: &str, _xattrs: &Xattrs) -> Vec<(String, std::io::Error)> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::xattr::{
+        from_sidecar, is_sidecar, sidecar_path, sidecar_target, to_sidecar, Xattrs,
+    };
+
+    #[test]
+    fn test_sidecar() {
+        assert!(is_sidecar("a.txt.cloudsync-meta.json"));
+        assert!(!is_sidecar(".cloudsync-meta.json"));
+        assert!(!is_sidecar("a.txt"));
+
+        assert_eq!(
+            sidecar_path("/docs/a.txt"),
+            "/docs/a.txt.cloudsync-meta.json"
+        );
+        assert_eq!(
+            sidecar_target("/docs/a.txt.cloudsync-meta.json"),
+            Some("/docs/a.txt")
+        );
+        assert_eq!(sidecar_target("/docs/a.txt"), None);
+
+        let mut xattrs = Xattrs::new();
+        xattrs.insert("user.tags".to_string(), b"red\0blue".to_vec());
+        xattrs.insert("user.empty".to_string(), Vec::new());
+        assert_eq!(from_sidecar(&to_sidecar(&xattrs)).unwrap(), xattrs);
+
+        assert!(from_sidecar(br#"{"xattrs":{"user.tags":"zz"}}"#).is_err());
+        assert!(from_sidecar(b"not json").is_err());
+    }
+}